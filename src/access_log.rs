@@ -0,0 +1,78 @@
+use clap::ValueEnum;
+use serde_json::json;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// One `key=value` line per request
+    Text,
+    /// One JSON object per request
+    Json,
+}
+
+/// Completion details a handler attaches to its response (as an extension)
+/// for the access log.
+#[derive(Clone, Debug, Default)]
+pub struct CompletionInfo {
+    /// The `chatcmpl-` id returned to the client
+    pub id: String,
+    pub model: String,
+    pub total_tokens: Option<i64>,
+    /// Time from sending the upstream request to reading its last byte (its
+    /// first event, for live streams)
+    pub upstream_latency: Option<Duration>,
+}
+
+/// One served request.
+pub struct AccessRecord<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    pub bytes_in: usize,
+    /// `None` when the body length is not known up front
+    pub bytes_out: Option<u64>,
+    pub latency: Duration,
+    pub completion: Option<&'a CompletionInfo>,
+}
+
+impl AccessRecord<'_> {
+    pub fn render(&self, format: LogFormat) -> String {
+        let model = self.completion.map(|c| c.model.as_str());
+        let tokens = self.completion.and_then(|c| c.total_tokens);
+        let upstream_ms = self
+            .completion
+            .and_then(|c| c.upstream_latency)
+            .map(|latency| latency.as_millis() as u64);
+        let latency_ms = self.latency.as_millis() as u64;
+
+        match format {
+            LogFormat::Json => json!({
+                "method": self.method,
+                "path": self.path,
+                "status": self.status,
+                "bytes_in": self.bytes_in,
+                "bytes_out": self.bytes_out,
+                "model": model,
+                "tokens": tokens,
+                "latency_ms": latency_ms,
+                "upstream_latency_ms": upstream_ms
+            })
+            .to_string(),
+            LogFormat::Text => {
+                let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+                format!(
+                    "method={} path={} status={} bytes_in={} bytes_out={} model={} tokens={} latency_ms={} upstream_latency_ms={}",
+                    self.method,
+                    self.path,
+                    self.status,
+                    self.bytes_in,
+                    or_dash(self.bytes_out.map(|b| b.to_string())),
+                    model.unwrap_or("-"),
+                    or_dash(tokens.map(|t| t.to_string())),
+                    latency_ms,
+                    or_dash(upstream_ms.map(|ms| ms.to_string())),
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use super::jwt;
+use super::AuthData;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+const QUOTA_UNKNOWN: u64 = u64::MAX;
+// Accounts that look exhausted keep a sliver of traffic so recovery is noticed.
+const MIN_QUOTA_PERMILLE: u64 = 100;
+
+/// One set of ChatGPT credentials the proxy can send requests with.
+#[derive(Debug)]
+pub struct Account {
+    pub label: String,
+    /// Expanded location of the auth.json, rewritten when tokens are refreshed
+    pub auth_path: PathBuf,
+    auth: RwLock<AuthData>,
+    pub weight: u32,
+    /// Backend models this account may serve; `None` means every allowed model
+    models: Option<Vec<String>>,
+    pub requests: AtomicU64,
+    // Remaining quota in permille as last reported by the backend
+    remaining_quota: AtomicU64,
+    /// Held while refreshing, so a rotated refresh token is only spent once
+    pub refresh_lock: tokio::sync::Mutex<()>,
+}
+
+impl Account {
+    pub fn new(label: String, auth_path: PathBuf, auth: AuthData, weight: u32) -> Self {
+        Self {
+            label,
+            auth_path,
+            auth: RwLock::new(auth),
+            weight,
+            models: None,
+            requests: AtomicU64::new(0),
+            remaining_quota: AtomicU64::new(QUOTA_UNKNOWN),
+            refresh_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Restrict the account to `models` instead of the global allowlist.
+    pub fn serving_models(mut self, models: Vec<String>) -> Self {
+        self.models = Some(models);
+        self
+    }
+
+    pub fn serves(&self, model: &str) -> bool {
+        self.models
+            .as_ref()
+            .is_none_or(|models| models.iter().any(|m| m == model))
+    }
+
+    /// Snapshot of the current credentials.
+    pub fn auth(&self) -> AuthData {
+        self.auth
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Swap in refreshed OAuth tokens, keeping the refresh token when none is issued.
+    pub fn update_tokens(&self, access_token: String, refresh_token: Option<String>) {
+        let mut auth = self
+            .auth
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(tokens) = auth.tokens.as_mut() {
+            tokens.access_token = access_token;
+            if refresh_token.is_some() {
+                tokens.refresh_token = refresh_token;
+            }
+        }
+    }
+
+    /// Expiry (`exp`, Unix seconds) of the OAuth access token, if it has one.
+    pub fn token_expires_at(&self) -> Option<i64> {
+        let tokens = self.auth().tokens?;
+        jwt::token_lifetime(&tokens.access_token)
+            .ok()
+            .map(|(_, expires_at)| expires_at)
+    }
+
+    fn remaining_quota_permille(&self) -> Option<u64> {
+        match self.remaining_quota.load(Ordering::Relaxed) {
+            QUOTA_UNKNOWN => None,
+            permille => Some(permille),
+        }
+    }
+}
+
+/// Smooth weighted round-robin over the configured accounts, optionally
+/// scaled by the quota each account has left.
+#[derive(Debug)]
+pub struct AccountPool {
+    accounts: Vec<Account>,
+    current_weights: Mutex<Vec<i64>>,
+    quota_aware: bool,
+}
+
+impl AccountPool {
+    pub fn new(accounts: Vec<Account>, quota_aware: bool) -> Self {
+        let current_weights = Mutex::new(vec![0; accounts.len()]);
+        Self {
+            accounts,
+            current_weights,
+            quota_aware,
+        }
+    }
+
+    pub fn accounts(&self) -> &[Account] {
+        &self.accounts
+    }
+
+    fn effective_weight(&self, account: &Account) -> i64 {
+        let base = i64::from(account.weight) * 1000;
+        match account.remaining_quota_permille() {
+            Some(permille) if self.quota_aware => {
+                base * permille.max(MIN_QUOTA_PERMILLE) as i64 / 1000
+            }
+            _ => base,
+        }
+    }
+
+    /// Pick the next account that can serve `model` (any account when `None`),
+    /// skipping those in `exclude`, and count the request against it.
+    pub fn select(&self, model: Option<&str>, exclude: &[&Account]) -> Option<&Account> {
+        let mut current = self
+            .current_weights
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Accounts that cannot serve the model sit this round out entirely
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (index, account) in self.accounts.iter().enumerate() {
+            if !self.eligible(account, model, exclude) {
+                continue;
+            }
+            let weight = self.effective_weight(account);
+            current[index] += weight;
+            total += weight;
+            if best.is_none_or(|best| current[index] > current[best]) {
+                best = Some(index);
+            }
+        }
+        let best = best?;
+        current[best] -= total;
+
+        let account = &self.accounts[best];
+        account.requests.fetch_add(1, Ordering::Relaxed);
+        Some(account)
+    }
+
+    /// Whether another account besides those in `exclude` can serve `model`.
+    pub fn has_alternative(&self, model: Option<&str>, exclude: &[&Account]) -> bool {
+        self.accounts
+            .iter()
+            .any(|account| self.eligible(account, model, exclude))
+    }
+
+    fn eligible(&self, account: &Account, model: Option<&str>, exclude: &[&Account]) -> bool {
+        model.is_none_or(|model| account.serves(model))
+            && !exclude.iter().any(|tried| std::ptr::eq(*tried, account))
+    }
+
+    /// Remember the quota the backend reports for an account.
+    pub fn record_rate_limits(&self, account: &Account, headers: &reqwest::header::HeaderMap) {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<f64>().ok())
+        };
+
+        let remaining_fraction = if let Some(used_percent) = header("x-codex-primary-used-percent")
+        {
+            Some(1.0 - used_percent / 100.0)
+        } else {
+            match (
+                header("x-ratelimit-remaining-requests"),
+                header("x-ratelimit-limit-requests"),
+            ) {
+                (Some(remaining), Some(limit)) if limit > 0.0 => Some(remaining / limit),
+                _ => None,
+            }
+        };
+
+        if let Some(fraction) = remaining_fraction {
+            let permille = (fraction.clamp(0.0, 1.0) * 1000.0).round() as u64;
+            account.remaining_quota.store(permille, Ordering::Relaxed);
+        }
+    }
+
+    /// Token expiry countdown for `/health`: the soonest expiry across accounts
+    /// at the top level, plus each account's own.
+    pub fn token_health(&self) -> serde_json::Value {
+        let now = chrono::Utc::now().timestamp();
+        let accounts = self
+            .accounts
+            .iter()
+            .map(|account| {
+                let expires_in = account.token_expires_at().map(|exp| exp - now);
+                serde_json::json!({
+                    "account": account.label,
+                    "token_expires_in_seconds": expires_in,
+                    "token_expired": expires_in.map(|secs| secs <= 0)
+                })
+            })
+            .collect::<Vec<_>>();
+        let soonest = self
+            .accounts
+            .iter()
+            .filter_map(Account::token_expires_at)
+            .min()
+            .map(|exp| exp - now);
+        serde_json::json!({
+            "token_expires_in_seconds": soonest,
+            "token_expired": soonest.map(|secs| secs <= 0),
+            "accounts": accounts
+        })
+    }
+
+    /// Per-account credential details for `/health?deep=1`: the token kind,
+    /// and the account id and scopes the access token claims. The id from
+    /// auth.json is shown alongside, as a mismatch explains 403s.
+    pub fn token_diagnostics(&self) -> serde_json::Value {
+        let accounts = self
+            .accounts
+            .iter()
+            .map(|account| {
+                let auth = account.auth();
+                let mut details = match (&auth.tokens, &auth.api_key) {
+                    (Some(tokens), _) => jwt::describe_token(&tokens.access_token),
+                    (None, Some(api_key)) => jwt::describe_token(api_key),
+                    (None, None) => serde_json::json!({ "token_kind": "none" }),
+                };
+                details["account"] = serde_json::json!(account.label);
+                details["auth_file_account_id"] =
+                    serde_json::json!(auth.tokens.and_then(|tokens| tokens.account_id));
+                details
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!(accounts)
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let name = "codex_proxy_account_requests_total";
+        let _ = writeln!(out, "# HELP {name} Requests sent with each account");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for account in &self.accounts {
+            let _ = writeln!(
+                out,
+                "{name}{{account=\"{}\"}} {}",
+                account.label,
+                account.requests.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
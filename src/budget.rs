@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Oldest conversations are forgotten beyond this, bounding memory
+const MAX_TRACKED_CONVERSATIONS: usize = 10_000;
+
+/// Cumulative token use per conversation (`X-Conversation-Id`) against a
+/// fixed budget.
+#[derive(Debug)]
+pub struct ConversationBudgets {
+    budget: u64,
+    used: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl ConversationBudgets {
+    /// `budget == 0` disables tracking entirely.
+    pub fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    /// Tokens the conversation may still use.
+    pub fn remaining(&self, conversation: &str) -> u64 {
+        let used = self.used.lock().unwrap();
+        let spent = used.get(conversation).map_or(0, |(tokens, _)| *tokens);
+        self.budget.saturating_sub(spent)
+    }
+
+    /// Add a completion's tokens and return what remains afterwards.
+    pub fn record(&self, conversation: &str, tokens: u64) -> u64 {
+        let mut used = self.used.lock().unwrap();
+        if !used.contains_key(conversation) && used.len() >= MAX_TRACKED_CONVERSATIONS {
+            let oldest = used
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                used.remove(&oldest);
+            }
+        }
+        let entry = used
+            .entry(conversation.to_string())
+            .or_insert((0, Instant::now()));
+        entry.0 = entry.0.saturating_add(tokens);
+        entry.1 = Instant::now();
+        self.budget.saturating_sub(entry.0)
+    }
+}
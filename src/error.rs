@@ -0,0 +1,190 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use thiserror::Error;
+use warp::http::StatusCode;
+
+/// Failures while serving a chat completion, carrying enough structure to pick
+/// the client-facing HTTP status and OpenAI error body.
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("ChatGPT backend rejected the proxy credentials: {0}")]
+    Auth(String),
+    #[error("Access token was rejected and could not be refreshed ({0}); run `codex login` again")]
+    ReauthRequired(String),
+    #[error("ChatGPT backend returned {status} with body: {body}")]
+    Upstream { status: u16, body: String },
+    #[error("Failed to reach ChatGPT backend: {0}")]
+    Network(String),
+    #[error("Timed out waiting for ChatGPT backend")]
+    Timeout,
+    #[error("Failed to convert backend response: {0}")]
+    Conversion(String),
+    #[error("Rate limited by ChatGPT backend")]
+    RateLimited { retry_after: Option<u64> },
+    #[error("Proxy is at capacity and its request queue is full")]
+    Overloaded { retry_after: Option<u64> },
+    #[error("Response was blocked by the content filter")]
+    ContentFilter,
+    #[error("ChatGPT backend returned success but no assistant content could be extracted")]
+    EmptyResponse,
+    #[error("Request looped back into this proxy; check the upstream URL")]
+    LoopDetected,
+    #[error("No configured account can serve model '{0}'")]
+    NoAccountForModel(String),
+    #[error("tool_choice required a tool call, but the model answered with text")]
+    ToolCallRequired,
+}
+
+impl ProxyError {
+    /// Classify a non-success upstream status.
+    pub fn from_upstream(status: StatusCode, retry_after: Option<u64>, body: String) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Auth(body),
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited { retry_after },
+            StatusCode::LOOP_DETECTED => Self::LoopDetected,
+            _ => Self::Upstream {
+                status: status.as_u16(),
+                body,
+            },
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Auth(_) | Self::ReauthRequired(_) => StatusCode::UNAUTHORIZED,
+            // Client-side rejections keep their status; backend failures become 502
+            Self::Upstream { status, .. } => match StatusCode::from_u16(*status) {
+                Ok(code) if code.is_client_error() => code,
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            Self::Network(_)
+            | Self::Conversion(_)
+            | Self::EmptyResponse
+            | Self::ToolCallRequired => StatusCode::BAD_GATEWAY,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Overloaded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Self::ContentFilter => StatusCode::BAD_REQUEST,
+            Self::LoopDetected => StatusCode::LOOP_DETECTED,
+            Self::NoAccountForModel(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// Whether the backend rejected the requested model itself (unknown or
+    /// unsupported), as opposed to the request content.
+    pub fn is_model_unavailable(&self) -> bool {
+        let Self::Upstream { status, body } = self else {
+            // No account having the model is just as good a reason to fall back
+            return matches!(self, Self::NoAccountForModel(_));
+        };
+        if *status == 404 {
+            return true;
+        }
+        let body = body.to_lowercase();
+        *status == 400
+            && body.contains("model")
+            && [
+                "not supported",
+                "unsupported",
+                "not found",
+                "does not exist",
+            ]
+            .iter()
+            .any(|needle| body.contains(needle))
+    }
+
+    /// Whether retrying the same request may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Network(_)
+            | Self::Timeout
+            | Self::RateLimited { .. }
+            | Self::Overloaded { .. }
+            | Self::EmptyResponse
+            | Self::ToolCallRequired => true,
+            Self::Upstream { status, .. } => *status >= 500,
+            Self::Auth(_)
+            | Self::ReauthRequired(_)
+            | Self::Conversion(_)
+            | Self::ContentFilter
+            | Self::LoopDetected
+            | Self::NoAccountForModel(_) => false,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::RateLimited { retry_after } | Self::Overloaded { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+
+    fn error_type_and_code(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::Auth(_) => ("authentication_error", "upstream_auth_failed"),
+            Self::ReauthRequired(_) => ("authentication_error", "reauthentication_required"),
+            Self::Upstream { .. } => ("proxy_error", "upstream_error"),
+            Self::Network(_) => ("proxy_error", "upstream_unreachable"),
+            Self::Timeout => ("proxy_error", "upstream_timeout"),
+            Self::Conversion(_) => ("proxy_error", "conversion_error"),
+            Self::RateLimited { .. } => ("rate_limit_error", "rate_limit_exceeded"),
+            Self::Overloaded { .. } => ("proxy_error", "queue_full"),
+            Self::ContentFilter => ("invalid_request_error", "content_filter"),
+            Self::EmptyResponse => ("proxy_error", "empty_response"),
+            Self::LoopDetected => ("proxy_error", "loop_detected"),
+            Self::NoAccountForModel(_) => ("invalid_request_error", "model_not_available"),
+            Self::ToolCallRequired => ("proxy_error", "tool_call_required"),
+        }
+    }
+
+    /// OpenAI-style error envelope for this error, with the message replaced
+    /// when `messages` has one for it.
+    pub fn to_body(&self, messages: &ErrorMessages) -> Value {
+        let (error_type, code) = self.error_type_and_code();
+        let message = match messages.message_for(code, self.status()) {
+            Some(message) => message.to_string(),
+            None => format!("Proxy error: {}", self),
+        };
+        json!({
+            "error": {
+                "message": message,
+                "type": error_type,
+                "code": code
+            }
+        })
+    }
+}
+
+/// Client-facing messages that replace the default ones, keyed by error
+/// `code` (`rate_limit_exceeded`), HTTP status (`429`) or status class
+/// (`5xx`). The most specific key wins.
+#[derive(Debug, Default)]
+pub struct ErrorMessages(HashMap<String, String>);
+
+impl ErrorMessages {
+    pub fn new(messages: HashMap<String, String>) -> Self {
+        Self(messages)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.0.keys()
+    }
+
+    fn message_for(&self, code: &str, status: StatusCode) -> Option<&str> {
+        let class = format!("{}xx", status.as_u16() / 100);
+        let message = [code, status.as_str(), class.as_str()]
+            .into_iter()
+            .find_map(|key| self.0.get(key));
+        message.map(String::as_str)
+    }
+}
+
+impl From<reqwest::Error> for ProxyError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Network(error.to_string())
+        }
+    }
+}
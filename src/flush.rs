@@ -0,0 +1,93 @@
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When buffered SSE frames are written to the client. With neither limit
+/// set, every frame is flushed on its own.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlushPolicy {
+    /// Longest time the first buffered frame may wait for company
+    pub window: Option<Duration>,
+    /// Flush as soon as this many bytes are buffered (0 = no size threshold)
+    pub max_bytes: usize,
+}
+
+impl FlushPolicy {
+    pub fn new(window_ms: u64, max_bytes: usize) -> Self {
+        Self {
+            window: (window_ms > 0).then(|| Duration::from_millis(window_ms)),
+            max_bytes,
+        }
+    }
+
+    pub fn is_per_frame(&self) -> bool {
+        self.window.is_none() && self.max_bytes == 0
+    }
+
+    pub fn describe(&self) -> String {
+        match (self.window, self.max_bytes) {
+            (None, 0) => "per delta".to_string(),
+            (Some(window), 0) => format!("every {}ms", window.as_millis()),
+            (None, bytes) => format!("every {} bytes", bytes),
+            (Some(window), bytes) => {
+                format!("every {}ms or {} bytes", window.as_millis(), bytes)
+            }
+        }
+    }
+}
+
+struct Coalescer<S> {
+    frames: S,
+    policy: FlushPolicy,
+    finished: bool,
+}
+
+/// Group `frames` into body chunks according to `policy`. Each chunk is
+/// written (and flushed) by hyper as one unit.
+pub fn coalesce<S>(
+    frames: S,
+    policy: FlushPolicy,
+) -> impl Stream<Item = Result<Bytes, Infallible>> + Send
+where
+    S: Stream<Item = String> + Unpin + Send,
+{
+    let state = Coalescer {
+        frames,
+        policy,
+        finished: false,
+    };
+    futures_util::stream::unfold(state, |mut state| async move {
+        if state.finished {
+            return None;
+        }
+        let mut buffer = String::new();
+        let mut deadline = None;
+        loop {
+            let next = match deadline {
+                Some(deadline) => {
+                    match tokio::time::timeout_at(deadline, state.frames.next()).await {
+                        Ok(next) => next,
+                        // The window closed with frames waiting
+                        Err(_) => break,
+                    }
+                }
+                None => state.frames.next().await,
+            };
+            let Some(frame) = next else {
+                state.finished = true;
+                break;
+            };
+            if buffer.is_empty() {
+                deadline = state.policy.window.map(|window| Instant::now() + window);
+            }
+            buffer.push_str(&frame);
+            let size_reached = state.policy.max_bytes > 0 && buffer.len() >= state.policy.max_bytes;
+            if state.policy.is_per_frame() || size_reached {
+                break;
+            }
+        }
+        (!buffer.is_empty()).then(|| (Ok(Bytes::from(buffer)), state))
+    })
+}
@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+/// Where the ids in responses come from: completion and chunk ids, generated
+/// tool call ids and `obfuscation` padding. Seeded, every run yields the same
+/// sequence, so output can be compared byte for byte.
+#[derive(Debug)]
+pub struct IdSource {
+    seed: Option<u64>,
+    next: AtomicU64,
+}
+
+impl IdSource {
+    /// `None` draws random ids.
+    pub fn new(seed: Option<u64>) -> Self {
+        Self {
+            seed,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    /// A version 4 UUID: random, or the next one in the seeded sequence.
+    pub fn uuid(&self) -> Uuid {
+        let Some(seed) = self.seed else {
+            return Uuid::new_v4();
+        };
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&splitmix64(seed ^ n.wrapping_mul(2)).to_le_bytes());
+        bytes[8..]
+            .copy_from_slice(&splitmix64(seed ^ n.wrapping_mul(2).wrapping_add(1)).to_le_bytes());
+        uuid::Builder::from_random_bytes(bytes).into_uuid()
+    }
+
+    /// Unix time for `created` when the backend reports none; seeded, always 0.
+    pub fn now(&self) -> i64 {
+        match self.seed {
+            Some(_) => 0,
+            None => chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+// Spreads consecutive inputs over the whole 64-bit range
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
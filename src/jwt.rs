@@ -0,0 +1,81 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde_json::{json, Value};
+
+// Namespaced claim the ChatGPT OAuth tokens use for account details.
+const OPENAI_AUTH_CLAIM: &str = "https://api.openai.com/auth";
+
+/// Decode the claims of a JWT without verifying its signature.
+pub fn decode_claims(token: &str) -> Result<Value> {
+    let payload = token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| anyhow!("access token is not a JWT"))?;
+    // Some issuers pad the segments; the URL-safe engine here expects them unpadded.
+    let bytes = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .context("access token payload is not valid base64url")?;
+    serde_json::from_slice(&bytes).context("access token payload is not valid JSON")
+}
+
+/// Extract the ChatGPT account id from an access token's claims.
+pub fn account_id_from_token(token: &str) -> Result<String> {
+    let claims = decode_claims(token)?;
+    claims
+        .get(OPENAI_AUTH_CLAIM)
+        .and_then(|auth| auth.get("chatgpt_account_id"))
+        .or_else(|| claims.get("chatgpt_account_id"))
+        .and_then(|v| v.as_str())
+        .map(ToString::to_string)
+        .ok_or_else(|| anyhow!("access token has no chatgpt_account_id claim"))
+}
+
+/// What a bearer credential looks like, for diagnostics only; nothing is
+/// verified. JWTs also report the account id and OAuth scopes they claim.
+pub fn describe_token(token: &str) -> Value {
+    let Ok(claims) = decode_claims(token) else {
+        let kind = if token.starts_with("sk-") {
+            "api_key"
+        } else {
+            "opaque"
+        };
+        return json!({ "token_kind": kind });
+    };
+    let auth = claims.get(OPENAI_AUTH_CLAIM);
+    let account_id = auth
+        .and_then(|auth| auth.get("chatgpt_account_id"))
+        .or_else(|| claims.get("chatgpt_account_id"))
+        .and_then(|v| v.as_str());
+    // Issuers use `scp` (a list) or `scope` (space-separated)
+    let scopes = match claims.get("scp").or_else(|| claims.get("scope")) {
+        Some(Value::Array(scopes)) => scopes
+            .iter()
+            .filter_map(|s| s.as_str())
+            .map(ToString::to_string)
+            .collect(),
+        Some(Value::String(scopes)) => scopes.split_whitespace().map(ToString::to_string).collect(),
+        _ => Vec::new(),
+    };
+    let kind = if auth.is_some() || account_id.is_some() {
+        "chatgpt_oauth"
+    } else {
+        "jwt"
+    };
+    json!({
+        "token_kind": kind,
+        "account_id": account_id,
+        "plan_type": auth.and_then(|auth| auth.get("chatgpt_plan_type")),
+        "scopes": scopes
+    })
+}
+
+/// Issue (`iat`, if present) and expiry (`exp`) times of a token, in Unix seconds.
+pub fn token_lifetime(token: &str) -> Result<(Option<i64>, i64)> {
+    let claims = decode_claims(token)?;
+    let expires_at = claims
+        .get("exp")
+        .and_then(|v| v.as_i64())
+        .ok_or_else(|| anyhow!("access token has no exp claim"))?;
+    Ok((claims.get("iat").and_then(|v| v.as_i64()), expires_at))
+}
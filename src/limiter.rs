@@ -0,0 +1,72 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Caps concurrent upstream requests and how many may wait for a slot.
+#[derive(Debug)]
+pub struct RequestLimiter {
+    semaphore: Option<Arc<Semaphore>>,
+    max_queue_depth: usize,
+    queued: AtomicUsize,
+}
+
+/// Returned when every slot is busy and the wait queue is full.
+#[derive(Debug)]
+pub struct QueueFull;
+
+// Decrements the queue depth even if the waiting request is cancelled.
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RequestLimiter {
+    /// `max_concurrent == 0` disables limiting entirely.
+    pub fn new(max_concurrent: usize, max_queue_depth: usize) -> Self {
+        Self {
+            semaphore: (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))),
+            max_queue_depth,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Wait for a slot, or fail immediately when the queue is already full.
+    pub async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, QueueFull> {
+        let Some(semaphore) = &self.semaphore else {
+            return Ok(None);
+        };
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+
+        let reserved = self
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                (queued < self.max_queue_depth).then_some(queued + 1)
+            });
+        if reserved.is_err() {
+            return Err(QueueFull);
+        }
+        let _guard = QueuedGuard(&self.queued);
+
+        // The semaphore is never closed, so acquiring can only succeed
+        Ok(semaphore.clone().acquire_owned().await.ok())
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let name = "codex_proxy_queue_depth";
+        let _ = writeln!(out, "# HELP {name} Requests waiting for a concurrency slot");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {}", self.queue_depth());
+        out
+    }
+}
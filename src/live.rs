@@ -0,0 +1,589 @@
+use super::{
+    ChatCompletionsRequest, ChatCompletionsResponse, ChunkFrames, ProxyError, ProxyServer,
+    ResolvedModel, StreamOptions, UpstreamExchange,
+};
+use crate::access_log::CompletionInfo;
+use crate::routing::UpstreamRoute;
+use crate::sse::{AccumulatedOutput, AccumulatedToolCall, ResponseAccumulator};
+use futures_util::{Stream, StreamExt};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+
+/// A streaming completion forwarded while the backend generates it. It only
+/// exists once the first upstream event has arrived, so a request that fails
+/// before that still gets a regular error status (and its fallback models).
+pub struct LiveStream {
+    proxy: ProxyServer,
+    exchange: UpstreamExchange,
+    accumulator: ResponseAccumulator,
+    forwarder: Forwarder,
+    upstream_latency: std::time::Duration,
+    conversation_id: Option<String>,
+    /// What to send again if the answer comes back empty (`--retry-empty`),
+    /// taken by the one retry
+    retry: Option<(ChatCompletionsRequest, Arc<UpstreamRoute>)>,
+    // Held until the stream ends, so a live stream keeps its concurrency slot
+    permit: Option<OwnedSemaphorePermit>,
+    finished: bool,
+}
+
+/// Send the request and wait for the backend's first event.
+pub async fn open(
+    proxy: &ProxyServer,
+    chat_req: ChatCompletionsRequest,
+    resolved_model: ResolvedModel,
+    route: &Arc<UpstreamRoute>,
+    options: StreamOptions,
+) -> Result<LiveStream, ProxyError> {
+    let stream = proxy
+        .with_model_fallback(resolved_model, |model| {
+            start(proxy, chat_req.clone(), model, route, options)
+        })
+        .await?;
+    println!(
+        "   Streaming live from backend model '{}'",
+        stream.exchange.resolved_model.backend_model
+    );
+    Ok(stream)
+}
+
+async fn start(
+    proxy: &ProxyServer,
+    chat_req: ChatCompletionsRequest,
+    resolved_model: ResolvedModel,
+    route: &Arc<UpstreamRoute>,
+    options: StreamOptions,
+) -> Result<LiveStream, ProxyError> {
+    let retry = proxy.retry_empty.then(|| (chat_req.clone(), route.clone()));
+    let mut exchange = proxy.open_upstream(chat_req, resolved_model, route).await?;
+    let mut accumulator = proxy.response_accumulator();
+    match exchange.events.next().await? {
+        Some(event) => accumulator.handle_event(&event),
+        // Nothing to stream: the whole answer is handled when the stream finishes
+        None => {
+            if let Some(body) = exchange.events.take_object()? {
+                accumulator.handle_response_object(&body);
+            }
+        }
+    }
+    let upstream_latency = exchange.started.elapsed();
+
+    let id = match accumulator.response_id() {
+        Some(upstream_id) if proxy.upstream_based_ids => format!("chatcmpl-{}", upstream_id),
+        _ => format!("chatcmpl-{}", proxy.ids.uuid()),
+    };
+    let frames = ChunkFrames {
+        id,
+        object: proxy.object_names.chunk.clone(),
+        created: accumulator.created_at().unwrap_or_else(|| proxy.ids.now()),
+        model: exchange.resolved_model.request_model.clone(),
+        service_tier: accumulator
+            .service_tier()
+            .map_or_else(|| exchange.requested_tier.clone(), str::to_string),
+        include_usage: options.include_usage.unwrap_or(false),
+        include_obfuscation: options.include_obfuscation.unwrap_or(false),
+        logprobs: proxy.include_logprobs,
+        ids: proxy.ids.clone(),
+    };
+    let mut forwarder = Forwarder {
+        frames,
+        reasoning_field: proxy.reasoning_field.clone(),
+        prefix: proxy.response_prefix.clone(),
+        suffix: proxy.response_suffix.clone(),
+        sent: Sent::default(),
+        pending: VecDeque::new(),
+    };
+    forwarder.push_delta(json!({ "role": proxy.response_role }));
+    forwarder.forward(
+        accumulator.content(),
+        accumulator.refusal(),
+        accumulator.reasoning(),
+        accumulator.tool_calls(),
+    );
+
+    Ok(LiveStream {
+        proxy: proxy.clone(),
+        exchange,
+        accumulator,
+        forwarder,
+        upstream_latency,
+        conversation_id: None,
+        retry,
+        permit: None,
+        finished: false,
+    })
+}
+
+impl LiveStream {
+    /// Charge the tokens to this conversation's budget once the stream ends.
+    pub fn recording_usage(mut self, conversation_id: Option<String>) -> Self {
+        self.conversation_id = conversation_id;
+        self
+    }
+
+    pub fn holding(mut self, permit: Option<OwnedSemaphorePermit>) -> Self {
+        self.permit = permit;
+        self
+    }
+
+    /// What the access log knows when the response starts; the token count
+    /// is only known once the stream has ended.
+    pub fn completion_info(&self) -> CompletionInfo {
+        CompletionInfo {
+            id: self.forwarder.frames.id.clone(),
+            model: self.forwarder.frames.model.clone(),
+            total_tokens: None,
+            upstream_latency: Some(self.upstream_latency),
+        }
+    }
+
+    pub fn upstream_id(&self) -> Option<&str> {
+        self.accumulator.response_id()
+    }
+
+    pub fn served_model(&self) -> &str {
+        &self.exchange.resolved_model.backend_model
+    }
+
+    /// The SSE frames, each yielded as soon as the upstream event behind it
+    /// has been read. `[DONE]` follows only once the upstream stream closes.
+    pub fn frames(self) -> impl Stream<Item = String> + Send {
+        futures_util::stream::unfold(self, |mut live| async move {
+            loop {
+                if let Some(frame) = live.forwarder.pending.pop_front() {
+                    return Some((frame, live));
+                }
+                if live.finished {
+                    return None;
+                }
+                live.advance().await;
+            }
+        })
+    }
+
+    /// The frames, read from the backend on a task of their own and handed
+    /// over a channel holding at most `capacity` of them. A slow client fills
+    /// the channel, which pauses the reading; a client that goes away closes
+    /// it, which ends the task and drops the upstream connection.
+    pub fn pipe(self, capacity: usize) -> impl Stream<Item = String> + Send {
+        pipe(self.frames(), capacity)
+    }
+
+    async fn advance(&mut self) {
+        match self.exchange.events.next().await {
+            Ok(Some(event)) => {
+                self.accumulator.handle_event(&event);
+                self.forwarder.forward(
+                    self.accumulator.content(),
+                    self.accumulator.refusal(),
+                    self.accumulator.reasoning(),
+                    self.accumulator.tool_calls(),
+                );
+            }
+            Ok(None) => {
+                let output = std::mem::take(&mut self.accumulator).finish();
+                if is_empty_answer(&output) && self.forwarder.sent.is_answer_empty() {
+                    if let Some((chat_req, route)) = self.retry.take() {
+                        return self.retry_empty(chat_req, &route).await;
+                    }
+                }
+                self.finish(output)
+            }
+            Err(e) => self.fail(&e),
+        }
+    }
+
+    /// Send the request once more after an answer without content, carrying
+    /// on in the same client stream. Only the role (and any reasoning) has
+    /// been sent so far, so the retry's output simply follows it. Reasoning
+    /// already sent stays the only reasoning; the retry's own is dropped.
+    async fn retry_empty(&mut self, chat_req: ChatCompletionsRequest, route: &UpstreamRoute) {
+        eprintln!("⚠️  Backend returned no content, retrying once (--retry-empty)");
+        let model = self.exchange.resolved_model.clone();
+        let proxy = self.proxy.clone();
+        let opened = proxy
+            .with_model_fallback(model, |model| {
+                proxy.open_upstream(chat_req.clone(), model, route)
+            })
+            .await;
+        match opened {
+            Ok(exchange) => {
+                self.exchange = exchange;
+                let mut accumulator = self.proxy.response_accumulator();
+                if self.forwarder.sent.reasoning > 0 {
+                    accumulator = accumulator.dropping_reasoning(true);
+                }
+                self.accumulator = accumulator;
+            }
+            Err(e) => self.fail(&e),
+        }
+    }
+
+    /// Forward whatever only the completed items carried, then the finish
+    /// reason, usage and `[DONE]`.
+    fn finish(&mut self, output: AccumulatedOutput) {
+        self.finished = true;
+        self.forwarder.forward(
+            &output.content,
+            &output.refusal,
+            &output.reasoning,
+            output.tool_calls.iter(),
+        );
+        if self.forwarder.sent.content > 0 && !self.forwarder.suffix.is_empty() {
+            let suffix = self.forwarder.suffix.clone();
+            self.forwarder.push_delta(json!({ "content": suffix }));
+        }
+
+        let response = self.proxy.build_chat_response(
+            output,
+            self.exchange.resolved_model.clone(),
+            self.exchange.requested_tier.clone(),
+            &self.exchange.prompt_messages,
+            self.exchange.started.elapsed(),
+            self.exchange.events.truncated(),
+        );
+        let mut response: ChatCompletionsResponse = match response {
+            Ok(response) => response,
+            Err(e) => return self.fail(&e),
+        };
+        response.id = self.forwarder.frames.id.clone();
+        let finish_reason = response.choices[0].finish_reason.clone();
+        let frames = &self.forwarder.frames;
+        let mut tail = vec![frames.chunk(0, json!({}), finish_reason.as_deref())];
+        if frames.include_usage {
+            tail.push(frames.usage(&response.usage));
+        }
+        tail.push("data: [DONE]\n\n".to_string());
+        self.forwarder.pending.extend(tail);
+
+        self.proxy
+            .record_usage(&response, self.conversation_id.as_deref());
+        println!(
+            "   Live stream finished: {} chunks, {} tokens",
+            self.forwarder.sent.chunks,
+            response
+                .usage
+                .as_ref()
+                .map_or(0, |usage| usage.total_tokens)
+        );
+        self.permit.take();
+    }
+
+    /// The status line has already been sent, so a failure mid-stream ends
+    /// it with an error frame instead, and without `[DONE]`.
+    fn fail(&mut self, error: &ProxyError) {
+        eprintln!(
+            "Proxy error mid-stream (retryable: {}): {}",
+            error.is_retryable(),
+            error
+        );
+        self.finished = true;
+        let body = error.to_body(&self.proxy.error_messages);
+        self.forwarder
+            .pending
+            .push_back(format!("data: {}\n\n", body));
+        self.permit.take();
+    }
+}
+
+/// Drive `frames` on a task of its own, buffering at most `capacity` of them
+/// for the returned stream. The task waits while the buffer is full and
+/// stops once the returned stream is dropped.
+fn pipe<S>(frames: S, capacity: usize) -> impl Stream<Item = String> + Send
+where
+    S: Stream<Item = String> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(capacity);
+    tokio::spawn(async move {
+        let mut frames = Box::pin(frames);
+        while let Some(frame) = frames.next().await {
+            if tx.send(frame).await.is_err() {
+                println!("   Client went away mid-stream, closing the upstream stream");
+                return;
+            }
+        }
+    });
+    futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|frame| (frame, rx))
+    })
+}
+
+/// How much of each output has already been sent to the client.
+#[derive(Default)]
+struct Sent {
+    content: usize,
+    refusal: usize,
+    reasoning: usize,
+    /// Id and argument bytes of each tool call, in order of first appearance
+    tool_calls: Vec<(String, usize)>,
+    prefix: bool,
+    chunks: usize,
+}
+
+impl Sent {
+    /// Nothing of the answer itself has gone out yet: no text, refusal or tool call.
+    fn is_answer_empty(&self) -> bool {
+        self.content == 0 && self.refusal == 0 && self.tool_calls.is_empty()
+    }
+}
+
+/// An answer `build_chat_response` rejects as empty; a content-filtered one
+/// is an answer of its own and not retried.
+fn is_empty_answer(output: &AccumulatedOutput) -> bool {
+    output.content.is_empty()
+        && output.refusal.is_empty()
+        && output.tool_calls.is_empty()
+        && output.incomplete_reason.as_deref() != Some("content_filter")
+}
+
+/// Turns accumulated upstream output into delta chunks for whatever has not
+/// been sent yet.
+struct Forwarder {
+    frames: ChunkFrames,
+    reasoning_field: String,
+    prefix: String,
+    suffix: String,
+    sent: Sent,
+    pending: VecDeque<String>,
+}
+
+impl Forwarder {
+    fn push_delta(&mut self, delta: Value) {
+        self.sent.chunks += 1;
+        self.pending.push_back(self.frames.chunk(0, delta, None));
+    }
+
+    fn forward<'a>(
+        &mut self,
+        content: &str,
+        refusal: &str,
+        reasoning: &str,
+        tool_calls: impl Iterator<Item = &'a AccumulatedToolCall>,
+    ) {
+        // Reasoning deltas arrive ahead of the answer, so they keep that order
+        if let Some(text) = unsent(reasoning, self.sent.reasoning) {
+            self.sent.reasoning = reasoning.len();
+            let field = self.reasoning_field.clone();
+            self.push_delta(json!({ field: text }));
+        }
+        if let Some(text) = unsent(content, self.sent.content) {
+            if !self.sent.prefix && !self.prefix.is_empty() {
+                let prefix = self.prefix.clone();
+                self.push_delta(json!({ "content": prefix }));
+            }
+            self.sent.prefix = true;
+            self.sent.content = content.len();
+            self.push_delta(json!({ "content": text }));
+        }
+        if let Some(text) = unsent(refusal, self.sent.refusal) {
+            self.sent.refusal = refusal.len();
+            self.push_delta(json!({ "refusal": text }));
+        }
+
+        for (index, call) in tool_calls.enumerate() {
+            // A call is announced with its id and name, so wait for the name
+            if index == self.sent.tool_calls.len() {
+                if call.name.is_empty() {
+                    break;
+                }
+                let id = if call.call_id.is_empty() {
+                    format!("call_{}", self.frames.ids.uuid().simple())
+                } else {
+                    call.call_id.clone()
+                };
+                self.push_delta(json!({ "tool_calls": [{
+                    "index": index,
+                    "id": id,
+                    "type": "function",
+                    "function": { "name": call.name, "arguments": "" }
+                }] }));
+                self.sent.tool_calls.push((id, 0));
+            }
+            if let Some(text) = unsent(&call.arguments, self.sent.tool_calls[index].1) {
+                self.sent.tool_calls[index].1 = call.arguments.len();
+                self.push_delta(json!({ "tool_calls": [{
+                    "index": index,
+                    "function": { "arguments": text }
+                }] }));
+            }
+        }
+    }
+}
+
+/// The part of `text` after the first `sent` bytes, if there is any. Text
+/// that was replaced rather than extended (a completed item superseding its
+/// deltas) cannot be taken back, so it is not resent.
+fn unsent(text: &str, sent: usize) -> Option<&str> {
+    text.get(sent..).filter(|rest| !rest.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids::IdSource;
+    use crate::sse::{parse_line, LineDecoder, SseLine};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn forwarder() -> Forwarder {
+        Forwarder {
+            frames: ChunkFrames {
+                id: "chatcmpl-test".to_string(),
+                object: "chat.completion.chunk".to_string(),
+                created: 0,
+                model: "gpt-5".to_string(),
+                service_tier: "default".to_string(),
+                include_usage: false,
+                include_obfuscation: false,
+                logprobs: false,
+                ids: Arc::new(IdSource::new(Some(1))),
+            },
+            reasoning_field: "reasoning_content".to_string(),
+            prefix: String::new(),
+            suffix: String::new(),
+            sent: Sent::default(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Feed raw upstream body chunks through the decoder, accumulator and
+    /// forwarder as a live stream does, returning the emitted chunk JSON.
+    fn stream(chunks: &[&[u8]]) -> Vec<Value> {
+        let mut decoder = LineDecoder::new();
+        let mut accumulator = ResponseAccumulator::new();
+        let mut forwarder = forwarder();
+        for chunk in chunks {
+            for line in decoder.push(chunk) {
+                if let SseLine::Event(event) = parse_line(&line) {
+                    accumulator.handle_event(&event);
+                    forwarder.forward(
+                        accumulator.content(),
+                        accumulator.refusal(),
+                        accumulator.reasoning(),
+                        accumulator.tool_calls(),
+                    );
+                }
+            }
+        }
+        forwarder
+            .pending
+            .iter()
+            .map(|frame| {
+                let data = frame.strip_prefix("data: ").unwrap().trim_end();
+                serde_json::from_str(data).expect("every frame is valid JSON")
+            })
+            .collect()
+    }
+
+    fn delta_event(index: u64, delta: &str) -> String {
+        let event =
+            json!({ "type": "response.output_text.delta", "output_index": index, "delta": delta });
+        format!("data: {}\n\n", event)
+    }
+
+    #[test]
+    fn emoji_split_across_network_chunks_stays_whole() {
+        let body = [
+            "data: {\"type\":\"response.output_item.added\",\"output_index\":0,\"item\":{\"type\":\"message\"}}\n\n".to_string(),
+            delta_event(0, "Hi "),
+            delta_event(0, "😀"),
+            delta_event(0, "🎉 done"),
+        ]
+        .concat();
+        let bytes = body.as_bytes();
+        // Cut inside both emoji, whose UTF-8 forms are four bytes long
+        let first = body.find('😀').unwrap() + 2;
+        let second = body.find('🎉').unwrap() + 1;
+        let chunks = stream(&[&bytes[..first], &bytes[first..second], &bytes[second..]]);
+
+        let contents: Vec<&str> = chunks
+            .iter()
+            .map(|chunk| chunk["choices"][0]["delta"]["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(contents, ["Hi ", "😀", "🎉 done"]);
+        for content in contents {
+            assert!(std::str::from_utf8(content.as_bytes()).is_ok());
+            assert!(!content.contains(char::REPLACEMENT_CHARACTER));
+        }
+    }
+
+    #[test]
+    fn concurrent_tool_calls_keep_their_own_indices() {
+        let added = |index: u64, call_id: &str, name: &str| {
+            let event = json!({
+                "type": "response.output_item.added",
+                "output_index": index,
+                "item": { "type": "function_call", "call_id": call_id, "name": name, "arguments": "" }
+            });
+            format!("data: {}\n\n", event)
+        };
+        let arguments = |index: u64, delta: &str| {
+            let event = json!({
+                "type": "response.function_call_arguments.delta",
+                "output_index": index,
+                "delta": delta
+            });
+            format!("data: {}\n\n", event)
+        };
+        let body = [
+            added(1, "call_weather", "get_weather"),
+            added(2, "call_time", "get_time"),
+            arguments(1, "{\"city\":"),
+            arguments(2, "{\"zone\":"),
+            arguments(2, "\"UTC\"}"),
+            arguments(1, "\"Paris\"}"),
+        ]
+        .concat();
+        let chunks = stream(&[body.as_bytes()]);
+
+        let mut ids = Vec::new();
+        let mut assembled = [String::new(), String::new()];
+        for chunk in &chunks {
+            let call = &chunk["choices"][0]["delta"]["tool_calls"][0];
+            let index = call["index"].as_u64().unwrap() as usize;
+            if let Some(id) = call.get("id") {
+                ids.push((index, id.as_str().unwrap()));
+            }
+            assembled[index].push_str(call["function"]["arguments"].as_str().unwrap());
+        }
+        // Each id goes out once, on the call's first chunk
+        assert_eq!(ids, [(0, "call_weather"), (1, "call_time")]);
+        assert_eq!(assembled, ["{\"city\":\"Paris\"}", "{\"zone\":\"UTC\"}"]);
+        assert_eq!(
+            chunks[0]["choices"][0]["delta"]["tool_calls"][0]["function"]["name"],
+            "get_weather"
+        );
+        assert_eq!(
+            chunks[1]["choices"][0]["delta"]["tool_calls"][0]["function"]["name"],
+            "get_time"
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_client_pauses_the_producer() {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let frames = futures_util::stream::iter(0..100).map({
+            let produced = produced.clone();
+            move |n| {
+                produced.fetch_add(1, Ordering::SeqCst);
+                format!("data: {}\n\n", n)
+            }
+        });
+        let settle = || tokio::time::sleep(std::time::Duration::from_millis(20));
+
+        let mut piped = Box::pin(pipe(frames, 2));
+        settle().await;
+        // Two frames fill the channel and a third waits to be sent
+        assert_eq!(produced.load(Ordering::SeqCst), 3);
+
+        assert_eq!(piped.next().await.as_deref(), Some("data: 0\n\n"));
+        settle().await;
+        assert_eq!(produced.load(Ordering::SeqCst), 4);
+
+        // A client that goes away stops the producer for good
+        drop(piped);
+        settle().await;
+        assert_eq!(produced.load(Ordering::SeqCst), 4);
+    }
+}
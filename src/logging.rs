@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+/// A log file that is renamed to `<path>.1` (shifting older files up to
+/// `<path>.<keep>`) once it would grow past `max_bytes`.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64, keep: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, generation: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", generation));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for generation in (1..self.keep).rev() {
+                let from = self.rotated_path(generation);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated_path(generation + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+/// What is written to stdout/stderr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Console {
+    /// Everything
+    All,
+    /// Errors and access log records only
+    Quiet,
+    /// Nothing; output only goes to the log file
+    Off,
+}
+
+struct LogSink {
+    file: Option<Mutex<RotatingFile>>,
+    console: Console,
+}
+
+static SINK: OnceLock<LogSink> = OnceLock::new();
+
+/// Route log output to `path` (when given) with size-based rotation, and to
+/// the console as `console` allows. Until this runs, everything goes to the
+/// console only.
+pub fn init(path: Option<&Path>, max_bytes: u64, keep: usize, console: Console) -> Result<()> {
+    let file = path
+        .map(|path| RotatingFile::open(path, max_bytes, keep).map(Mutex::new))
+        .transpose()?;
+    let _ = SINK.set(LogSink { file, console });
+    Ok(())
+}
+
+/// Write one line of output; `is_error` lines go to stderr on the console.
+pub fn write_line(is_error: bool, args: fmt::Arguments) {
+    let console = SINK.get().map_or(Console::All, |sink| sink.console);
+    let to_console = match console {
+        Console::All => true,
+        Console::Quiet => is_error,
+        Console::Off => false,
+    };
+    write(is_error, to_console, args);
+}
+
+/// Write one access log record, which `--quiet` still prints.
+pub fn write_record(args: fmt::Arguments) {
+    let console = SINK.get().map_or(Console::All, |sink| sink.console);
+    write(false, console != Console::Off, args);
+}
+
+fn write(is_error: bool, to_console: bool, args: fmt::Arguments) {
+    let sink = SINK.get();
+    if to_console {
+        if is_error {
+            let _ = writeln!(std::io::stderr(), "{}", args);
+        } else {
+            let _ = writeln!(std::io::stdout(), "{}", args);
+        }
+    }
+
+    if let Some(file) = sink.and_then(|sink| sink.file.as_ref()) {
+        let line = format!(
+            "{} {} {}",
+            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            if is_error { "ERROR" } else { "INFO " },
+            args
+        );
+        let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // A full disk should not take the proxy down with it
+        let _ = file.write_line(&line);
+    }
+}
+
+/// `io::Write` adapter so `env_logger` records share the same destinations.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        for line in text.lines() {
+            write_line(true, format_args!("{}", line));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,24 +1,549 @@
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use futures_util::StreamExt;
 use reqwest::Client;
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
 use uuid::Uuid;
 use warp::{Filter, Reply};
 
+// All console output goes through `logging` so --log-file captures it. These
+// shadow the std macros in this file and every module declared below.
+macro_rules! println {
+    ($($arg:tt)*) => {
+        $crate::logging::write_line(false, format_args!($($arg)*))
+    };
+}
+
+macro_rules! eprintln {
+    ($($arg:tt)*) => {
+        $crate::logging::write_line(true, format_args!($($arg)*))
+    };
+}
+
+mod access_log;
+mod accounts;
+mod budget;
+mod error;
+mod flush;
+mod ids;
+mod jwt;
+mod limiter;
+mod live;
+mod logging;
+mod metrics;
+mod mock;
+mod profiles;
+mod recent;
+mod refresh;
+mod replay;
+mod routing;
+mod sse;
+mod throughput;
+mod tokens;
+mod transforms;
+
+use access_log::{AccessRecord, CompletionInfo, LogFormat};
+use accounts::{Account, AccountPool};
+use budget::ConversationBudgets;
+use error::{ErrorMessages, ProxyError};
+use flush::FlushPolicy;
+use ids::IdSource;
+use limiter::RequestLimiter;
+use metrics::Metrics;
+use recent::RecentRequests;
+use routing::{Router, UpstreamRoute};
+use sse::{AccumulatedOutput, LineDecoder, ResponseAccumulator, SseLine, UpstreamUsage};
+use throughput::TokenThroughput;
+use tokens::{EstimatorKind, TokenEstimator};
+use transforms::Transform;
+
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+// Options from a config profile come first on the command line, so the
+// user's own flags must be able to replace them
+#[command(author, version, about, long_about = None, args_override_self = true)]
 struct Args {
-    /// Port to listen on
-    #[arg(short, long, default_value = "8080")]
+    /// Port to listen on (falls back to the PORT environment variable)
+    #[arg(short, long, env = "PORT", default_value = "8080")]
     port: u16,
 
-    /// Path to Codex auth.json file
+    /// Path to Codex auth.json file. Repeat to rotate across several accounts;
+    /// append `@WEIGHT` to send an account proportionally more requests.
     #[arg(long, default_value = "~/.codex/auth.json")]
-    auth_path: String,
+    auth_path: Vec<String>,
+
+    /// Scale account weights by the remaining quota reported in rate-limit headers
+    #[arg(long)]
+    quota_aware_routing: bool,
+
+    /// Milliseconds to wait before retrying on the next account after a 429
+    /// (0 = retry at once)
+    #[arg(long, env = "ACCOUNT_RETRY_DELAY_MS", default_value = "250")]
+    account_retry_delay_ms: u64,
+
+    /// Send each client's own `Authorization` header upstream instead of
+    /// loading auth.json. Clients must then hold valid backend tokens.
+    #[arg(long, conflicts_with_all = ["auth_path", "account_models"])]
+    forward_auth: bool,
+
+    /// ChatGPT account id sent as `chatgpt-account-id` for every account,
+    /// instead of the one in auth.json. The tokens still come from the file.
+    #[arg(long, env = "CHATGPT_ACCOUNT_ID", conflicts_with = "forward_auth")]
+    account_id: Option<String>,
+
+    /// Role reported on responses when the backend does not specify one
+    #[arg(long, default_value = "assistant")]
+    response_role: String,
+
+    /// Text put before every assistant answer, e.g. a label or disclaimer
+    #[arg(long, env = "RESPONSE_PREFIX", default_value = "")]
+    response_prefix: String,
+
+    /// Text put after every assistant answer
+    #[arg(long, env = "RESPONSE_SUFFIX", default_value = "")]
+    response_suffix: String,
+
+    /// Instructions for requests without a system or developer message,
+    /// replacing the built-in prompt
+    #[arg(long, env = "PROXY_INSTRUCTIONS")]
+    instructions: Option<String>,
+
+    /// Derive the `chatcmpl-` id from the upstream response id instead of a random UUID
+    #[arg(long)]
+    upstream_based_ids: bool,
+
+    /// Generate completion, chunk and tool call ids from this seed instead of
+    /// randomly, so output is reproducible (for tests and snapshots)
+    #[arg(long, env = "ID_SEED")]
+    id_seed: Option<u64>,
+
+    /// Maximum concurrent upstream requests (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    max_concurrent_requests: usize,
+
+    /// Requests allowed to wait for a slot before new ones get 503
+    #[arg(long, default_value = "32")]
+    max_queue_depth: usize,
+
+    /// Retry-After seconds sent with 503 responses when the queue is full
+    #[arg(long, default_value = "1")]
+    queue_retry_after: u64,
+
+    /// Upstream requests run at once for one `n > 1` completion (0 = all)
+    #[arg(long, env = "FANOUT_CONCURRENCY", default_value = "4")]
+    fanout_concurrency: usize,
+
+    /// When some `n > 1` upstream requests fail, return the choices that
+    /// succeeded instead of failing the whole completion
+    #[arg(long)]
+    partial_fanout: bool,
+
+    /// Retry once when the backend answers with no content at all, before
+    /// failing with `empty_response`; live streams retry too while nothing
+    /// of the answer has been sent
+    #[arg(long)]
+    retry_empty: bool,
+
+    /// When `tool_choice` demands a tool call and the model answers in text
+    /// anyway: return the text (off), retry once with a stronger instruction, or fail
+    #[arg(long, env = "ENFORCE_TOOL_CHOICE", value_enum, default_value = "off")]
+    enforce_tool_choice: ToolChoiceEnforcement,
+
+    /// Who decides when a body asks for `stream: true` but the `Accept`
+    /// header only admits JSON: stream anyway (body-wins) or answer with a
+    /// single JSON response (accept-wins)
+    #[arg(
+        long,
+        env = "STREAM_ACCEPT_CONFLICT",
+        value_enum,
+        default_value = "body-wins"
+    )]
+    stream_accept_conflict: StreamAcceptConflict,
+
+    /// Largest `n` a request may ask for (0 = no limit). Larger values are
+    /// clamped to the cap.
+    #[arg(long, env = "MAX_N", default_value = "0")]
+    max_n: u32,
+
+    /// Reject requests whose `n` exceeds --max-n instead of clamping
+    #[arg(long)]
+    strict_max_n: bool,
+
+    /// Extra header for upstream requests as "Name: Value" (repeatable). Replaces
+    /// any built-in header of the same name. Also read from UPSTREAM_HEADERS.
+    #[arg(long = "upstream-header", value_name = "NAME: VALUE")]
+    upstream_headers: Vec<String>,
+
+    /// Client-facing message for an error as "KEY=Message" (repeatable), where
+    /// KEY is an error code, HTTP status or status class such as `5xx`.
+    /// Also read from ERROR_MESSAGES.
+    #[arg(long = "error-message", value_name = "KEY=MESSAGE")]
+    error_messages: Vec<String>,
+
+    /// Accept-Encoding sent upstream: any of gzip, deflate, br, or identity
+    /// to ask for uncompressed bodies
+    #[arg(long, env = "UPSTREAM_ACCEPT_ENCODING", default_value = DEFAULT_ACCEPT_ENCODING)]
+    upstream_accept_encoding: String,
+
+    /// Fallback chains tried when the backend rejects a model, e.g.
+    /// "gpt-5.3-codex=gpt-5.2-codex|gpt-5,gpt-5.2=gpt-5"
+    #[arg(long, env = "FALLBACK_MODELS", default_value = "")]
+    fallback_models: String,
+
+    /// Backend models each account may serve, keyed by its --auth-path value,
+    /// e.g. "~/.codex/plus.json=gpt-5|gpt-5.2". Unlisted accounts serve every
+    /// allowed model.
+    #[arg(long, env = "ACCOUNT_MODELS", default_value = "")]
+    account_models: String,
+
+    /// Send matching requests to another Responses endpoint, as
+    /// "CONDITION=URL[,api-key-env=VAR]" with CONDITION one of tools, no-tools,
+    /// model:NAME or * (repeatable, first match wins). Also read from UPSTREAM_ROUTES.
+    #[arg(long = "route", value_name = "RULE")]
+    routes: Vec<String>,
+
+    /// Per-path time limits overriding the defaults, e.g.
+    /// "/v1/chat/completions=900,/v1/models=3" (seconds, 0 = no limit, `*` = other paths)
+    #[arg(long, env = "ROUTE_TIMEOUTS", default_value = "")]
+    route_timeouts: String,
+
+    /// Longest wait in seconds for the next upstream chunk of a streaming
+    /// completion (0 = no limit). Streaming completions skip the route timeout.
+    #[arg(long, env = "STREAM_IDLE_TIMEOUT", default_value = "60")]
+    stream_idle_timeout: u64,
+
+    /// Seconds `/health?deep=1` waits for the backend before reporting it
+    /// unreachable, independent of the route timeouts
+    #[arg(long, env = "HEALTH_CHECK_TIMEOUT", default_value = "2")]
+    health_check_timeout: u64,
+
+    /// Stop reading an upstream response after this many SSE events and
+    /// finish it as truncated (0 = no limit)
+    #[arg(long, env = "MAX_SSE_EVENTS", default_value = "100000")]
+    max_sse_events: usize,
+
+    /// Largest tool call arguments kept, in bytes; longer ones are cut off
+    /// and the response finishes as truncated (0 = no limit)
+    #[arg(long, env = "MAX_TOOL_ARGUMENTS_BYTES", default_value = "1048576")]
+    max_tool_arguments_bytes: usize,
+
+    /// Log every upstream SSE line as it is processed: event type, output
+    /// index, item type and delta size
+    #[arg(long)]
+    trace_sse: bool,
+
+    /// Coalesce streamed deltas for up to this many milliseconds before
+    /// flushing (0 = no time window)
+    #[arg(long, env = "STREAM_FLUSH_MS", default_value = "0")]
+    stream_flush_ms: u64,
+
+    /// Flush coalesced deltas once this many bytes are buffered (0 = no size
+    /// threshold). With neither this nor --stream-flush-ms, every delta is
+    /// flushed on its own.
+    #[arg(long, env = "STREAM_FLUSH_BYTES", default_value = "0")]
+    stream_flush_bytes: usize,
+
+    /// Frames a live stream may read ahead of the client. Once this many are
+    /// waiting, reading from the backend pauses until the client catches up
+    #[arg(long, env = "STREAM_CHANNEL_CAPACITY", default_value = "16")]
+    stream_channel_capacity: usize,
+
+    /// Largest `max_tokens` each model accepts, e.g. "gpt-5=128000,gpt-5-codex=64000".
+    /// Larger requests are clamped to the cap.
+    #[arg(long, env = "MAX_OUTPUT_TOKENS", default_value = "")]
+    max_output_tokens: String,
+
+    /// Reject requests whose `max_tokens` exceeds the model's cap instead of clamping
+    #[arg(long)]
+    strict_max_tokens: bool,
+
+    /// Reject chat requests with top-level fields the proxy does not know,
+    /// instead of silently ignoring them
+    #[arg(long)]
+    strict_request_fields: bool,
+
+    /// Most messages a chat request may carry (0 = no limit). Longer requests
+    /// are rejected before conversion.
+    #[arg(long, env = "MAX_MESSAGES", default_value = "0")]
+    max_messages: usize,
+
+    /// Instead of rejecting requests over --max-messages, keep the system
+    /// messages and the most recent non-system ones
+    #[arg(long)]
+    truncate_messages: bool,
+
+    /// Reasoning effort for requests that set none, unless the model has its own
+    #[arg(long, env = "REASONING_EFFORT", value_parser = parse_effort)]
+    reasoning_effort: Option<String>,
+
+    /// Default reasoning effort per model, e.g. "gpt-5=high,gpt-5-codex=low"
+    #[arg(long, env = "MODEL_REASONING_EFFORTS", default_value = "")]
+    model_reasoning_efforts: String,
+
+    /// Client model names served by a backend model, e.g.
+    /// "gpt-4o=gpt-5.2-codex,claude-3-5-sonnet=gpt-5". Mapped names are
+    /// accepted and listed like allowed models; responses keep the client's name
+    #[arg(long, env = "MODEL_MAP", default_value = "")]
+    model_map: String,
+
+    /// Answer errors with HTTP 200 (keeping the error body) for clients that
+    /// treat any non-2xx status as a transport failure
+    #[arg(long)]
+    errors_as_200: bool,
+
+    /// Refresh OAuth access tokens in the background once this fraction of
+    /// their lifetime has passed (0 disables proactive refresh)
+    #[arg(long, env = "TOKEN_REFRESH_AT", default_value = "0.8")]
+    token_refresh_at: f64,
+
+    /// Never call the backend; answer every completion with a deterministic
+    /// canned response run through the normal conversion and streaming code
+    #[arg(long)]
+    mock_upstream: bool,
+
+    /// Send a captured request (a Chat Completions body, or an object with
+    /// method, path, headers and body) through the proxy once, print the
+    /// response and exit without starting the server
+    #[arg(long, value_name = "FILE")]
+    replay: Option<std::path::PathBuf>,
+
+    /// Discard upstream reasoning entirely instead of returning it as
+    /// `reasoning_content`, and never ask the backend for reasoning summaries
+    #[arg(long)]
+    drop_reasoning: bool,
+
+    /// Delta field that carries reasoning in streamed chunks, e.g. `reasoning`
+    /// for clients that expect that name
+    #[arg(long, env = "REASONING_FIELD", default_value = "reasoning_content")]
+    reasoning_field: String,
+
+    /// End every stream with a usage chunk, as if the client had set
+    /// `stream_options.include_usage`, for clients that read usage from the
+    /// last chunk unconditionally
+    #[arg(long)]
+    always_stream_usage: bool,
+
+    /// Send `logprobs: null` on every choice, for SDKs that require the key
+    #[arg(long)]
+    include_logprobs: bool,
+
+    /// Refuse `stream: true` where the answer would be buffered and replayed
+    /// as synthetic chunks, instead of faking the stream
+    #[arg(long)]
+    strict_streaming: bool,
+
+    /// Override the `object` strings in responses, e.g.
+    /// "completion=chat.completion,chunk=chat.completion.chunk,list=list,model=model"
+    #[arg(long, env = "OBJECT_NAMES", default_value = "")]
+    object_names: String,
+
+    /// Access log format: one line per request with status, sizes, model,
+    /// tokens and latencies
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// JSON array of tool definitions added to every request
+    #[arg(long, env = "DEFAULT_TOOLS_FILE")]
+    default_tools_file: Option<String>,
+
+    /// How default tools combine with the client's own
+    #[arg(long, value_enum, default_value = "append")]
+    default_tools_mode: DefaultToolsMode,
+
+    /// Total tokens a conversation (X-Conversation-Id header) may use before
+    /// further requests on it are rejected (0 = unlimited)
+    #[arg(long, env = "CONVERSATION_TOKEN_BUDGET", default_value = "0")]
+    conversation_token_budget: u64,
+
+    /// Tokens all requests together may use per minute before new ones get
+    /// 429 (0 = unlimited; throughput is reported in /metrics either way)
+    #[arg(long, env = "TOKENS_PER_MINUTE", default_value = "0")]
+    tokens_per_minute: u64,
+
+    /// How token counts are estimated when the backend reports none
+    #[arg(long, env = "TOKEN_ESTIMATOR", value_enum, default_value = "heuristic")]
+    token_estimator: EstimatorKind,
+
+    /// Let an X-Upstream-Model header pick the backend model for a request,
+    /// after the requested model has passed the allowlist
+    #[arg(long)]
+    allow_upstream_model_header: bool,
+
+    /// Bearer token required by the /admin endpoints; without one they are disabled
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Requests kept for GET /admin/requests (0 = keep none)
+    #[arg(long, env = "RECENT_REQUESTS", default_value = "100")]
+    recent_requests: usize,
+
+    /// Rewrite request messages before conversion: exec:COMMAND,
+    /// prepend-file:PATH, drop-system or trim (repeatable, applied in order)
+    #[arg(long = "transform", value_name = "TRANSFORM")]
+    transforms: Vec<String>,
+
+    /// Print only errors and access log lines to the console: no banner,
+    /// request dumps or progress messages
+    #[arg(long, short = 'q')]
+    quiet: bool,
+
+    /// Also write logs to this file, rotating it by size
+    #[arg(long, env = "LOG_FILE")]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Rotate the log file once it reaches this many megabytes
+    #[arg(long, default_value = "10")]
+    log_max_size_mb: u64,
+
+    /// Rotated log files to keep as <log-file>.1 ... <log-file>.N
+    #[arg(long, default_value = "5")]
+    log_keep: usize,
+
+    /// Log only to --log-file, not to stdout/stderr
+    #[arg(long, requires = "log_file")]
+    no_console_log: bool,
+
+    /// Log ChatGPT account ids in full instead of masking all but their last
+    /// characters (request headers, bodies and the curl command)
+    #[arg(long)]
+    no_log_redaction: bool,
+
+    /// TOML config file with a [default] table and named [profiles.NAME] tables
+    /// of option values
+    #[arg(long, env = "PROXY_CONFIG")]
+    config: Option<std::path::PathBuf>,
+
+    /// Profile from the config file to apply over its defaults
+    #[arg(long, env = "PROXY_PROFILE")]
+    profile: Option<String>,
+
+    /// Skip loading settings from a .env file in the working directory
+    #[arg(long)]
+    no_dotenv: bool,
+
+    /// How to log requests for paths the proxy does not serve
+    #[arg(long, value_enum, default_value = "all")]
+    unmatched_log: UnmatchedLog,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum UnmatchedLog {
+    /// Log every unmatched request in full
+    All,
+    /// Log at most one line per interval, without the request dump
+    Sampled,
+    /// Answer 404 silently (still counted in /metrics)
+    Off,
+}
+
+/// What to do when the backend ignores a `tool_choice` that demands a tool
+/// call and answers in text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ToolChoiceEnforcement {
+    /// Return the text answer, logging the mismatch
+    Off,
+    /// Ask once more with an instruction to call a tool, then fail
+    Retry,
+    /// Fail with `tool_call_required`
+    Error,
+}
+
+/// How to settle `stream: true` against an `Accept` header without `text/event-stream`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum StreamAcceptConflict {
+    /// Stream as the body asks, like OpenAI, which ignores `Accept`
+    BodyWins,
+    /// Answer with one JSON response
+    AcceptWins,
+}
+
+// Added as a system message when retrying under `--enforce-tool-choice retry`
+const TOOL_CHOICE_RETRY_INSTRUCTION: &str =
+    "You must respond by calling one of the provided tools. Do not answer with plain text.";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum DefaultToolsMode {
+    /// Add default tools after the client's, skipping names the client defines
+    Append,
+    /// Send only the default tools, ignoring the client's
+    Replace,
+}
+
+// Paths served by `universal_request_handler`; anything else is scanner noise.
+const KNOWN_PATHS: &[&str] = &[
+    "/health",
+    "/version",
+    "/metrics",
+    "/models",
+    "/v1/models",
+    "/chat/completions",
+    "/v1/chat/completions",
+    "/chat/completions/validate",
+    "/v1/chat/completions/validate",
+    "/admin/cache",
+    "/admin/requests",
+];
+
+const UNMATCHED_LOG_INTERVAL_SECS: i64 = 60;
+
+/// The response id in `GET /v1/responses/{id}` or `GET /v1/chat/completions/{id}`
+/// (with or without `/v1`). Chat-shaped ids may carry the `chatcmpl-` prefix.
+fn stored_response_id(path: &str) -> Option<&str> {
+    let path = path.strip_prefix("/v1").unwrap_or(path);
+    let id = match path.strip_prefix("/responses/") {
+        Some(id) => id,
+        None => {
+            let id = path.strip_prefix("/chat/completions/")?;
+            id.strip_prefix("chatcmpl-").unwrap_or(id)
+        }
+    };
+    (!id.is_empty() && id != "validate" && !id.contains('/')).then_some(id)
+}
+
+/// Marks upstream requests with this process's id so one that comes back
+/// is recognized as a loop. Other proxy instances use other ids, so chaining
+/// separate proxies still works.
+const LOOP_GUARD_HEADER: &str = "x-codex-proxy-loop-id";
+
+/// Encodings the HTTP client decodes, advertised to the backend by default.
+const DEFAULT_ACCEPT_ENCODING: &str = "gzip, deflate, br";
+const ACCEPT_ENCODINGS: &[&str] = &["gzip", "deflate", "br", "identity"];
+
+/// Per-request backend model override, honored with `--allow-upstream-model-header`.
+const UPSTREAM_MODEL_HEADER: &str = "x-upstream-model";
+
+const SERVER_HEADER: &str = concat!("codex-openai-proxy/", env!("CARGO_PKG_VERSION"));
+
+/// Crate version plus the commit and time embedded by build.rs.
+fn build_version_response() -> Value {
+    let built_at = env!("BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map(|time| time.to_rfc3339());
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_hash": env!("BUILD_GIT_HASH"),
+        "build_timestamp": built_at
+    })
 }
 
+// Per-path handler time limits in seconds; `*` covers every other path
+const DEFAULT_ROUTE_TIMEOUTS: &[(&str, u64)] = &[
+    ("/health", 5),
+    ("/version", 5),
+    ("/metrics", 5),
+    ("/models", 10),
+    ("/v1/models", 10),
+    ("/chat/completions", 600),
+    ("/v1/chat/completions", 600),
+    ("*", 30),
+];
+
 const DEFAULT_ALLOWED_MODELS: &[&str] = &[
     "gpt-5",
     "gpt-5.2",
@@ -39,8 +564,23 @@ const REASONING_SUFFIX_ALIASES: [(&str, &str); 6] = [
     ("-low", "low"),
 ];
 
+// Values accepted for `service_tier`
+const SERVICE_TIERS: [&str; 5] = ["auto", "default", "flex", "scale", "priority"];
+
+// Values accepted for `reasoning.effort`
+const REASONING_EFFORTS: [&str; 6] = ["none", "minimal", "low", "medium", "high", "xhigh"];
+
 const REASONING_CANONICAL_SUFFIXES: [&str; 4] = ["-low", "-medium", "-high", "-xhigh"];
 
+const DEFAULT_MODELS_OWNED_BY: &str = "openai";
+
+const DEFAULT_INSTRUCTIONS: &str = "You are a helpful AI assistant. Provide clear, accurate, and concise responses to user questions and requests.";
+
+// Base for the per-model `created` timestamps in the models list (2025-01-01T00:00:00Z).
+// Each model gets a stable offset from this so the value never changes between calls.
+const MODELS_CREATED_BASE: i64 = 1_735_689_600;
+const MODELS_CREATED_SPREAD_SECS: u64 = 365 * 24 * 60 * 60;
+
 fn load_allowed_models() -> Vec<String> {
     let configured = std::env::var("ALLOWED_MODELS")
         .ok()
@@ -74,26 +614,409 @@ fn load_allowed_models() -> Vec<String> {
     deduped
 }
 
-fn build_models_response(allowed_models: &[String]) -> Value {
-    let created = chrono::Utc::now().timestamp();
+/// `object` strings of the response shapes the proxy produces.
+#[derive(Clone, Debug)]
+struct ObjectNames {
+    completion: String,
+    chunk: String,
+    list: String,
+    model: String,
+}
+
+impl Default for ObjectNames {
+    fn default() -> Self {
+        Self {
+            completion: "chat.completion".to_string(),
+            chunk: "chat.completion.chunk".to_string(),
+            list: "list".to_string(),
+            model: "model".to_string(),
+        }
+    }
+}
+
+/// Apply `key=value` overrides (keys: completion, chunk, list, model) over the
+/// OpenAI defaults.
+fn parse_object_names(raw: &str) -> Result<ObjectNames> {
+    let mut names = ObjectNames::default();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (key, value) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid object name '{}': expected 'key=value'", entry))?;
+        let slot = match key.trim() {
+            "completion" => &mut names.completion,
+            "chunk" => &mut names.chunk,
+            "list" => &mut names.list,
+            "model" => &mut names.model,
+            other => {
+                return Err(anyhow!(
+                    "Unknown object name key '{}': use completion, chunk, list or model",
+                    other
+                ))
+            }
+        };
+        *slot = value.trim().to_string();
+    }
+    Ok(names)
+}
+
+fn load_models_owned_by() -> String {
+    std::env::var("MODELS_OWNED_BY")
+        .ok()
+        .map(|raw| raw.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| DEFAULT_MODELS_OWNED_BY.to_string())
+}
+
+/// Check that `--upstream-accept-encoding` only names encodings the client
+/// can decode. Quality values (`gzip;q=0.5`) are allowed.
+fn parse_accept_encoding(raw: &str) -> Result<String> {
+    let value = raw.trim();
+    if value.is_empty() {
+        return Err(anyhow!(
+            "--upstream-accept-encoding is empty; use 'identity' to disable compression"
+        ));
+    }
+    for coding in value.split(',') {
+        let name = coding.split(';').next().unwrap_or_default().trim();
+        if !ACCEPT_ENCODINGS.contains(&name.to_ascii_lowercase().as_str()) {
+            return Err(anyhow!(
+                "Unsupported encoding '{}' in --upstream-accept-encoding: use {}",
+                name,
+                ACCEPT_ENCODINGS.join(", ")
+            ));
+        }
+    }
+    Ok(value.to_string())
+}
+
+/// Parse `Name: Value` header specs from `UPSTREAM_HEADERS` (`;`-separated)
+/// followed by `--upstream-header` flags, so flags win on duplicates.
+fn load_upstream_headers(flags: &[String]) -> Result<reqwest::header::HeaderMap> {
+    let from_env = std::env::var("UPSTREAM_HEADERS").unwrap_or_default();
+    let specs = from_env
+        .split(';')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .map(ToString::to_string)
+        .chain(flags.iter().cloned());
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    for spec in specs {
+        let (name, value) = spec
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid upstream header '{}': expected 'Name: Value'", spec))?;
+        let name = reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())
+            .with_context(|| format!("Invalid upstream header name in '{}'", spec))?;
+        let value = reqwest::header::HeaderValue::from_str(value.trim())
+            .with_context(|| format!("Invalid upstream header value in '{}'", spec))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// Parse `KEY=Message` specs from `ERROR_MESSAGES` (`;`-separated) followed
+/// by `--error-message` flags, so flags win on duplicates.
+fn load_error_messages(flags: &[String]) -> Result<ErrorMessages> {
+    let from_env = std::env::var("ERROR_MESSAGES").unwrap_or_default();
+    let specs = from_env
+        .split(';')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .map(ToString::to_string)
+        .chain(flags.iter().cloned());
+
+    let mut messages = HashMap::new();
+    for spec in specs {
+        let (key, message) = spec
+            .split_once('=')
+            .map(|(key, message)| (key.trim().to_lowercase(), message.trim()))
+            .filter(|(key, message)| !key.is_empty() && !message.is_empty())
+            .ok_or_else(|| anyhow!("Invalid error message '{}': expected 'KEY=Message'", spec))?;
+        messages.insert(key, message.to_string());
+    }
+    Ok(ErrorMessages::new(messages))
+}
+
+/// Merge `path=secs` overrides over `DEFAULT_ROUTE_TIMEOUTS`.
+fn parse_route_timeouts(raw: &str) -> Result<HashMap<String, u64>> {
+    let mut timeouts = DEFAULT_ROUTE_TIMEOUTS
+        .iter()
+        .map(|(path, secs)| (path.to_string(), *secs))
+        .collect::<HashMap<String, u64>>();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (path, secs) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid route timeout '{}': expected 'path=seconds'", entry))?;
+        let secs = secs
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("Invalid seconds in route timeout '{}'", entry))?;
+        timeouts.insert(path.trim().to_string(), secs);
+    }
+    Ok(timeouts)
+}
+
+fn parse_max_output_tokens(raw: &str) -> Result<HashMap<String, i32>> {
+    let mut caps = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (model, cap) = entry.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Invalid max output tokens '{}': expected 'model=tokens'",
+                entry
+            )
+        })?;
+        let cap = cap
+            .trim()
+            .parse::<i32>()
+            .ok()
+            .filter(|cap| *cap > 0)
+            .ok_or_else(|| anyhow!("Invalid token count in max output tokens '{}'", entry))?;
+        caps.insert(model.trim().to_string(), cap);
+    }
+    Ok(caps)
+}
+
+fn parse_effort(raw: &str) -> Result<String> {
+    let effort = raw.trim().to_ascii_lowercase();
+    if REASONING_EFFORTS.contains(&effort.as_str()) {
+        Ok(effort)
+    } else {
+        Err(anyhow!(
+            "Invalid reasoning effort '{}': use one of {}",
+            raw,
+            REASONING_EFFORTS.join(", ")
+        ))
+    }
+}
+
+fn parse_model_reasoning_efforts(raw: &str) -> Result<HashMap<String, String>> {
+    let mut efforts = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (model, effort) = entry.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Invalid model reasoning effort '{}': expected 'model=effort'",
+                entry
+            )
+        })?;
+        efforts.insert(model.trim().to_string(), parse_effort(effort)?);
+    }
+    Ok(efforts)
+}
+
+/// Parse `client=backend` pairs. A pair without `=`, with an empty side, or
+/// naming a client model twice is an error rather than skipped.
+fn parse_model_map(raw: &str) -> Result<HashMap<String, String>> {
+    let mut map = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (client, backend) = entry
+            .split_once('=')
+            .map(|(client, backend)| (client.trim(), backend.trim()))
+            .filter(|(client, backend)| !client.is_empty() && !backend.is_empty())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid model map entry '{}': expected 'client=backend'",
+                    entry
+                )
+            })?;
+        if map
+            .insert(client.to_string(), backend.to_string())
+            .is_some()
+        {
+            return Err(anyhow!("Model '{}' is mapped more than once", client));
+        }
+    }
+    Ok(map)
+}
+
+/// Parse `primary=fallback1|fallback2,...` into a map of ordered fallback lists.
+fn parse_fallback_models(raw: &str) -> Result<HashMap<String, Vec<String>>> {
+    let mut chains = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (primary, fallbacks) = entry.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Invalid fallback entry '{}': expected 'model=fallback1|fallback2'",
+                entry
+            )
+        })?;
+        let fallbacks = fallbacks
+            .split('|')
+            .map(str::trim)
+            .filter(|model| !model.is_empty())
+            .map(ToString::to_string)
+            .collect::<Vec<String>>();
+        if primary.trim().is_empty() || fallbacks.is_empty() {
+            return Err(anyhow!(
+                "Invalid fallback entry '{}': both the model and at least one fallback are required",
+                entry
+            ));
+        }
+        chains.insert(primary.trim().to_string(), fallbacks);
+    }
+    Ok(chains)
+}
+
+/// Parse `LABEL=model|model,...` into each account's model list.
+fn parse_account_models(raw: &str) -> Result<HashMap<String, Vec<String>>> {
+    let mut lists = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (label, models) = entry.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Invalid account models entry '{}': expected 'auth-path=model1|model2'",
+                entry
+            )
+        })?;
+        let models = models
+            .split('|')
+            .map(str::trim)
+            .filter(|model| !model.is_empty())
+            .map(ToString::to_string)
+            .collect::<Vec<String>>();
+        if label.trim().is_empty() || models.is_empty() {
+            return Err(anyhow!(
+                "Invalid account models entry '{}': both the account and at least one model are required",
+                entry
+            ));
+        }
+        lists.insert(label.trim().to_string(), models);
+    }
+    Ok(lists)
+}
+
+fn load_audio_models() -> Vec<String> {
+    std::env::var("AUDIO_MODELS")
+        .ok()
+        .map(|raw| {
+            raw.split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(ToString::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// FNV-1a keeps the derived timestamp identical across restarts and Rust versions.
+fn stable_model_created(model: &str) -> i64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in model.as_bytes() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    MODELS_CREATED_BASE + (hash % MODELS_CREATED_SPREAD_SECS) as i64
+}
+
+fn expand_request_models(allowed_models: &[String]) -> Vec<String> {
+    let mut models = allowed_models.to_vec();
+    for base_model in allowed_models {
+        for suffix in REASONING_CANONICAL_SUFFIXES {
+            models.push(format!("{base_model}{suffix}"));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    models
+        .into_iter()
+        .filter(|model| seen.insert(model.clone()))
+        .collect()
+}
+
+/// Model names clients may request: the allowed models, then mapped names.
+fn client_models(allowed_models: &[String], model_map: &HashMap<String, String>) -> Vec<String> {
+    let mut mapped: Vec<&String> = model_map.keys().collect();
+    mapped.sort();
+    allowed_models.iter().chain(mapped).cloned().collect()
+}
+
+fn build_models_response(
+    allowed_models: &[String],
+    owned_by: &str,
+    object_names: &ObjectNames,
+) -> Value {
     let models = allowed_models
         .iter()
         .map(|model| {
             json!({
                 "id": model,
-                "object": "model",
-                "created": created,
-                "owned_by": "openai"
+                "object": object_names.model,
+                "created": stable_model_created(model),
+                "owned_by": owned_by
             })
         })
         .collect::<Vec<Value>>();
 
     json!({
-        "object": "list",
+        "object": object_names.list,
         "data": models
     })
 }
 
+/// Whether an `Accept` header asks for `text/plain` at least as strongly as
+/// for `application/json`. Wildcards leave the default (JSON) in place.
+fn prefers_plain_text(headers: &warp::http::HeaderMap) -> bool {
+    let mut plain_q = 0.0_f32;
+    let mut json_q = 0.0_f32;
+    for value in headers.get_all("accept") {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for range in value.split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let q = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match media_type.as_str() {
+                "text/plain" => plain_q = plain_q.max(q),
+                "application/json" => json_q = json_q.max(q),
+                _ => {}
+            }
+        }
+    }
+    plain_q > 0.0 && plain_q >= json_q
+}
+
+/// Whether an `Accept` header admits JSON but not an event stream, neither
+/// `text/event-stream` nor a wildcard covering it. No header excludes nothing.
+fn accept_excludes_event_stream(headers: &warp::http::HeaderMap) -> bool {
+    let mut json = false;
+    for value in headers.get_all("accept") {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for range in value.split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let q = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q <= 0.0 {
+                continue;
+            }
+            match media_type.as_str() {
+                "text/event-stream" | "text/*" | "*/*" => return false,
+                "application/json" => json = true,
+                _ => {}
+            }
+        }
+    }
+    json
+}
+
+/// Newline-delimited model ids from a models list response.
+fn models_plain_text(models_response: &Value) -> String {
+    models_response
+        .get("data")
+        .and_then(|v| v.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|model| model.get("id").and_then(|v| v.as_str()))
+        .map(|id| format!("{}\n", id))
+        .collect()
+}
+
 fn build_model_not_allowed_response(model: &str, allowed_models: &[String]) -> Value {
     json!({
         "error": {
@@ -109,460 +1032,3151 @@ fn build_model_not_allowed_response(model: &str, allowed_models: &[String]) -> V
     })
 }
 
-fn build_proxy_error_response(error: &str) -> Value {
+fn build_audio_not_supported_response(model: &str) -> Value {
     json!({
         "error": {
-            "message": format!("Proxy error: {}", error),
-            "type": "proxy_error",
-            "code": "internal_error"
+            "message": format!(
+                "Model '{}' does not accept audio input. Configure audio-capable models with AUDIO_MODELS.",
+                model
+            ),
+            "type": "invalid_request_error",
+            "param": "messages",
+            "code": "audio_not_supported"
         }
     })
 }
 
-fn build_invalid_json_response(error: &str) -> Value {
-    json!({
-        "error": {
-            "message": format!("Invalid JSON body: {}", error),
-            "type": "invalid_request_error",
-            "param": "body",
-            "code": "invalid_json"
+// OpenAI's limits for `metadata`; they also bound the metrics label set per request
+const METADATA_MAX_KEYS: usize = 16;
+const METADATA_MAX_KEY_CHARS: usize = 64;
+const METADATA_MAX_VALUE_CHARS: usize = 512;
+
+/// Check `metadata` is an object of at most 16 string values with bounded key
+/// and value lengths, returning the reason it is rejected otherwise.
+fn validate_metadata(metadata: &Value) -> Result<(), String> {
+    let Some(entries) = metadata.as_object() else {
+        return Err("'metadata' must be an object of string key/value pairs".to_string());
+    };
+    if entries.len() > METADATA_MAX_KEYS {
+        return Err(format!(
+            "'metadata' has {} keys; at most {} are allowed",
+            entries.len(),
+            METADATA_MAX_KEYS
+        ));
+    }
+    for (key, value) in entries {
+        if key.chars().count() > METADATA_MAX_KEY_CHARS {
+            return Err(format!(
+                "'metadata' key '{}' exceeds {} characters",
+                key, METADATA_MAX_KEY_CHARS
+            ));
         }
-    })
+        let Some(value) = value.as_str() else {
+            return Err(format!("'metadata' value for '{}' must be a string", key));
+        };
+        if value.chars().count() > METADATA_MAX_VALUE_CHARS {
+            return Err(format!(
+                "'metadata' value for '{}' exceeds {} characters",
+                key, METADATA_MAX_VALUE_CHARS
+            ));
+        }
+    }
+    Ok(())
 }
 
-fn json_response(
-    status: warp::http::StatusCode,
-    body: &Value,
-) -> warp::http::Response<warp::hyper::Body> {
-    let reply = warp::reply::with_status(warp::reply::json(body), status);
-    let reply = warp::reply::with_header(reply, "content-type", "application/json");
-    let reply = warp::reply::with_header(reply, "access-control-allow-origin", "*");
-    reply.into_response()
+/// Rewrite the legacy function-calling fields into their modern forms:
+/// `functions` into `tools`, `function_call` into `tool_choice`, assistant
+/// `function_call` turns into `tool_calls`, and `function` results into `tool`
+/// messages answering them. Modern fields win when a client sends both.
+fn migrate_legacy_functions(chat_req: &mut ChatCompletionsRequest) {
+    if chat_req.tools.is_none() {
+        if let Some(functions) = &chat_req.functions {
+            chat_req.tools = Some(
+                functions
+                    .iter()
+                    .map(|function| json!({ "type": "function", "function": function }))
+                    .collect(),
+            );
+        }
+    }
+    if chat_req.tool_choice.is_none() {
+        chat_req.tool_choice = chat_req.function_call.as_ref().map(|choice| match choice {
+            Value::Object(choice) => json!({
+                "type": "function",
+                "function": { "name": choice.get("name").cloned().unwrap_or(Value::Null) }
+            }),
+            // "none" and "auto" mean the same for tools
+            other => other.clone(),
+        });
+    }
+
+    // Legacy results carry no call id, so each answers the call before it
+    let mut last_call_id = None;
+    for (index, message) in chat_req.messages.iter_mut().enumerate() {
+        if let Some(function_call) = message.function_call.take() {
+            if message.tool_calls.is_none() {
+                let call_id = format!("call_legacy_{}", index);
+                message.tool_calls = Some(vec![json!({
+                    "id": call_id,
+                    "type": "function",
+                    "function": function_call
+                })]);
+                last_call_id = Some(call_id);
+            }
+        }
+        if message.role == "function" {
+            message.role = "tool".to_string();
+            if message.tool_call_id.is_none() {
+                message.tool_call_id = last_call_id.take();
+            }
+        }
+    }
 }
 
-/// Chat Completions API format (what CLINE sends)
-#[derive(Deserialize, Debug)]
-struct ChatCompletionsRequest {
-    model: String,
-    messages: Vec<ChatMessage>,
-    temperature: Option<f32>,
-    max_tokens: Option<i32>,
-    stream: Option<bool>,
-    tools: Option<Vec<Value>>,
-    tool_choice: Option<Value>,
+/// Check that every tool is a function with a usable, unique name and an
+/// object (if any) for its parameters.
+fn validate_tools(tools: &[Value]) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for (index, tool) in tools.iter().enumerate() {
+        let tool_type = tool.get("type").and_then(|v| v.as_str());
+        if tool_type != Some("function") {
+            return Err(format!("tools[{}].type must be 'function'", index));
+        }
+        // Chat tools nest the definition under `function`; Responses tools are flat
+        let function = tool.get("function").unwrap_or(tool);
+        let name = tool_name(tool).unwrap_or_default();
+        let valid_name = !name.is_empty()
+            && name.len() <= 64
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_name {
+            return Err(format!(
+                "tools[{}].function.name must be 1-64 characters of a-z, A-Z, 0-9, _ or -",
+                index
+            ));
+        }
+        if !names.insert(name) {
+            return Err(format!(
+                "tools[{}]: duplicate function name '{}'",
+                index, name
+            ));
+        }
+        if function
+            .get("parameters")
+            .is_some_and(|parameters| !parameters.is_object())
+        {
+            return Err(format!(
+                "tools[{}].function.parameters must be a JSON Schema object",
+                index
+            ));
+        }
+    }
+    Ok(())
 }
 
-#[derive(Deserialize, Debug)]
-struct ChatMessage {
-    role: String,
-    content: Value, // Can be string or array
+fn tool_name(tool: &Value) -> Option<&str> {
+    tool.get("function")
+        .unwrap_or(tool)
+        .get("name")
+        .and_then(|v| v.as_str())
 }
 
-/// Chat Completions API response format (what CLINE expects)
-#[derive(Serialize, Debug)]
-struct ChatCompletionsResponse {
-    id: String,
-    object: String,
-    created: i64,
-    model: String,
-    choices: Vec<Choice>,
-    usage: Option<Usage>,
+fn load_default_tools(path: Option<&str>) -> Result<Vec<Value>> {
+    let Some(path) = path else {
+        return Ok(Vec::new());
+    };
+    let path = expand_home(path)?;
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read default tools file {}", path))?;
+    let tools: Vec<Value> = serde_json::from_str(&content)
+        .with_context(|| format!("Default tools file {} must be a JSON array", path))?;
+    validate_tools(&tools)
+        .map_err(|reason| anyhow!("Invalid default tools in {}: {}", path, reason))?;
+    Ok(tools)
 }
 
-#[derive(Serialize, Debug)]
-struct Choice {
-    index: i32,
-    message: ChatResponseMessage,
-    finish_reason: Option<String>,
+fn build_invalid_tools_response(reason: &str) -> Value {
+    json!({
+        "error": {
+            "message": reason,
+            "type": "invalid_request_error",
+            "param": "tools",
+            "code": "invalid_tools"
+        }
+    })
 }
 
-#[derive(Serialize, Debug)]
-struct ChatResponseMessage {
-    role: String,
-    content: String,
+fn build_invalid_tool_message_response(reason: &str) -> Value {
+    json!({
+        "error": {
+            "message": reason,
+            "type": "invalid_request_error",
+            "param": "messages",
+            "code": "invalid_tool_message"
+        }
+    })
 }
 
-#[derive(Serialize, Debug)]
-struct Usage {
-    prompt_tokens: i32,
-    completion_tokens: i32,
-    total_tokens: i32,
+fn build_max_n_exceeded_response(requested: u32, cap: u32) -> Value {
+    json!({
+        "error": {
+            "message": format!(
+                "n is too large: {}. At most {} choices may be requested.",
+                requested, cap
+            ),
+            "type": "invalid_request_error",
+            "param": "n",
+            "code": "max_n_exceeded"
+        }
+    })
 }
 
-/// Codex Responses API format (what we send to ChatGPT backend)
-#[derive(Serialize, Debug)]
-struct ResponsesApiRequest {
-    model: String,
-    instructions: String,
-    input: Vec<ResponseItem>,
-    tools: Vec<Value>,
-    tool_choice: String,
-    parallel_tool_calls: bool,
-    reasoning: Option<Value>,
-    store: bool,
-    stream: bool,
-    include: Vec<String>,
+fn build_max_tokens_exceeded_response(model: &str, requested: i32, cap: i32) -> Value {
+    json!({
+        "error": {
+            "message": format!(
+                "max_tokens is too large: {}. Model '{}' supports at most {} output tokens.",
+                requested, model, cap
+            ),
+            "type": "invalid_request_error",
+            "param": "max_tokens",
+            "code": "max_tokens_exceeded"
+        }
+    })
 }
 
-#[derive(Serialize, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum ResponseItem {
-    Message {
-        #[serde(skip_serializing_if = "Option::is_none")]
-        id: Option<String>,
-        role: String,
-        content: Vec<ContentItem>,
-    },
+fn build_missing_client_auth_response() -> Value {
+    json!({
+        "error": {
+            "message": "An Authorization bearer token is required (--forward-auth is on)",
+            "type": "authentication_error",
+            "param": null,
+            "code": "missing_client_auth"
+        }
+    })
 }
 
-#[derive(Serialize, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum ContentItem {
-    InputText { text: String },
+fn build_invalid_admin_token_response() -> Value {
+    json!({
+        "error": {
+            "message": "Missing or invalid admin token",
+            "type": "authentication_error",
+            "param": null,
+            "code": "invalid_admin_token"
+        }
+    })
+}
+
+fn build_cache_disabled_response() -> Value {
+    json!({
+        "error": {
+            "message": "Response caching is disabled",
+            "type": "invalid_request_error",
+            "param": null,
+            "code": "cache_disabled"
+        }
+    })
+}
+
+fn build_invalid_reasoning_effort_response(effort: &str) -> Value {
+    json!({
+        "error": {
+            "message": format!(
+                "Invalid reasoning_effort '{}'. Use one of: {}",
+                effort,
+                REASONING_EFFORTS.join(", ")
+            ),
+            "type": "invalid_request_error",
+            "param": "reasoning_effort",
+            "code": "invalid_reasoning_effort"
+        }
+    })
+}
+
+fn build_invalid_service_tier_response(tier: &str) -> Value {
+    json!({
+        "error": {
+            "message": format!(
+                "Invalid service_tier '{}'. Use one of: {}",
+                tier,
+                SERVICE_TIERS.join(", ")
+            ),
+            "type": "invalid_request_error",
+            "param": "service_tier",
+            "code": "invalid_service_tier"
+        }
+    })
+}
+
+fn build_conversation_budget_exceeded_response(conversation: &str, budget: u64) -> Value {
+    json!({
+        "error": {
+            "message": format!(
+                "Conversation '{}' has used its budget of {} tokens",
+                conversation, budget
+            ),
+            "type": "insufficient_quota",
+            "param": null,
+            "code": "conversation_budget_exceeded"
+        }
+    })
+}
+
+fn build_tokens_per_minute_exceeded_response(limit: u64) -> Value {
+    json!({
+        "error": {
+            "message": format!(
+                "The proxy's limit of {} tokens per minute has been reached; retry later",
+                limit
+            ),
+            "type": "rate_limit_error",
+            "param": null,
+            "code": "tokens_per_minute_exceeded"
+        }
+    })
+}
+
+fn build_unknown_field_response(field: &str) -> Value {
+    json!({
+        "error": {
+            "message": format!("Unrecognized request argument supplied: {}", field),
+            "type": "invalid_request_error",
+            "param": field,
+            "code": "unknown_field"
+        }
+    })
+}
+
+fn build_empty_messages_response(only_system: bool) -> Value {
+    let message = if only_system {
+        "messages contains only system or developer messages; add at least one user message"
+    } else {
+        "messages must contain at least one message"
+    };
+    json!({
+        "error": {
+            "message": message,
+            "type": "invalid_request_error",
+            "param": "messages",
+            "code": "empty_messages"
+        }
+    })
+}
+
+fn build_too_many_messages_response(count: usize, limit: usize) -> Value {
+    json!({
+        "error": {
+            "message": format!(
+                "messages has {} items; at most {} are allowed",
+                count, limit
+            ),
+            "type": "invalid_request_error",
+            "param": "messages",
+            "code": "too_many_messages"
+        }
+    })
+}
+
+fn build_streaming_unavailable_response(reason: &str) -> Value {
+    json!({
+        "error": {
+            "message": format!(
+                "Streaming is unavailable for this request ({}): the backend answer would be buffered, and --strict-streaming forbids replaying it as synthetic chunks. Retry with stream: false.",
+                reason
+            ),
+            "type": "invalid_request_error",
+            "param": "stream",
+            "code": "streaming_unavailable"
+        }
+    })
+}
+
+fn build_invalid_content_format_response(format: &str) -> Value {
+    json!({
+        "error": {
+            "message": format!(
+                "Invalid content_format '{}'. Use 'string' or 'parts'",
+                format
+            ),
+            "type": "invalid_request_error",
+            "param": "content_format",
+            "code": "invalid_content_format"
+        }
+    })
+}
+
+/// Whether the client asked for array-of-parts message content, by the
+/// `content_format` request field or an `Accept` parameter such as
+/// `application/json; content-format=parts`.
+fn wants_content_parts(content_format: Option<&str>, headers: &warp::http::HeaderMap) -> bool {
+    if let Some(format) = content_format {
+        return format == "parts";
+    }
+    headers
+        .get_all("accept")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split([',', ';']))
+        .any(|param| param.trim().eq_ignore_ascii_case("content-format=parts"))
+}
+
+fn build_invalid_metadata_response(reason: &str) -> Value {
+    json!({
+        "error": {
+            "message": reason,
+            "type": "invalid_request_error",
+            "param": "metadata",
+            "code": "invalid_metadata"
+        }
+    })
+}
+
+const JSON_ERROR_EXCERPT_CHARS: usize = 30;
+
+/// Show the body around a serde_json error position, e.g. `"model": >>> ,"messages"`.
+fn json_error_excerpt(body: &[u8], line: usize, column: usize) -> Option<String> {
+    let body = String::from_utf8_lossy(body);
+    let line_start = body
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum::<usize>();
+    // serde_json columns are 1-based byte offsets within the line
+    let mut offset = (line_start + column.saturating_sub(1)).min(body.len());
+    while !body.is_char_boundary(offset) {
+        offset -= 1;
+    }
+
+    let (before, after) = body.split_at(offset);
+    let before_chars = before.chars().count();
+    let before = before
+        .chars()
+        .skip(before_chars.saturating_sub(JSON_ERROR_EXCERPT_CHARS))
+        .collect::<String>();
+    let after = after
+        .chars()
+        .take(JSON_ERROR_EXCERPT_CHARS)
+        .collect::<String>();
+    if before.is_empty() && after.is_empty() {
+        return None;
+    }
+    Some(format!("{} >>> {}", before, after).replace('\n', "\\n"))
+}
+
+fn build_invalid_json_response(error: &serde_json::Error, body: &[u8]) -> Value {
+    let message = match json_error_excerpt(body, error.line(), error.column()) {
+        Some(excerpt) => format!("Invalid JSON body: {} (near: {})", error, excerpt),
+        None => format!("Invalid JSON body: {}", error),
+    };
+    json!({
+        "error": {
+            "message": message,
+            "type": "invalid_request_error",
+            "param": "body",
+            "code": "invalid_json"
+        }
+    })
+}
+
+fn json_response(
+    status: warp::http::StatusCode,
+    body: &Value,
+) -> warp::http::Response<warp::hyper::Body> {
+    let reply = warp::reply::with_status(warp::reply::json(body), status);
+    let reply = warp::reply::with_header(reply, "content-type", "application/json");
+    let reply = warp::reply::with_header(reply, "access-control-allow-origin", "*");
+    reply.into_response()
+}
+
+/// Attach a completion's upstream details: `x-upstream-id` and `x-served-model`
+/// headers for the client, plus the fields the access log reports.
+fn with_upstream_headers(
+    mut response: warp::http::Response<warp::hyper::Body>,
+    completion: &ChatCompletionsResponse,
+) -> warp::http::Response<warp::hyper::Body> {
+    response.extensions_mut().insert(CompletionInfo {
+        id: completion.id.clone(),
+        model: completion.model.clone(),
+        total_tokens: completion
+            .usage
+            .as_ref()
+            .map(|usage| i64::from(usage.total_tokens)),
+        upstream_latency: completion.upstream_latency,
+    });
+    let headers = [
+        ("x-upstream-id", completion.upstream_id.as_deref()),
+        ("x-served-model", completion.served_model.as_deref()),
+    ];
+    for (name, value) in headers {
+        if let Some(value) = value.and_then(|v| warp::http::HeaderValue::from_str(v).ok()) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// The SSE response for a live stream, with the headers known when it starts.
+fn live_stream_response(
+    stream: live::LiveStream,
+    permit: Option<tokio::sync::OwnedSemaphorePermit>,
+    conversation_id: Option<String>,
+    proxy: &ProxyServer,
+) -> warp::http::Response<warp::hyper::Body> {
+    let completion = stream.completion_info();
+    let headers = [
+        ("x-upstream-id", stream.upstream_id().map(str::to_string)),
+        ("x-served-model", Some(stream.served_model().to_string())),
+    ];
+    let stream = stream.recording_usage(conversation_id).holding(permit);
+    let body = warp::hyper::Body::wrap_stream(flush::coalesce(
+        Box::pin(stream.pipe(proxy.stream_channel_capacity)),
+        proxy.flush_policy,
+    ));
+    let mut response = warp::http::Response::new(body);
+    response.extensions_mut().insert(completion);
+    let response_headers = response.headers_mut();
+    for (name, value) in [
+        ("content-type", "text/event-stream"),
+        ("cache-control", "no-cache"),
+        ("connection", "keep-alive"),
+        ("access-control-allow-origin", "*"),
+    ] {
+        response_headers.insert(name, warp::http::HeaderValue::from_static(value));
+    }
+    for (name, value) in headers {
+        if let Some(value) = value.and_then(|v| warp::http::HeaderValue::from_str(&v).ok()) {
+            response_headers.insert(name, value);
+        }
+    }
+    response
+}
+
+/// Report a conversation's remaining token budget, when one is tracked.
+fn with_budget_header(
+    mut response: warp::http::Response<warp::hyper::Body>,
+    remaining: Option<u64>,
+) -> warp::http::Response<warp::hyper::Body> {
+    if let Some(remaining) = remaining {
+        response.headers_mut().insert(
+            "x-conversation-budget-remaining",
+            warp::http::HeaderValue::from(remaining),
+        );
+    }
+    response
+}
+
+/// System and developer messages plus the last `limit` others, in their
+/// original order.
+/// Tool results cut off from their assistant call are dropped too, as the
+/// backend rejects results for calls it never saw.
+fn keep_recent_messages(messages: Vec<ChatMessage>, limit: usize) -> Vec<ChatMessage> {
+    let is_instruction = |msg: &ChatMessage| matches!(msg.role.as_str(), "system" | "developer");
+    let others = messages.iter().filter(|msg| !is_instruction(msg)).count();
+    let mut skip = others.saturating_sub(limit);
+    let mut orphaned_results = true;
+    messages
+        .into_iter()
+        .filter(|msg| {
+            if is_instruction(msg) {
+                return true;
+            }
+            if skip > 0 {
+                skip -= 1;
+                return false;
+            }
+            orphaned_results &= msg.role == "tool";
+            !orphaned_results
+        })
+        .collect()
+}
+
+/// Whether `tool_choice` obliges the model to call a tool: `"required"`, or a
+/// named function.
+fn requires_tool_call(tool_choice: Option<&Value>) -> bool {
+    match tool_choice {
+        Some(Value::String(choice)) => choice == "required",
+        Some(Value::Object(choice)) => {
+            choice.get("type").and_then(|v| v.as_str()) == Some("function")
+        }
+        _ => false,
+    }
+}
+
+/// A tool in the Responses API shape. A Chat Completions tool has its
+/// `function` definition lifted to the top level; flat tools pass through.
+fn responses_tool(tool: Value) -> Value {
+    match tool {
+        Value::Object(mut tool) => {
+            if let Some(Value::Object(function)) = tool.remove("function") {
+                tool.extend(function);
+            }
+            Value::Object(tool)
+        }
+        other => other,
+    }
+}
+
+/// The client's `tool_choice` in the Responses API shape, `"auto"` when unset.
+/// A named function `{"type": "function", "function": {"name": X}}` becomes
+/// `{"type": "function", "name": X}`; strings pass through.
+fn responses_tool_choice(tool_choice: Option<Value>) -> Value {
+    match tool_choice {
+        Some(Value::Object(mut choice)) => {
+            if let Some(name) = choice
+                .remove("function")
+                .and_then(|function| function.get("name").cloned())
+            {
+                choice.insert("name".to_string(), name);
+            }
+            Value::Object(choice)
+        }
+        Some(choice) => choice,
+        None => json!("auto"),
+    }
 }
 
-/// Codex auth.json structure
-#[derive(Deserialize, Debug, Clone)]
-struct AuthData {
-    #[serde(rename = "OPENAI_API_KEY")]
-    api_key: Option<String>,
-    tokens: Option<TokenData>,
+fn proxy_error_response(
+    error: &ProxyError,
+    messages: &ErrorMessages,
+) -> warp::http::Response<warp::hyper::Body> {
+    let mut response = json_response(error.status(), &error.to_body(messages));
+    if let Some(retry_after) = error.retry_after() {
+        response.headers_mut().insert(
+            warp::http::header::RETRY_AFTER,
+            warp::http::HeaderValue::from(retry_after),
+        );
+    }
+    response
+}
+
+fn is_audio_part(part: &Value) -> bool {
+    part.get("type").and_then(|t| t.as_str()) == Some("input_audio")
+}
+
+fn messages_contain_audio(messages: &[ChatMessage]) -> bool {
+    messages.iter().any(|msg| {
+        msg.content
+            .as_array()
+            .is_some_and(|parts| parts.iter().any(is_audio_part))
+    })
+}
+
+/// Text of a single content part. Accepts bare strings, `{text: "..."}`, the
+/// nested `{type: "text", text: {value: "..."}}` shape, and `input_text` /
+/// `output_text` parts.
+fn part_text(part: &Value) -> Option<&str> {
+    if let Some(text) = part.as_str() {
+        return Some(text);
+    }
+    let text = part.get("text")?;
+    text.as_str()
+        .or_else(|| text.get("value").and_then(|v| v.as_str()))
+}
+
+/// Convert Chat Completions message content (string or array of parts) into
+/// Responses API content items, merging adjacent text parts.
+fn convert_message_content(content: &Value) -> Vec<ContentItem> {
+    let parts = match content {
+        Value::String(s) => {
+            return vec![ContentItem::InputText { text: s.clone() }];
+        }
+        Value::Array(arr) => arr,
+        _ => {
+            return vec![ContentItem::InputText {
+                text: content.to_string(),
+            }];
+        }
+    };
+
+    let mut items = Vec::new();
+    let mut texts: Vec<String> = Vec::new();
+    let flush_texts = |texts: &mut Vec<String>, items: &mut Vec<ContentItem>| {
+        if !texts.is_empty() {
+            items.push(ContentItem::InputText {
+                text: texts.join(" "),
+            });
+            texts.clear();
+        }
+    };
+
+    for part in parts {
+        let part_type = part.get("type").and_then(|t| t.as_str());
+        match part_type {
+            Some("input_audio") => {
+                let audio = part.get("input_audio");
+                let field = |name: &str| {
+                    audio
+                        .and_then(|a| a.get(name))
+                        .and_then(|v| v.as_str())
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                flush_texts(&mut texts, &mut items);
+                items.push(ContentItem::InputAudio {
+                    input_audio: InputAudio {
+                        data: field("data"),
+                        format: field("format"),
+                    },
+                });
+            }
+            Some("image_url") => {
+                // `image_url` is either `{ "url": "..." }` or a bare string
+                let url = part.get("image_url").and_then(|image| {
+                    image
+                        .get("url")
+                        .and_then(|u| u.as_str())
+                        .or_else(|| image.as_str())
+                });
+                if let Some(url) = url {
+                    flush_texts(&mut texts, &mut items);
+                    items.push(ContentItem::InputImage {
+                        image_url: url.to_string(),
+                    });
+                }
+            }
+            _ => match part_text(part) {
+                Some(text) => texts.push(text.to_string()),
+                None => println!(
+                    "⚠️  Skipping content part without text (type: {})",
+                    part_type.unwrap_or("none")
+                ),
+            },
+        }
+    }
+    flush_texts(&mut texts, &mut items);
+
+    if items.is_empty() {
+        items.push(ContentItem::InputText {
+            text: String::new(),
+        });
+    }
+    items
+}
+
+/// Whether a message's raw `content` carries nothing: null, `""` or no parts.
+fn is_empty_content(content: &Value) -> bool {
+    match content {
+        Value::Null => true,
+        Value::String(s) => s.is_empty(),
+        Value::Array(parts) => parts.is_empty(),
+        _ => false,
+    }
+}
+
+/// Check that every tool message answers an earlier tool call: the one its
+/// `tool_call_id` names, or without an id the oldest call still unanswered.
+/// Returns the reason it is rejected otherwise.
+fn validate_tool_results(messages: &[ChatMessage]) -> Result<(), String> {
+    let mut calls = HashSet::new();
+    let mut pending_calls = std::collections::VecDeque::new();
+    for (index, msg) in messages.iter().enumerate() {
+        if msg.role == "tool" {
+            match &msg.tool_call_id {
+                Some(id) => {
+                    if !calls.contains(id) {
+                        return Err(format!(
+                            "messages[{}] is a tool message for tool_call_id '{}', which no earlier assistant message called",
+                            index, id
+                        ));
+                    }
+                    if let Some(answered) = pending_calls.iter().position(|pending| pending == id) {
+                        pending_calls.remove(answered);
+                    }
+                }
+                None => {
+                    if pending_calls.pop_front().is_none() {
+                        return Err(format!(
+                            "messages[{}] is a tool message without tool_call_id and there is no unanswered tool call for it to answer",
+                            index
+                        ));
+                    }
+                }
+            }
+            continue;
+        }
+        for call in msg.tool_calls.iter().flatten() {
+            let id = tool_call_parts(call).0;
+            calls.insert(id.clone());
+            pending_calls.push_back(id);
+        }
+    }
+    Ok(())
+}
+
+/// `(id, name, arguments)` of a Chat Completions tool call. Arguments are a
+/// JSON string on the wire, but some clients send the object itself.
+fn tool_call_parts(call: &Value) -> (String, String, String) {
+    let function = call.get("function");
+    let text = |value: Option<&Value>| match value {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    };
+    (
+        text(call.get("id")),
+        text(function.and_then(|f| f.get("name"))),
+        text(function.and_then(|f| f.get("arguments"))),
+    )
+}
+
+/// Concatenate the text parts of converted content, ignoring media.
+fn content_text(content: &[ContentItem]) -> String {
+    content
+        .iter()
+        .filter_map(|item| match item {
+            ContentItem::InputText { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Chat Completions API format (what CLINE sends)
+#[derive(Deserialize, Debug, Clone)]
+struct ChatCompletionsRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    /// Accepted but not forwarded: the Codex backend samples at its own
+    /// temperature. `null` parses as `None`, the same as an omitted field, so
+    /// any future forwarding or clamping must skip it rather than send a value.
+    #[allow(dead_code)]
+    temperature: Option<f32>,
+    max_tokens: Option<i32>,
+    stream: Option<bool>,
+    /// Number of choices to generate; each is a separate upstream request
+    n: Option<u32>,
+    tools: Option<Vec<Value>>,
+    tool_choice: Option<Value>,
+    /// Let the model call several tools in one turn; off unless the client asks
+    parallel_tool_calls: Option<bool>,
+    /// Client tags (string key/value pairs), validated by `validate_metadata`
+    metadata: Option<Value>,
+    stream_options: Option<StreamOptions>,
+    /// Overrides the model-name suffix and configured default efforts
+    reasoning_effort: Option<String>,
+    /// Processing tier, one of `SERVICE_TIERS`
+    service_tier: Option<String>,
+    /// Proxy extension: `parts` returns message content as an array of typed
+    /// parts instead of a string (non-streaming only)
+    content_format: Option<String>,
+    /// Ask the backend to keep the response for later retrieval by id
+    store: Option<bool>,
+    /// Predicted output (`{"type": "content", "content": ...}`) for faster
+    /// rewrites; dropped for the Codex backend, which does not take it
+    prediction: Option<Value>,
+    /// Legacy spelling of `tools`, translated by `migrate_legacy_functions`.
+    /// Still set afterwards, marking a client that expects `function_call` back.
+    functions: Option<Vec<Value>>,
+    /// Legacy spelling of `tool_choice`
+    function_call: Option<Value>,
+}
+
+/// The fields of `ChatCompletionsRequest`, parsed only to find unknown ones
+/// under `--strict-request-fields`. Keep the two in step.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct StrictChatCompletionsFields {
+    model: IgnoredAny,
+    messages: IgnoredAny,
+    temperature: Option<IgnoredAny>,
+    max_tokens: Option<IgnoredAny>,
+    stream: Option<IgnoredAny>,
+    n: Option<IgnoredAny>,
+    tools: Option<IgnoredAny>,
+    tool_choice: Option<IgnoredAny>,
+    parallel_tool_calls: Option<IgnoredAny>,
+    metadata: Option<IgnoredAny>,
+    stream_options: Option<IgnoredAny>,
+    reasoning_effort: Option<IgnoredAny>,
+    service_tier: Option<IgnoredAny>,
+    content_format: Option<IgnoredAny>,
+    store: Option<IgnoredAny>,
+    prediction: Option<IgnoredAny>,
+    functions: Option<IgnoredAny>,
+    function_call: Option<IgnoredAny>,
+}
+
+/// Streaming options the proxy honors; others are accepted and ignored.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+struct StreamOptions {
+    /// Add `"usage": null` to every chunk and a final usage-only chunk
+    include_usage: Option<bool>,
+    /// Add random `obfuscation` padding to every chunk
+    include_obfuscation: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ChatMessage {
+    role: String,
+    #[serde(default)]
+    content: Value, // Can be string, array or null (assistant tool calls)
+    /// Calls requested by an assistant turn, in OpenAI's `tool_calls` shape
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<Value>>,
+    /// On `tool` messages, the id of the call this is the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+    /// Legacy single call on an assistant turn, rewritten into `tool_calls`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function_call: Option<Value>,
+}
+
+/// Assistant message content: a plain string, or typed parts (`text` with
+/// annotations, `refusal`) for clients that ask for them.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<Value>),
+}
+
+/// Chat Completions API response format (what CLINE expects)
+#[derive(Serialize, Debug)]
+struct ChatCompletionsResponse {
+    id: String,
+    // Returned to clients in the `x-upstream-id` header rather than the body
+    #[serde(skip)]
+    upstream_id: Option<String>,
+    // Backend model that produced the answer, returned in `x-served-model`
+    #[serde(skip)]
+    served_model: Option<String>,
+    // Time spent on the upstream request, reported in the access log
+    #[serde(skip)]
+    upstream_latency: Option<std::time::Duration>,
+    object: String,
+    created: i64,
+    model: String,
+    /// Tier that served the request: the backend's report, else the requested one
+    service_tier: String,
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+}
+
+impl ChatCompletionsResponse {
+    /// Whether any choice calls a tool.
+    fn has_tool_calls(&self) -> bool {
+        self.choices
+            .iter()
+            .any(|choice| choice.message.tool_calls.is_some())
+    }
+
+    /// Answer in the legacy `functions` shape: the first call as
+    /// `message.function_call` and `finish_reason: "function_call"`.
+    fn use_legacy_function_call(&mut self) {
+        for choice in &mut self.choices {
+            let Some(calls) = choice.message.tool_calls.take() else {
+                continue;
+            };
+            if calls.len() > 1 {
+                println!(
+                    "   Legacy function_call holds one call; dropping {} more",
+                    calls.len() - 1
+                );
+            }
+            choice.message.function_call = calls.into_iter().next().map(|call| call.function);
+            if choice.finish_reason.as_deref() == Some("tool_calls") {
+                choice.finish_reason = Some("function_call".to_string());
+            }
+        }
+    }
+
+    /// Put `prefix` and `suffix` around each text answer. Refusals and
+    /// tool-call-only answers have no content and are left alone.
+    fn wrap_content(&mut self, prefix: &str, suffix: &str) {
+        if prefix.is_empty() && suffix.is_empty() {
+            return;
+        }
+        for choice in &mut self.choices {
+            let message = &mut choice.message;
+            let Some(MessageContent::Text(text)) = &mut message.content else {
+                continue;
+            };
+            *text = format!("{}{}{}", prefix, text, suffix);
+            // Separate parts keep annotation offsets in the original text valid
+            if !prefix.is_empty() {
+                message
+                    .content_parts
+                    .insert(0, json!({ "type": "text", "text": prefix }));
+            }
+            if !suffix.is_empty() {
+                message
+                    .content_parts
+                    .push(json!({ "type": "text", "text": suffix }));
+            }
+        }
+    }
+
+    /// Send each message's content as its typed parts rather than a string.
+    fn use_content_parts(&mut self) {
+        for choice in &mut self.choices {
+            let message = &mut choice.message;
+            if !message.content_parts.is_empty() {
+                message.content = Some(MessageContent::Parts(std::mem::take(
+                    &mut message.content_parts,
+                )));
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Choice {
+    index: i32,
+    message: ChatResponseMessage,
+    // The backend reports no logprobs; `Some(null)` only makes the key present
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Value>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatResponseMessage {
+    role: String,
+    content: Option<MessageContent>,
+    // Structured form of `content`, sent instead of it when parts are requested
+    #[serde(skip)]
+    content_parts: Vec<Value>,
+    // Populated instead of `content` when the model declines to answer
+    refusal: Option<String>,
+    // Reasoning is only surfaced as tagged stream chunks, never in the visible answer
+    #[serde(skip)]
+    reasoning: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    // Legacy clients (`functions` requests) get their call here instead of `tool_calls`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<FunctionCall>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct ToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    call_type: String,
+    function: FunctionCall,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct FunctionCall {
+    name: String,
+    arguments: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Usage {
+    prompt_tokens: i32,
+    completion_tokens: i32,
+    total_tokens: i32,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+impl From<UpstreamUsage> for Usage {
+    fn from(usage: UpstreamUsage) -> Self {
+        let clamp = |count: i64| count.clamp(0, i64::from(i32::MAX)) as i32;
+        Self {
+            prompt_tokens: clamp(usage.input_tokens),
+            completion_tokens: clamp(usage.output_tokens),
+            total_tokens: clamp(usage.total_tokens),
+        }
+    }
+}
+
+/// Codex Responses API format (what we send to ChatGPT backend)
+#[derive(Serialize, Debug)]
+struct ResponsesApiRequest {
+    model: String,
+    instructions: String,
+    input: Vec<ResponseItem>,
+    tools: Vec<Value>,
+    tool_choice: Value,
+    parallel_tool_calls: bool,
+    reasoning: Option<Value>,
+    store: bool,
+    stream: bool,
+    include: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_tier: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prediction: Option<Value>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseItem {
+    Message {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        role: String,
+        content: Vec<ContentItem>,
+    },
+    FunctionCall {
+        call_id: String,
+        name: String,
+        arguments: String,
+    },
+    FunctionCallOutput {
+        call_id: String,
+        output: String,
+    },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)] // variant names mirror the wire `type` tags
+enum ContentItem {
+    InputText { text: String },
+    InputImage { image_url: String },
+    InputAudio { input_audio: InputAudio },
+}
+
+#[derive(Serialize, Debug)]
+struct InputAudio {
+    data: String,
+    format: String,
+}
+
+/// Codex auth.json structure
+#[derive(Deserialize, Debug, Clone)]
+struct AuthData {
+    #[serde(rename = "OPENAI_API_KEY")]
+    api_key: Option<String>,
+    tokens: Option<TokenData>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct TokenData {
+    access_token: String,
+    // Older auth.json files omit this; it is then derived from the access token
+    account_id: Option<String>,
+    refresh_token: Option<String>,
+}
+
+struct ProxyServer {
+    client: Client,
+    accounts: Arc<AccountPool>,
+    account_retry_delay: std::time::Duration,
+    /// Replaces each account's own `tokens.account_id` upstream
+    account_id: Option<String>,
+    allowed_models: Vec<String>,
+    models_response: Arc<Value>,
+    metrics: Arc<Metrics>,
+    unmatched_log: UnmatchedLog,
+    /// Mask account ids in request logs (off with --no-log-redaction)
+    log_redaction: bool,
+    last_unmatched_log: Arc<AtomicI64>,
+    response_role: String,
+    response_prefix: String,
+    response_suffix: String,
+    audio_models: Vec<String>,
+    /// Sent when the client has no system or developer message
+    fallback_instructions: String,
+    drop_reasoning: bool,
+    reasoning_field: String,
+    always_stream_usage: bool,
+    include_logprobs: bool,
+    strict_streaming: bool,
+    mock_upstream: bool,
+    upstream_based_ids: bool,
+    ids: Arc<IdSource>,
+    limiter: Arc<RequestLimiter>,
+    queue_retry_after: u64,
+    fanout_concurrency: usize,
+    partial_fanout: bool,
+    retry_empty: bool,
+    enforce_tool_choice: ToolChoiceEnforcement,
+    stream_accept_conflict: StreamAcceptConflict,
+    max_n: u32,
+    strict_max_n: bool,
+    upstream_headers: Arc<reqwest::header::HeaderMap>,
+    error_messages: Arc<ErrorMessages>,
+    accept_encoding: String,
+    fallback_models: Arc<HashMap<String, Vec<String>>>,
+    max_output_tokens: Arc<HashMap<String, i32>>,
+    strict_max_tokens: bool,
+    strict_request_fields: bool,
+    max_messages: usize,
+    truncate_messages: bool,
+    default_reasoning_effort: Option<String>,
+    model_reasoning_efforts: Arc<HashMap<String, String>>,
+    /// Client model name to backend model (`--model-map`)
+    model_map: Arc<HashMap<String, String>>,
+    router: Arc<Router>,
+    object_names: Arc<ObjectNames>,
+    log_format: LogFormat,
+    route_timeouts: Arc<HashMap<String, u64>>,
+    stream_idle_timeout: Option<std::time::Duration>,
+    health_check_timeout: std::time::Duration,
+    max_sse_events: usize,
+    max_tool_arguments_bytes: usize,
+    trace_sse: bool,
+    flush_policy: FlushPolicy,
+    stream_channel_capacity: usize,
+    transforms: Arc<Vec<Transform>>,
+    default_tools: Arc<Vec<Value>>,
+    default_tools_mode: DefaultToolsMode,
+    admin_token: Option<String>,
+    forward_auth: bool,
+    recent: Arc<RecentRequests>,
+    budgets: Arc<ConversationBudgets>,
+    throughput: Arc<TokenThroughput>,
+    token_estimator: Arc<dyn TokenEstimator>,
+    instance_id: String,
+    allow_upstream_model_header: bool,
+    errors_as_200: bool,
+}
+
+#[derive(Clone, Debug)]
+struct ResolvedModel {
+    request_model: String,
+    backend_model: String,
+    reasoning_effort: Option<String>,
+}
+
+/// Split an `--auth-path` value into the path and its optional `@WEIGHT`.
+fn parse_auth_spec(spec: &str) -> Result<(&str, u32)> {
+    match spec.rsplit_once('@') {
+        Some((path, weight)) => {
+            let weight = weight
+                .parse::<u32>()
+                .ok()
+                .filter(|weight| *weight > 0)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid account weight in '{}': expected a positive integer",
+                        spec
+                    )
+                })?;
+            Ok((path, weight))
+        }
+        None => Ok((spec, 1)),
+    }
+}
+
+fn expand_home(path: &str) -> Result<String> {
+    if path.starts_with("~/") {
+        let home = std::env::var("HOME").context("HOME environment variable not set")?;
+        Ok(path.replacen('~', &home, 1))
+    } else {
+        Ok(path.to_string())
+    }
+}
+
+/// Read an auth.json. Its account id is required unless `account_id_override`
+/// is set, as only that one is sent then.
+async fn load_auth_data(auth_path: &str, account_id_override: bool) -> Result<AuthData> {
+    let auth_content = tokio::fs::read_to_string(&auth_path)
+        .await
+        .with_context(|| format!("Failed to read {}", auth_path))?;
+
+    let mut auth_data: AuthData = serde_json::from_str(&auth_content)
+        .with_context(|| format!("Failed to parse {}", auth_path))?;
+
+    if let Some(tokens) = auth_data.tokens.as_mut() {
+        if tokens.account_id.is_none() && !account_id_override {
+            let account_id =
+                jwt::account_id_from_token(&tokens.access_token).with_context(|| {
+                    format!(
+                    "{} has no tokens.account_id and it could not be derived from the access token",
+                    auth_path
+                )
+                })?;
+            tokens.account_id = Some(account_id);
+        }
+    }
+
+    Ok(auth_data)
+}
+
+impl ProxyServer {
+    async fn new(args: &Args) -> Result<Self> {
+        let mut account_models = parse_account_models(&args.account_models)?;
+        let mut accounts = Vec::new();
+        // Forwarded client credentials replace the account pool entirely
+        let auth_paths: &[String] = if args.forward_auth {
+            &[]
+        } else {
+            &args.auth_path
+        };
+        for spec in auth_paths {
+            let (auth_path, weight) = parse_auth_spec(spec)?;
+            let expanded_path = expand_home(auth_path)?;
+            let auth_data = load_auth_data(&expanded_path, args.account_id.is_some()).await?;
+            let mut account = Account::new(
+                auth_path.to_string(),
+                expanded_path.into(),
+                auth_data,
+                weight,
+            );
+            if let Some(models) = account_models.remove(auth_path) {
+                println!("✓ Account {} serves: {}", auth_path, models.join(", "));
+                account = account.serving_models(models);
+            }
+            accounts.push(account);
+        }
+        if let Some(label) = account_models.keys().next() {
+            return Err(anyhow!(
+                "--account-models names '{}', which is not an --auth-path account",
+                label
+            ));
+        }
+        let accounts = Arc::new(AccountPool::new(accounts, args.quota_aware_routing));
+
+        // Create client with browser-like configuration
+        let client = Client::builder()
+            .user_agent("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
+            // Compressed bodies are decoded before the SSE parser sees them
+            .gzip(true)
+            .deflate(true)
+            .brotli(true)
+            .build()
+            .context("Failed to create HTTP client")?;
+
+        let allowed_models = load_allowed_models();
+        if allowed_models.is_empty() {
+            return Err(anyhow!(
+                "No allowed models configured. Set ALLOWED_MODELS or use defaults."
+            ));
+        }
+
+        let accept_encoding = parse_accept_encoding(&args.upstream_accept_encoding)?;
+        if accept_encoding != DEFAULT_ACCEPT_ENCODING {
+            println!("✓ Upstream Accept-Encoding: {}", accept_encoding);
+        }
+
+        let upstream_headers = load_upstream_headers(&args.upstream_headers)?;
+        for name in upstream_headers.keys() {
+            println!("✓ Extra upstream header: {}", name);
+        }
+
+        let error_messages = load_error_messages(&args.error_messages)?;
+        for key in error_messages.keys() {
+            println!("✓ Custom error message for: {}", key);
+        }
+
+        let router = Router::load(&args.routes)?;
+        for rule in router.describe() {
+            println!("✓ Upstream route: {}", rule);
+        }
+
+        let object_names = parse_object_names(&args.object_names)?;
+
+        let reasoning_field = args.reasoning_field.trim().to_string();
+        if reasoning_field.is_empty()
+            || ["role", "content", "refusal", "tool_calls"].contains(&reasoning_field.as_str())
+        {
+            return Err(anyhow!(
+                "Invalid --reasoning-field '{}': it must be a name other than role, content, refusal or tool_calls",
+                args.reasoning_field
+            ));
+        }
+        if reasoning_field != "reasoning_content" {
+            println!("✓ Streamed reasoning field: {}", reasoning_field);
+        }
+
+        let transforms = args
+            .transforms
+            .iter()
+            .map(|spec| Transform::parse(spec))
+            .collect::<Result<Vec<_>>>()?;
+        for transform in &transforms {
+            println!("✓ Message transform: {}", transform.describe());
+        }
+
+        let flush_policy = FlushPolicy::new(args.stream_flush_ms, args.stream_flush_bytes);
+        if !flush_policy.is_per_frame() {
+            println!("✓ Stream flushing: {}", flush_policy.describe());
+        }
+
+        let default_tools = load_default_tools(args.default_tools_file.as_deref())?;
+        if !default_tools.is_empty() {
+            println!(
+                "✓ Default tools ({:?}): {}",
+                args.default_tools_mode,
+                default_tools
+                    .iter()
+                    .filter_map(tool_name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        let token_estimator = tokens::build(args.token_estimator)?;
+        println!("✓ Token estimator: {}", token_estimator.name());
+
+        let model_map = parse_model_map(&args.model_map)?;
+        if !model_map.is_empty() {
+            let mut pairs: Vec<String> = model_map
+                .iter()
+                .map(|(client, backend)| format!("{} -> {}", client, backend))
+                .collect();
+            pairs.sort();
+            println!("✓ Model map: {}", pairs.join(", "));
+        }
+
+        // The list only depends on startup configuration, so build it once.
+        let models_response = Arc::new(build_models_response(
+            &expand_request_models(&client_models(&allowed_models, &model_map)),
+            &load_models_owned_by(),
+            &object_names,
+        ));
+
+        Ok(Self {
+            client,
+            accounts,
+            account_retry_delay: std::time::Duration::from_millis(args.account_retry_delay_ms),
+            account_id: args.account_id.clone(),
+            allowed_models,
+            models_response,
+            metrics: Arc::new(Metrics::default()),
+            unmatched_log: args.unmatched_log,
+            log_redaction: !args.no_log_redaction,
+            last_unmatched_log: Arc::new(AtomicI64::new(0)),
+            response_role: args.response_role.clone(),
+            response_prefix: args.response_prefix.clone(),
+            response_suffix: args.response_suffix.clone(),
+            audio_models: load_audio_models(),
+            fallback_instructions: args
+                .instructions
+                .clone()
+                .filter(|text| !text.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_INSTRUCTIONS.to_string()),
+            drop_reasoning: args.drop_reasoning,
+            reasoning_field,
+            always_stream_usage: args.always_stream_usage,
+            include_logprobs: args.include_logprobs,
+            strict_streaming: args.strict_streaming,
+            mock_upstream: args.mock_upstream,
+            upstream_based_ids: args.upstream_based_ids,
+            ids: Arc::new(IdSource::new(args.id_seed)),
+            limiter: Arc::new(RequestLimiter::new(
+                args.max_concurrent_requests,
+                args.max_queue_depth,
+            )),
+            queue_retry_after: args.queue_retry_after,
+            fanout_concurrency: args.fanout_concurrency,
+            partial_fanout: args.partial_fanout,
+            retry_empty: args.retry_empty,
+            enforce_tool_choice: args.enforce_tool_choice,
+            stream_accept_conflict: args.stream_accept_conflict,
+            max_n: args.max_n,
+            strict_max_n: args.strict_max_n,
+            upstream_headers: Arc::new(upstream_headers),
+            error_messages: Arc::new(error_messages),
+            accept_encoding,
+            fallback_models: Arc::new(parse_fallback_models(&args.fallback_models)?),
+            max_output_tokens: Arc::new(parse_max_output_tokens(&args.max_output_tokens)?),
+            strict_max_tokens: args.strict_max_tokens,
+            strict_request_fields: args.strict_request_fields,
+            max_messages: args.max_messages,
+            truncate_messages: args.truncate_messages,
+            default_reasoning_effort: args.reasoning_effort.clone(),
+            model_map: Arc::new(model_map),
+            model_reasoning_efforts: Arc::new(parse_model_reasoning_efforts(
+                &args.model_reasoning_efforts,
+            )?),
+            router: Arc::new(router),
+            object_names: Arc::new(object_names),
+            log_format: args.log_format,
+            route_timeouts: Arc::new(parse_route_timeouts(&args.route_timeouts)?),
+            stream_idle_timeout: (args.stream_idle_timeout > 0)
+                .then(|| std::time::Duration::from_secs(args.stream_idle_timeout)),
+            health_check_timeout: std::time::Duration::from_secs(args.health_check_timeout),
+            max_sse_events: args.max_sse_events,
+            max_tool_arguments_bytes: args.max_tool_arguments_bytes,
+            trace_sse: args.trace_sse,
+            flush_policy,
+            stream_channel_capacity: args.stream_channel_capacity,
+            transforms: Arc::new(transforms),
+            default_tools: Arc::new(default_tools),
+            default_tools_mode: args.default_tools_mode,
+            admin_token: args.admin_token.clone(),
+            forward_auth: args.forward_auth,
+            recent: Arc::new(RecentRequests::new(args.recent_requests)),
+            budgets: Arc::new(ConversationBudgets::new(args.conversation_token_budget)),
+            throughput: Arc::new(TokenThroughput::new(args.tokens_per_minute)),
+            token_estimator,
+            instance_id: Uuid::new_v4().simple().to_string(),
+            allow_upstream_model_header: args.allow_upstream_model_header,
+            errors_as_200: args.errors_as_200,
+        })
+    }
+
+    fn allowed_models(&self) -> &[String] {
+        &self.allowed_models
+    }
+
+    fn allowed_request_models(&self) -> Vec<String> {
+        expand_request_models(&client_models(&self.allowed_models, &self.model_map))
+    }
+
+    /// Backend model serving a client-facing name: its `--model-map` target,
+    /// or the name itself when it is allowed.
+    fn backend_model_for<'a>(&'a self, model: &'a str) -> Option<&'a str> {
+        match self.model_map.get(model) {
+            Some(backend) => Some(backend),
+            None => self
+                .allowed_models
+                .iter()
+                .any(|allowed| allowed == model)
+                .then_some(model),
+        }
+    }
+
+    fn resolve_model(&self, model: &str) -> Option<ResolvedModel> {
+        if let Some(backend_model) = self.backend_model_for(model) {
+            return Some(ResolvedModel {
+                request_model: model.to_string(),
+                backend_model: backend_model.to_string(),
+                reasoning_effort: None,
+            });
+        }
+
+        for (suffix, effort) in REASONING_SUFFIX_ALIASES {
+            if let Some(base_model) = model.strip_suffix(suffix) {
+                if let Some(backend_model) = self.backend_model_for(base_model) {
+                    return Some(ResolvedModel {
+                        request_model: model.to_string(),
+                        backend_model: backend_model.to_string(),
+                        reasoning_effort: Some(effort.to_string()),
+                    });
+                }
+            }
+        }
+
+        None
+    }
+
+    fn supports_audio(&self, backend_model: &str) -> bool {
+        self.audio_models.iter().any(|model| model == backend_model)
+    }
+
+    fn models_response(&self) -> &Value {
+        &self.models_response
+    }
+
+    /// Configured output token cap for the backend model, else the requested name.
+    fn max_output_tokens(&self, model: &ResolvedModel) -> Option<i32> {
+        self.max_output_tokens
+            .get(&model.backend_model)
+            .or_else(|| self.max_output_tokens.get(&model.request_model))
+            .copied()
+    }
+
+    /// Parse and check a chat completion body without calling upstream: JSON
+    /// shape, metadata, tools, model allowlist, reasoning effort, audio support
+    /// and the output token cap (clamping `max_tokens` unless strict). The
+    /// error is the 400 body for the first problem found.
+    fn validate_chat_request(
+        &self,
+        body: &[u8],
+        headers: &warp::http::HeaderMap,
+    ) -> Result<(ChatCompletionsRequest, ResolvedModel), Value> {
+        let mut chat_req: ChatCompletionsRequest = serde_json::from_slice(body).map_err(|e| {
+            println!("❌ JSON parse error: {}", e);
+            build_invalid_json_response(&e, body)
+        })?;
+
+        if self.strict_request_fields {
+            if let Err(e) = serde_json::from_slice::<StrictChatCompletionsFields>(body) {
+                // serde names the field as "unknown field `name`, expected ..."
+                if let Some(field) = e
+                    .to_string()
+                    .strip_prefix("unknown field `")
+                    .and_then(|rest| rest.split_once('`'))
+                    .map(|(field, _)| field.to_string())
+                {
+                    println!(
+                        "❌ Unknown request field '{}' (--strict-request-fields)",
+                        field
+                    );
+                    return Err(build_unknown_field_response(&field));
+                }
+            }
+        }
+
+        migrate_legacy_functions(&mut chat_req);
+
+        // Bounded before conversion, which walks every message
+        if self.max_messages > 0 && chat_req.messages.len() > self.max_messages {
+            if !self.truncate_messages {
+                return Err(build_too_many_messages_response(
+                    chat_req.messages.len(),
+                    self.max_messages,
+                ));
+            }
+            let count = chat_req.messages.len();
+            chat_req.messages = keep_recent_messages(chat_req.messages, self.max_messages);
+            println!(
+                "✂️  Truncated messages from {} to {} (system and developer messages plus the last {})",
+                count,
+                chat_req.messages.len(),
+                self.max_messages
+            );
+        }
+
+        // The backend answers an empty input with an opaque error, so say why here
+        if chat_req
+            .messages
+            .iter()
+            .all(|msg| matches!(msg.role.as_str(), "system" | "developer"))
+        {
+            return Err(build_empty_messages_response(!chat_req.messages.is_empty()));
+        }
+
+        validate_tool_results(&chat_req.messages)
+            .map_err(|reason| build_invalid_tool_message_response(&reason))?;
+
+        if let Some(metadata) = &chat_req.metadata {
+            validate_metadata(metadata)
+                .map_err(|reason| build_invalid_metadata_response(&reason))?;
+        }
+
+        if let Some(tier) = chat_req.service_tier.as_deref() {
+            if !SERVICE_TIERS.contains(&tier) {
+                return Err(build_invalid_service_tier_response(tier));
+            }
+        }
+
+        if let Some(format) = chat_req.content_format.as_deref() {
+            if format != "string" && format != "parts" {
+                return Err(build_invalid_content_format_response(format));
+            }
+        }
+
+        if let Some(tools) = &chat_req.tools {
+            validate_tools(tools).map_err(|reason| build_invalid_tools_response(&reason))?;
+        }
+
+        let mut resolved_model = self.resolve_model(&chat_req.model).ok_or_else(|| {
+            build_model_not_allowed_response(&chat_req.model, &self.allowed_request_models())
+        })?;
+
+        if let Some(requested) = chat_req.reasoning_effort.as_deref() {
+            let effort = parse_effort(requested)
+                .map_err(|_| build_invalid_reasoning_effort_response(requested))?;
+            chat_req.reasoning_effort = Some(effort);
+        }
+        resolved_model.reasoning_effort =
+            self.reasoning_effort(&resolved_model, chat_req.reasoning_effort.as_deref());
+
+        if messages_contain_audio(&chat_req.messages)
+            && !self.supports_audio(&resolved_model.backend_model)
+        {
+            return Err(build_audio_not_supported_response(&chat_req.model));
+        }
+
+        if let (Some(requested), Some(cap)) =
+            (chat_req.max_tokens, self.max_output_tokens(&resolved_model))
+        {
+            if requested > cap {
+                if self.strict_max_tokens {
+                    return Err(build_max_tokens_exceeded_response(
+                        &chat_req.model,
+                        requested,
+                        cap,
+                    ));
+                }
+                println!(
+                    "✂️  Clamping max_tokens {} to {} for model '{}'",
+                    requested, cap, chat_req.model
+                );
+                chat_req.max_tokens = Some(cap);
+            }
+        }
+
+        // Each choice is its own upstream request, so `n` multiplies cost
+        if let Some(requested) = chat_req.n.filter(|&n| self.max_n > 0 && n > self.max_n) {
+            if self.strict_max_n {
+                return Err(build_max_n_exceeded_response(requested, self.max_n));
+            }
+            println!("✂️  Clamping n {} to {}", requested, self.max_n);
+            chat_req.n = Some(self.max_n);
+        }
+
+        if chat_req.stream == Some(true) && accept_excludes_event_stream(headers) {
+            match self.stream_accept_conflict {
+                StreamAcceptConflict::BodyWins => println!(
+                    "⚠️  stream: true but Accept rules out text/event-stream; streaming anyway (--stream-accept-conflict body-wins)"
+                ),
+                StreamAcceptConflict::AcceptWins => {
+                    println!(
+                        "⚠️  stream: true but Accept rules out text/event-stream; answering with JSON (--stream-accept-conflict accept-wins)"
+                    );
+                    chat_req.stream = Some(false);
+                }
+            }
+        }
+
+        if self.strict_streaming && chat_req.stream.unwrap_or(false) {
+            if let Some(reason) = self.replay_reason(&chat_req) {
+                println!(
+                    "❌ Refusing to fake a stream for {} (--strict-streaming)",
+                    reason
+                );
+                return Err(build_streaming_unavailable_response(reason));
+            }
+        }
+
+        Ok((chat_req, resolved_model))
+    }
+
+    /// Count a completed request's tokens, and charge its conversation.
+    /// Returns the conversation's remaining budget when one is tracked.
+    fn record_usage(
+        &self,
+        response: &ChatCompletionsResponse,
+        conversation_id: Option<&str>,
+    ) -> Option<u64> {
+        let tokens = response.usage.as_ref().map_or(0, |u| u.total_tokens.max(0));
+        self.throughput.record(tokens as u64);
+        Some(self.budgets.record(conversation_id?, tokens as u64))
+    }
+
+    /// Why a streaming request must be answered in full and replayed as
+    /// chunks, or `None` when its output can be forwarded as it arrives.
+    fn replay_reason(&self, chat_req: &ChatCompletionsRequest) -> Option<&'static str> {
+        if chat_req.n.unwrap_or(1) > 1 {
+            // The choices come from separate upstream requests
+            Some("n > 1")
+        } else if chat_req.functions.is_some() {
+            // Tool calls are rewritten into `function_call` once complete
+            Some("legacy functions")
+        } else if self.enforce_tool_choice != ToolChoiceEnforcement::Off
+            && requires_tool_call(chat_req.tool_choice.as_ref())
+        {
+            // The answer is checked for a tool call before it is sent
+            Some("--enforce-tool-choice")
+        } else {
+            None
+        }
+    }
+
+    /// The client's tools combined with --default-tools-file. A client that
+    /// sets `tool_choice: "none"` asked for no tool calls, so gets no defaults.
+    fn with_default_tools(
+        &self,
+        tools: Option<Vec<Value>>,
+        tool_choice: Option<&Value>,
+    ) -> Option<Vec<Value>> {
+        if self.default_tools.is_empty() || tool_choice.and_then(|v| v.as_str()) == Some("none") {
+            return tools;
+        }
+        match self.default_tools_mode {
+            DefaultToolsMode::Replace => Some(self.default_tools.to_vec()),
+            DefaultToolsMode::Append => {
+                let mut tools = tools.unwrap_or_default();
+                let client_names = tools
+                    .iter()
+                    .filter_map(tool_name)
+                    .map(str::to_string)
+                    .collect::<HashSet<_>>();
+                tools.extend(
+                    self.default_tools
+                        .iter()
+                        .filter(|tool| !tool_name(tool).is_some_and(|n| client_names.contains(n)))
+                        .cloned(),
+                );
+                Some(tools)
+            }
+        }
+    }
+
+    /// Effort sent upstream, by precedence: the request's `reasoning_effort`,
+    /// the model-name suffix, the per-model default, then the global default.
+    fn reasoning_effort(&self, model: &ResolvedModel, requested: Option<&str>) -> Option<String> {
+        requested
+            .map(str::to_string)
+            .or_else(|| model.reasoning_effort.clone())
+            .or_else(|| {
+                self.model_reasoning_efforts
+                    .get(&model.request_model)
+                    .or_else(|| self.model_reasoning_efforts.get(&model.backend_model))
+                    .cloned()
+            })
+            .or_else(|| self.default_reasoning_effort.clone())
+    }
+
+    /// Time limit for handling a request to `path`, `None` when unlimited.
+    fn route_timeout(&self, path: &str) -> Option<std::time::Duration> {
+        let secs = self
+            .route_timeouts
+            .get(path)
+            .or_else(|| self.route_timeouts.get("*"))
+            .copied()
+            .unwrap_or(0);
+        (secs > 0).then(|| std::time::Duration::from_secs(secs))
+    }
+
+    /// Whether the default upstream answers within `--health-check-timeout`.
+    /// A 5xx answer means it is up but failing, so it counts as degraded.
+    async fn probe_upstream(&self) -> Value {
+        if self.mock_upstream {
+            return json!({ "upstream": "mock" });
+        }
+        let route = self.router.select(&[], false);
+        let started = std::time::Instant::now();
+        let probe = self
+            .client
+            .get(&route.url)
+            .header(LOOP_GUARD_HEADER, &self.instance_id)
+            .timeout(self.health_check_timeout)
+            .send()
+            .await;
+        match probe {
+            Ok(response) if response.status().is_server_error() => {
+                eprintln!(
+                    "⚠️  Health check got {} from {}",
+                    response.status(),
+                    route.url
+                );
+                json!({
+                    "upstream": "degraded",
+                    "upstream_status": response.status().as_u16(),
+                    "upstream_latency_ms": started.elapsed().as_millis() as u64
+                })
+            }
+            Ok(_) => json!({
+                "upstream": "reachable",
+                "upstream_latency_ms": started.elapsed().as_millis() as u64
+            }),
+            Err(e) => {
+                eprintln!("⚠️  Health check could not reach {}: {}", route.url, e);
+                json!({
+                    "upstream": "unreachable",
+                    "upstream_error": if e.is_timeout() {
+                        format!("no answer within {}s", self.health_check_timeout.as_secs())
+                    } else {
+                        e.to_string()
+                    }
+                })
+            }
+        }
+    }
+
+    /// The response refusing an /admin request, or `None` when its bearer token
+    /// is valid. Without a configured admin token the endpoints do not exist,
+    /// so the refusal is a plain 404.
+    fn admin_rejection(
+        &self,
+        headers: &warp::http::HeaderMap,
+    ) -> Option<warp::http::Response<warp::hyper::Body>> {
+        let Some(expected) = &self.admin_token else {
+            return Some(
+                warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)
+                    .into_response(),
+            );
+        };
+        let presented = headers
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        (presented != Some(expected.as_str())).then(|| {
+            json_response(
+                warp::http::StatusCode::UNAUTHORIZED,
+                &build_invalid_admin_token_response(),
+            )
+        })
+    }
+
+    /// With `--forward-auth`, a copy of `route` carrying the client's bearer
+    /// token; routes with their own key keep it. `None` when the client sent
+    /// no token.
+    fn forwarded_route(
+        &self,
+        route: Arc<UpstreamRoute>,
+        headers: &warp::http::HeaderMap,
+    ) -> Option<Arc<UpstreamRoute>> {
+        if !self.forward_auth || route.api_key.is_some() {
+            return Some(route);
+        }
+        let token = headers
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+            .filter(|token| !token.is_empty());
+        token.map(|token| {
+            Arc::new(UpstreamRoute {
+                url: route.url.clone(),
+                api_key: Some(token.to_string()),
+            })
+        })
+    }
+
+    /// Whether a `stream: true` request is streamed given its `Accept` header.
+    fn streams_despite_accept(&self, headers: &warp::http::HeaderMap) -> bool {
+        self.stream_accept_conflict == StreamAcceptConflict::BodyWins
+            || !accept_excludes_event_stream(headers)
+    }
+
+    fn log_unmatched(&self, method: &warp::http::Method, path: &str) {
+        match self.unmatched_log {
+            UnmatchedLog::All => println!("❌ UNMATCHED: {} {}", method, path),
+            UnmatchedLog::Sampled => {
+                let now = chrono::Utc::now().timestamp();
+                let last = self.last_unmatched_log.load(Ordering::Relaxed);
+                if now - last >= UNMATCHED_LOG_INTERVAL_SECS
+                    && self
+                        .last_unmatched_log
+                        .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                        .is_ok()
+                {
+                    println!(
+                        "❌ UNMATCHED: {} {} ({} unmatched requests so far, further lines suppressed for {}s)",
+                        method,
+                        path,
+                        self.metrics.unmatched_requests_total.load(Ordering::Relaxed),
+                        UNMATCHED_LOG_INTERVAL_SECS
+                    );
+                }
+            }
+            UnmatchedLog::Off => {}
+        }
+    }
+
+    fn convert_chat_to_responses(
+        &self,
+        chat_req: ChatCompletionsRequest,
+        resolved_model: &ResolvedModel,
+    ) -> ResponsesApiRequest {
+        // Convert messages to ResponseItems
+        let mut input = Vec::new();
+        let mut system_prompts = Vec::new();
+
+        // Calls not yet answered, oldest first; a tool result without an id
+        // answers the oldest one
+        let mut pending_calls = std::collections::VecDeque::new();
+
+        for msg in chat_req.messages {
+            if msg.role == "tool" {
+                let call_id = match msg.tool_call_id {
+                    Some(id) => {
+                        match pending_calls.iter().position(|pending| *pending == id) {
+                            Some(index) => {
+                                pending_calls.remove(index);
+                            }
+                            None => eprintln!(
+                                "⚠️  Tool result for '{}' answers a call that already has one",
+                                id
+                            ),
+                        }
+                        id
+                    }
+                    // Validation rejects a result without an id that answers nothing
+                    None => pending_calls.pop_front().unwrap_or_default(),
+                };
+                input.push(ResponseItem::FunctionCallOutput {
+                    call_id,
+                    output: content_text(&convert_message_content(&msg.content)),
+                });
+                continue;
+            }
+
+            if let Some(tool_calls) = msg.tool_calls.filter(|calls| !calls.is_empty()) {
+                if !is_empty_content(&msg.content) {
+                    input.push(ResponseItem::Message {
+                        id: None,
+                        content: convert_message_content(&msg.content),
+                        role: msg.role,
+                    });
+                }
+                for call in tool_calls {
+                    let (call_id, name, arguments) = tool_call_parts(&call);
+                    pending_calls.push_back(call_id.clone());
+                    input.push(ResponseItem::FunctionCall {
+                        call_id,
+                        name,
+                        arguments,
+                    });
+                }
+                continue;
+            }
+
+            let content = convert_message_content(&msg.content);
+            // The client's own prompt becomes the instructions instead of an input item
+            if msg.role == "system" || msg.role == "developer" {
+                let text = content_text(&content);
+                if !text.trim().is_empty() {
+                    system_prompts.push(text);
+                }
+                continue;
+            }
+            input.push(ResponseItem::Message {
+                id: None,
+                content,
+                role: msg.role,
+            });
+        }
+
+        let instructions = if system_prompts.is_empty() {
+            self.fallback_instructions.clone()
+        } else {
+            system_prompts.join("\n")
+        };
+
+        let tools: Vec<Value> = chat_req
+            .tools
+            .unwrap_or_default()
+            .into_iter()
+            .map(responses_tool)
+            .collect();
+        // The backend only takes parallel_tool_calls alongside tools
+        let parallel_tool_calls = !tools.is_empty() && chat_req.parallel_tool_calls == Some(true);
+        ResponsesApiRequest {
+            model: resolved_model.backend_model.clone(),
+            instructions,
+            input,
+            tools,
+            tool_choice: responses_tool_choice(chat_req.tool_choice),
+            parallel_tool_calls,
+            // No `summary` is requested, so with --drop-reasoning the backend has
+            // no reason to spend output tokens summarizing
+            reasoning: resolved_model
+                .reasoning_effort
+                .as_ref()
+                .map(|effort| json!({ "effort": effort })),
+            store: chat_req.store.unwrap_or(false),
+            stream: true,
+            include: vec![],
+            metadata: chat_req.metadata,
+            max_output_tokens: chat_req.max_tokens,
+            service_tier: chat_req.service_tier,
+            prediction: chat_req.prediction,
+        }
+    }
+
+    async fn proxy_request(
+        &self,
+        chat_req: ChatCompletionsRequest,
+        resolved_model: ResolvedModel,
+        route: &UpstreamRoute,
+    ) -> Result<ChatCompletionsResponse, ProxyError> {
+        println!("🔄 Processing proxy request...");
+        let n = chat_req.n.unwrap_or(1).max(1);
+        if n == 1 {
+            return self
+                .proxy_request_with_fallback(chat_req, resolved_model, route)
+                .await;
+        }
+
+        // The backend produces one answer per request, so fan out and merge the choices
+        let concurrency = match self.fanout_concurrency {
+            0 => n as usize,
+            limit => limit.min(n as usize),
+        };
+        println!(
+            "   Fanning out {} upstream requests for n={} ({} at a time)",
+            n, n, concurrency
+        );
+        let requests = (0..n).map(|_| {
+            self.proxy_request_with_fallback(chat_req.clone(), resolved_model.clone(), route)
+        });
+        let results: Vec<Result<ChatCompletionsResponse, ProxyError>> =
+            futures_util::stream::iter(requests)
+                .buffered(concurrency)
+                .collect()
+                .await;
+
+        let mut succeeded = Vec::with_capacity(results.len());
+        let mut first_error = None;
+        for result in results {
+            match result {
+                Ok(response) => succeeded.push(response),
+                Err(e) => {
+                    eprintln!("❌ Fan-out request failed: {}", e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(error) = first_error {
+            if !self.partial_fanout || succeeded.is_empty() {
+                return Err(error);
+            }
+            println!(
+                "   Returning {} of {} choices (--partial-fanout)",
+                succeeded.len(),
+                n
+            );
+        }
+        let mut responses = succeeded.into_iter();
+
+        let mut merged = responses.next().ok_or(ProxyError::EmptyResponse)?;
+        for response in responses {
+            // The requests ran in parallel, so the slowest one is the upstream time
+            merged.upstream_latency = merged.upstream_latency.max(response.upstream_latency);
+            merged.choices.extend(response.choices);
+            if let (Some(total), Some(usage)) = (merged.usage.as_mut(), response.usage) {
+                total.add(&usage);
+            }
+        }
+        for (index, choice) in merged.choices.iter_mut().enumerate() {
+            choice.index = index as i32;
+        }
+        Ok(merged)
+    }
+
+    /// Try the resolved model, then each configured fallback while the backend
+    /// reports the model itself as unavailable.
+    async fn proxy_request_with_fallback(
+        &self,
+        chat_req: ChatCompletionsRequest,
+        resolved_model: ResolvedModel,
+        route: &UpstreamRoute,
+    ) -> Result<ChatCompletionsResponse, ProxyError> {
+        let mut result = self
+            .with_model_fallback(resolved_model.clone(), |model| {
+                self.proxy_request_enforcing_tool_choice(chat_req.clone(), model, route)
+            })
+            .await;
+        if self.retry_empty && matches!(result, Err(ProxyError::EmptyResponse)) {
+            eprintln!("⚠️  Backend returned no content, retrying once (--retry-empty)");
+            result = self
+                .with_model_fallback(resolved_model, |model| {
+                    self.proxy_request_enforcing_tool_choice(chat_req.clone(), model, route)
+                })
+                .await;
+        }
+        if let Ok(response) = &result {
+            println!(
+                "   Served by backend model '{}'",
+                response.served_model.as_deref().unwrap_or_default()
+            );
+        }
+        result
+    }
+
+    /// Run `attempt` with the resolved model, then with each fallback from
+    /// --fallback-models while it fails because the model is unavailable.
+    async fn with_model_fallback<T, F, Fut>(
+        &self,
+        resolved_model: ResolvedModel,
+        mut attempt: F,
+    ) -> Result<T, ProxyError>
+    where
+        F: FnMut(ResolvedModel) -> Fut,
+        Fut: std::future::Future<Output = Result<T, ProxyError>>,
+    {
+        let fallbacks = self
+            .fallback_models
+            .get(&resolved_model.backend_model)
+            .cloned()
+            .unwrap_or_default();
+
+        let mut attempt_model = resolved_model;
+        let mut remaining = fallbacks.into_iter();
+        loop {
+            let result = attempt(attempt_model.clone()).await;
+            match (result, remaining.next()) {
+                (Err(e), Some(next_model)) if e.is_model_unavailable() => {
+                    println!(
+                        "↪️  Model '{}' unavailable upstream, falling back to '{}'",
+                        attempt_model.backend_model, next_model
+                    );
+                    attempt_model.backend_model = next_model;
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+
+    /// Send the request, then check the answer against a `tool_choice` that
+    /// demands a tool call, acting on `--enforce-tool-choice`. The backend is
+    /// sent the `tool_choice` too, so this only catches answers that ignore it.
+    async fn proxy_request_enforcing_tool_choice(
+        &self,
+        chat_req: ChatCompletionsRequest,
+        resolved_model: ResolvedModel,
+        route: &UpstreamRoute,
+    ) -> Result<ChatCompletionsResponse, ProxyError> {
+        let required = requires_tool_call(chat_req.tool_choice.as_ref())
+            && chat_req
+                .tools
+                .as_ref()
+                .is_some_and(|tools| !tools.is_empty());
+        let retry_req = (required && self.enforce_tool_choice == ToolChoiceEnforcement::Retry)
+            .then(|| chat_req.clone());
+        let response = self
+            .proxy_request_original(chat_req, resolved_model.clone(), route)
+            .await?;
+        if !required || response.has_tool_calls() {
+            return Ok(response);
+        }
+
+        eprintln!("⚠️  tool_choice requires a tool call, but the model answered with text");
+        let Some(mut retry_req) = retry_req else {
+            return match self.enforce_tool_choice {
+                ToolChoiceEnforcement::Error => Err(ProxyError::ToolCallRequired),
+                _ => Ok(response),
+            };
+        };
+        println!("🔁 Retrying once with an instruction to call a tool");
+        retry_req.messages.push(ChatMessage {
+            role: "system".to_string(),
+            content: Value::String(TOOL_CHOICE_RETRY_INSTRUCTION.to_string()),
+            tool_calls: None,
+            tool_call_id: None,
+            function_call: None,
+        });
+        let response = self
+            .proxy_request_original(retry_req, resolved_model, route)
+            .await?;
+        if response.has_tool_calls() {
+            println!("   Retry answered with a tool call");
+            Ok(response)
+        } else {
+            eprintln!("⚠️  Retry also answered with text");
+            Err(ProxyError::ToolCallRequired)
+        }
+    }
+
+    /// Add authentication: the route's own key, or a pooled account (returned
+    /// so its rate limit headers can be recorded). That is `pinned` when set,
+    /// otherwise the next account that can serve `model` and is not in `exclude`.
+    fn with_upstream_auth<'a>(
+        &'a self,
+        mut request_builder: reqwest::RequestBuilder,
+        route: &UpstreamRoute,
+        model: Option<&str>,
+        exclude: &[&Account],
+        pinned: Option<&'a Account>,
+    ) -> Result<(reqwest::RequestBuilder, Option<&'a Account>), ProxyError> {
+        let account = match &route.api_key {
+            Some(api_key) => {
+                request_builder =
+                    request_builder.header("Authorization", format!("Bearer {}", api_key));
+                None
+            }
+            None => {
+                let account = match pinned {
+                    Some(account) => account,
+                    None => self.accounts.select(model, exclude).ok_or_else(|| {
+                        ProxyError::NoAccountForModel(model.unwrap_or_default().to_string())
+                    })?,
+                };
+                let auth = account.auth();
+                if let Some(tokens) = &auth.tokens {
+                    request_builder = request_builder
+                        .header("Authorization", format!("Bearer {}", tokens.access_token));
+                    if let Some(account_id) =
+                        self.account_id.as_ref().or(tokens.account_id.as_ref())
+                    {
+                        request_builder = request_builder.header("chatgpt-account-id", account_id);
+                    }
+                } else if let Some(api_key) = &auth.api_key {
+                    request_builder =
+                        request_builder.header("Authorization", format!("Bearer {}", api_key));
+                }
+                Some(account)
+            }
+        };
+        Ok((request_builder, account))
+    }
+
+    /// Send the converted request upstream, returning the response once it has
+    /// a success status.
+    async fn send_upstream(
+        &self,
+        responses_req: &ResponsesApiRequest,
+        payload: Vec<u8>,
+        route: &UpstreamRoute,
+    ) -> Result<reqwest::Response, ProxyError> {
+        let model = Some(responses_req.model.as_str());
+        // Accounts that answered 429, in order
+        let mut tried: Vec<&Account> = Vec::new();
+        // Accounts whose token was refreshed after a 401; each gets one retry
+        let mut refreshed: Vec<&Account> = Vec::new();
+        // The account whose token was just refreshed, which the retry must use
+        let mut pinned: Option<&Account> = None;
+        loop {
+            // Build request to ChatGPT backend with browser-like headers
+            let request_builder = self
+                .client
+                .post(&route.url)
+                .header("Content-Type", "application/json")
+                .header("Accept", "text/event-stream")
+                .header("Accept-Language", "en-US,en;q=0.9")
+                .header("Accept-Encoding", &self.accept_encoding)
+                .header("Referer", "https://chatgpt.com/")
+                .header("Origin", "https://chatgpt.com")
+                .header("Sec-Fetch-Dest", "empty")
+                .header("Sec-Fetch-Mode", "cors")
+                .header("Sec-Fetch-Site", "same-origin")
+                .header("Cache-Control", "no-cache")
+                .header("Pragma", "no-cache")
+                .header("DNT", "1")
+                .header("OpenAI-Beta", "responses=experimental")
+                .header("originator", "codex_cli_rs");
+
+            let (mut request_builder, account) =
+                self.with_upstream_auth(request_builder, route, model, &tried, pinned.take())?;
+            let sent_token = account
+                .and_then(|account| account.auth().tokens)
+                .filter(|tokens| tokens.refresh_token.is_some())
+                .map(|tokens| tokens.access_token);
+
+            // Add session ID
+            let session_id = Uuid::new_v4();
+            request_builder = request_builder.header("session_id", session_id.to_string());
+
+            // Operator-configured headers go last so they can replace any of the above
+            if !self.upstream_headers.is_empty() {
+                request_builder = request_builder.headers(self.upstream_headers.as_ref().clone());
+            }
+            request_builder = request_builder.header(LOOP_GUARD_HEADER, &self.instance_id);
+
+            // Another pooled account gets a chance when this one is rate limited
+            let next_account = account.filter(|account| {
+                let mut exclude = tried.clone();
+                exclude.push(account);
+                self.accounts.has_alternative(model, &exclude)
+            });
+
+            // Every account posts to the same URL, so a connect error is not
+            // worth another account
+            let response = request_builder.body(payload.clone()).send().await?;
+            if let Some(account) = account {
+                self.accounts
+                    .record_rate_limits(account, response.headers());
+            }
+
+            if !response.status().is_success() {
+                let status = response.status();
+                if let (reqwest::StatusCode::TOO_MANY_REQUESTS, Some(account)) =
+                    (status, next_account)
+                {
+                    eprintln!(
+                        "⚠️  Account '{}' rate limited, failing over in {}ms",
+                        account.label,
+                        self.account_retry_delay.as_millis()
+                    );
+                    tried.push(account);
+                    // Paced so the next account is not tripped by the same burst
+                    tokio::time::sleep(self.account_retry_delay).await;
+                    continue;
+                }
+                if let (reqwest::StatusCode::UNAUTHORIZED, Some(account), Some(token)) =
+                    (status, account, &sent_token)
+                {
+                    if !refreshed.iter().any(|r| std::ptr::eq(*r, account)) {
+                        eprintln!(
+                            "⚠️  Access token for '{}' was rejected, refreshing",
+                            account.label
+                        );
+                        match refresh::refresh_rejected(&self.client, account, token).await {
+                            Ok(true) => {
+                                println!("✓ Refreshed access token for {}, retrying", account.label)
+                            }
+                            Ok(false) => println!(
+                                "✓ Access token for {} was already refreshed, retrying",
+                                account.label
+                            ),
+                            Err(e) => {
+                                eprintln!("❌ Token refresh for {} failed: {:#}", account.label, e);
+                                return Err(ProxyError::ReauthRequired(format!("{:#}", e)));
+                            }
+                        }
+                        refreshed.push(account);
+                        pinned = Some(account);
+                        continue;
+                    }
+                }
+                let retry_after = response
+                    .headers()
+                    .get(warp::http::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse::<u64>().ok());
+                let body = response.text().await.unwrap_or_default();
+                return Err(ProxyError::from_upstream(status, retry_after, body));
+            }
+
+            // reqwest removes the header from bodies it decoded, so one still
+            // present names an encoding the parser would only see as garbage
+            if let Some(encoding) = response
+                .headers()
+                .get(reqwest::header::CONTENT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .filter(|encoding| !encoding.trim().eq_ignore_ascii_case("identity"))
+            {
+                return Err(ProxyError::Conversion(format!(
+                    "backend sent a '{}' encoded body, which the proxy cannot decode; check --upstream-accept-encoding",
+                    encoding
+                )));
+            }
+
+            return Ok(response);
+        }
+    }
+
+    /// Fetch a response stored with `store: true` from the backend and convert
+    /// it as if it had just been generated.
+    async fn fetch_stored_response(
+        &self,
+        id: &str,
+        route: &UpstreamRoute,
+    ) -> Result<ChatCompletionsResponse, ProxyError> {
+        let url = format!("{}/{}", route.url.trim_end_matches('/'), id);
+        let request_builder = self
+            .client
+            .get(&url)
+            .header("Accept", "application/json")
+            .header("OpenAI-Beta", "responses=experimental")
+            .header("originator", "codex_cli_rs");
+        let (mut request_builder, account) =
+            self.with_upstream_auth(request_builder, route, None, &[], None)?;
+        if !self.upstream_headers.is_empty() {
+            request_builder = request_builder.headers(self.upstream_headers.as_ref().clone());
+        }
+        request_builder = request_builder.header(LOOP_GUARD_HEADER, &self.instance_id);
+
+        let upstream_started = std::time::Instant::now();
+        let response = request_builder.send().await?;
+        if let Some(account) = account {
+            self.accounts
+                .record_rate_limits(account, response.headers());
+        }
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(ProxyError::from_upstream(status, None, body));
+        }
+        let body: Value = response
+            .json()
+            .await
+            .map_err(|e| ProxyError::Conversion(format!("Invalid stored response: {}", e)))?;
+        let upstream_latency = upstream_started.elapsed();
+
+        let mut accumulator = self.response_accumulator();
+        accumulator.handle_response_object(&body);
+        let model = body
+            .get("model")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let resolved_model = ResolvedModel {
+            request_model: model.clone(),
+            backend_model: model,
+            reasoning_effort: None,
+        };
+        let mut response = self.build_chat_response(
+            accumulator.finish(),
+            resolved_model,
+            "default".to_string(),
+            &[],
+            upstream_latency,
+            false,
+        )?;
+        // The same id on every fetch, so clients can tell re-fetches apart from new answers
+        if let Some(upstream_id) = &response.upstream_id {
+            response.id = format!("chatcmpl-{}", upstream_id);
+        }
+        Ok(response)
+    }
+
+    async fn proxy_request_original(
+        &self,
+        chat_req: ChatCompletionsRequest,
+        resolved_model: ResolvedModel,
+        route: &UpstreamRoute,
+    ) -> Result<ChatCompletionsResponse, ProxyError> {
+        let mut exchange = self.open_upstream(chat_req, resolved_model, route).await?;
+        let mut accumulator = self.response_accumulator();
+        while let Some(event) = exchange.events.next().await? {
+            accumulator.handle_event(&event);
+        }
+        if let Some(body) = exchange.events.take_object()? {
+            accumulator.handle_response_object(&body);
+        }
+
+        self.build_chat_response(
+            accumulator.finish(),
+            exchange.resolved_model,
+            exchange.requested_tier,
+            &exchange.prompt_messages,
+            exchange.started.elapsed(),
+            exchange.events.truncated(),
+        )
+    }
+
+    fn response_accumulator(&self) -> ResponseAccumulator {
+        ResponseAccumulator::new()
+            .dropping_reasoning(self.drop_reasoning)
+            .limiting_tool_arguments(self.max_tool_arguments_bytes)
+    }
+
+    /// Convert the request and send it upstream, returning once the backend
+    /// has accepted it and its body can be read.
+    async fn open_upstream(
+        &self,
+        chat_req: ChatCompletionsRequest,
+        resolved_model: ResolvedModel,
+        route: &UpstreamRoute,
+    ) -> Result<UpstreamExchange, ProxyError> {
+        // `auto` lets the backend choose; without a report that means the default tier
+        let requested_tier = chat_req
+            .service_tier
+            .clone()
+            .filter(|tier| tier != "auto")
+            .unwrap_or_else(|| "default".to_string());
+        // A stream may run as long as output keeps coming, so bound the gaps instead
+        let idle_timeout = self
+            .stream_idle_timeout
+            .filter(|_| chat_req.stream.unwrap_or(false));
+        // Counted now, as conversion consumes the messages; used if the backend reports no usage
+        let prompt_messages: Vec<Value> = chat_req
+            .messages
+            .iter()
+            .filter_map(|msg| serde_json::to_value(msg).ok())
+            .collect();
+
+        // Convert to Responses API format
+        let mut responses_req = self.convert_chat_to_responses(chat_req, &resolved_model);
+        // The Codex backend rejects an output limit and stored responses; other
+        // Responses endpoints honor both
+        if route.is_codex() {
+            responses_req.max_output_tokens = None;
+            if responses_req.store {
+                println!("   Ignoring store: true (the Codex backend does not store responses)");
+                responses_req.store = false;
+            }
+            if responses_req.prediction.take().is_some() {
+                eprintln!("⚠️  Ignoring prediction (the Codex backend does not support predicted outputs)");
+            }
+        }
+
+        // Serialized once, so the size logged is exactly what is sent; it helps
+        // tell oversized contexts apart from other failures
+        let payload = serde_json::to_vec(&responses_req)
+            .map_err(|e| ProxyError::Conversion(e.to_string()))?;
+        println!(
+            "   Upstream payload: {} bytes, ~{} prompt tokens ({})",
+            payload.len(),
+            self.token_estimator.count_messages(&prompt_messages),
+            self.token_estimator.name()
+        );
+        self.metrics.record_payload_size(payload.len());
+
+        let upstream_started = std::time::Instant::now();
+        let body = if self.mock_upstream {
+            let payload = serde_json::from_slice::<Value>(&payload)
+                .map_err(|e| ProxyError::Conversion(e.to_string()))?;
+            UpstreamBody::Mock(Some(mock::sse_body(&payload)))
+        } else {
+            let send = self.send_upstream(&responses_req, payload, route);
+            UpstreamBody::Http(with_idle_timeout(idle_timeout, send).await??)
+        };
+
+        Ok(UpstreamExchange {
+            events: UpstreamEvents::new(body, idle_timeout, self.trace_sse, self.max_sse_events),
+            resolved_model,
+            requested_tier,
+            prompt_messages,
+            started: upstream_started,
+        })
+    }
+
+    /// Turn collected upstream output into a Chat Completions response.
+    /// `prompt_messages` are only used to estimate usage the backend did not report.
+    fn build_chat_response(
+        &self,
+        output: AccumulatedOutput,
+        resolved_model: ResolvedModel,
+        requested_tier: String,
+        prompt_messages: &[Value],
+        upstream_latency: std::time::Duration,
+        truncated: bool,
+    ) -> Result<ChatCompletionsResponse, ProxyError> {
+        let AccumulatedOutput {
+            response_id,
+            created_at,
+            service_tier,
+            usage,
+            role,
+            content: response_content,
+            refusal: response_refusal,
+            reasoning,
+            content_parts,
+            incomplete_reason,
+            tool_calls,
+        } = output;
+
+        // Cut-off arguments are rarely valid JSON, so the client is told the output ended early
+        let mut truncated = truncated;
+        for call in &tool_calls {
+            if let Some(received) = call.truncated_from {
+                eprintln!(
+                    "⚠️  Tool call '{}' sent {} bytes of arguments; kept the first {}",
+                    call.name,
+                    received,
+                    call.arguments.len()
+                );
+                truncated = true;
+            } else {
+                println!(
+                    "   Tool call '{}': {} bytes of arguments",
+                    call.name,
+                    call.arguments.len()
+                );
+            }
+        }
+
+        // If no content was collected, surface an explicit error instead of faking output.
+        // A response made only of tool calls is valid and has no text.
+        if response_content.is_empty() && response_refusal.is_empty() && tool_calls.is_empty() {
+            if incomplete_reason.as_deref() == Some("content_filter") {
+                return Err(ProxyError::ContentFilter);
+            }
+            return Err(ProxyError::EmptyResponse);
+        }
+
+        let usage = match usage {
+            Some(usage) => Usage::from(usage),
+            None => {
+                let completion_tokens = [&response_content, &response_refusal, &reasoning]
+                    .into_iter()
+                    .chain(
+                        tool_calls
+                            .iter()
+                            .flat_map(|call| [&call.name, &call.arguments]),
+                    )
+                    .map(|text| self.token_estimator.count(text))
+                    .sum::<usize>();
+                let prompt_tokens = self.token_estimator.count_messages(prompt_messages);
+                println!(
+                    "   Upstream reported no usage; estimated {} prompt + {} completion tokens",
+                    prompt_tokens, completion_tokens
+                );
+                Usage::from(UpstreamUsage {
+                    input_tokens: prompt_tokens as i64,
+                    output_tokens: completion_tokens as i64,
+                    total_tokens: (prompt_tokens + completion_tokens) as i64,
+                })
+            }
+        };
+
+        // Refusals are reported per the OpenAI schema: `content: null` plus a `refusal` string.
+        // Without completed message items, the parts are rebuilt from the deltas
+        let content_parts = if content_parts.is_empty() {
+            let mut parts = Vec::new();
+            if !response_content.is_empty() {
+                parts.push(json!({ "type": "text", "text": response_content }));
+            }
+            if !response_refusal.is_empty() {
+                parts.push(json!({ "type": "refusal", "refusal": response_refusal }));
+            }
+            parts
+        } else {
+            content_parts
+        };
+        let (content, refusal) = if !response_refusal.is_empty() {
+            (None, Some(response_refusal))
+        } else if response_content.is_empty() {
+            (None, None)
+        } else {
+            (Some(MessageContent::Text(response_content)), None)
+        };
+
+        let tool_calls = (!tool_calls.is_empty()).then(|| {
+            tool_calls
+                .into_iter()
+                .map(|call| ToolCall {
+                    // Clients match results to calls by id, so never send an empty one
+                    id: if call.call_id.is_empty() {
+                        format!("call_{}", self.ids.uuid().simple())
+                    } else {
+                        call.call_id
+                    },
+                    call_type: "function".to_string(),
+                    function: FunctionCall {
+                        name: call.name,
+                        arguments: call.arguments,
+                    },
+                })
+                .collect::<Vec<ToolCall>>()
+        });
+        let finish_reason = if truncated {
+            "length"
+        } else if tool_calls.is_some() {
+            "tool_calls"
+        } else {
+            "stop"
+        };
+
+        // Create Chat Completions response
+        let id = match &response_id {
+            Some(upstream_id) if self.upstream_based_ids => format!("chatcmpl-{}", upstream_id),
+            _ => format!("chatcmpl-{}", self.ids.uuid()),
+        };
+        let chat_res = ChatCompletionsResponse {
+            id,
+            upstream_id: response_id,
+            served_model: Some(resolved_model.backend_model.clone()),
+            upstream_latency: Some(upstream_latency),
+            object: self.object_names.completion.clone(),
+            // Backend generation time when reported, else the proxy's clock
+            created: created_at.unwrap_or_else(|| self.ids.now()),
+            model: resolved_model.request_model,
+            service_tier: service_tier.unwrap_or(requested_tier),
+            choices: vec![Choice {
+                index: 0,
+                message: ChatResponseMessage {
+                    role: role.unwrap_or_else(|| self.response_role.clone()),
+                    content,
+                    content_parts,
+                    refusal,
+                    reasoning: (!reasoning.is_empty()).then_some(reasoning),
+                    tool_calls,
+                    function_call: None,
+                },
+                logprobs: self.include_logprobs.then_some(Value::Null),
+                finish_reason: Some(finish_reason.to_string()),
+            }],
+            usage: Some(usage),
+        };
+
+        Ok(chat_res)
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
-struct TokenData {
-    access_token: String,
-    account_id: String,
-    refresh_token: Option<String>,
+/// Await `future`, giving up with `ProxyError::Timeout` after `limit` if set.
+async fn with_idle_timeout<T>(
+    limit: Option<std::time::Duration>,
+    future: impl std::future::Future<Output = T>,
+) -> Result<T, ProxyError> {
+    let Some(limit) = limit else {
+        return Ok(future.await);
+    };
+    tokio::time::timeout(limit, future).await.map_err(|_| {
+        eprintln!(
+            "⏱️  No upstream output for {}s, abandoning stream",
+            limit.as_secs()
+        );
+        ProxyError::Timeout
+    })
 }
 
-/// Codex Responses API response format
-#[derive(Deserialize, Debug)]
-struct ResponsesApiResponse {
-    response: Option<ResponseOutput>,
-    id: Option<String>,
+/// Whether a chat completion body asks for a streamed response.
+fn requests_streaming(body: &[u8]) -> bool {
+    #[derive(Deserialize)]
+    struct StreamFlag {
+        stream: Option<bool>,
+    }
+    serde_json::from_slice::<StreamFlag>(body)
+        .ok()
+        .and_then(|flag| flag.stream)
+        .unwrap_or(false)
 }
 
-#[derive(Deserialize, Debug)]
-struct ResponseOutput {
-    content: Option<Vec<ResponseContentItem>>,
-    role: Option<String>,
+/// Source of the upstream SSE bytes: the backend, or a canned body in
+/// `--mock-upstream` mode.
+enum UpstreamBody {
+    Http(reqwest::Response),
+    Mock(Option<Vec<u8>>),
 }
 
-#[derive(Deserialize, Debug)]
-struct ResponseContentItem {
-    #[serde(rename = "type")]
-    content_type: String,
-    text: Option<String>,
+impl UpstreamBody {
+    async fn chunk(&mut self) -> Result<Option<bytes::Bytes>, ProxyError> {
+        match self {
+            Self::Http(response) => Ok(response.chunk().await?),
+            Self::Mock(body) => Ok(body.take().map(bytes::Bytes::from)),
+        }
+    }
 }
 
-struct ProxyServer {
-    client: Client,
-    auth_data: AuthData,
-    allowed_models: Vec<String>,
+/// A request sent upstream: its body plus what is needed to turn the answer
+/// into a Chat Completions response.
+struct UpstreamExchange {
+    events: UpstreamEvents,
+    resolved_model: ResolvedModel,
+    requested_tier: String,
+    /// Counted before conversion consumed the messages; used if the backend
+    /// reports no usage
+    prompt_messages: Vec<Value>,
+    started: std::time::Instant,
 }
 
-#[derive(Clone, Debug)]
-struct ResolvedModel {
-    request_model: String,
-    backend_model: String,
-    reasoning_effort: Option<String>,
+/// Reads an upstream SSE body one event at a time. Chunks are decoded per
+/// complete line so a character split across network reads is never corrupted.
+struct UpstreamEvents {
+    body: UpstreamBody,
+    decoder: LineDecoder,
+    lines: std::collections::VecDeque<String>,
+    idle_timeout: Option<std::time::Duration>,
+    trace: bool,
+    max_events: usize,
+    // Kept until the first event arrives, in case the body is plain JSON
+    raw_body: Vec<u8>,
+    saw_event: bool,
+    events: usize,
+    truncated: bool,
+    done: bool,
 }
 
-impl ProxyServer {
-    async fn new(auth_path: &str) -> Result<Self> {
-        let auth_path = if auth_path.starts_with("~/") {
-            let home = std::env::var("HOME").context("HOME environment variable not set")?;
-            auth_path.replace("~", &home)
-        } else {
-            auth_path.to_string()
-        };
-
-        let auth_content = tokio::fs::read_to_string(&auth_path)
-            .await
-            .context("Failed to read auth.json")?;
-
-        let auth_data: AuthData =
-            serde_json::from_str(&auth_content).context("Failed to parse auth.json")?;
-
-        // Create client with browser-like configuration
-        let client = Client::builder()
-            .user_agent("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
-            .build()
-            .context("Failed to create HTTP client")?;
-
-        let allowed_models = load_allowed_models();
-        if allowed_models.is_empty() {
-            return Err(anyhow!(
-                "No allowed models configured. Set ALLOWED_MODELS or use defaults."
-            ));
+impl UpstreamEvents {
+    fn new(
+        body: UpstreamBody,
+        idle_timeout: Option<std::time::Duration>,
+        trace: bool,
+        max_events: usize,
+    ) -> Self {
+        Self {
+            body,
+            decoder: LineDecoder::new(),
+            lines: std::collections::VecDeque::new(),
+            idle_timeout,
+            trace,
+            max_events,
+            raw_body: Vec::new(),
+            saw_event: false,
+            events: 0,
+            truncated: false,
+            done: false,
         }
-
-        Ok(Self {
-            client,
-            auth_data,
-            allowed_models,
-        })
-    }
-
-    fn allowed_models(&self) -> &[String] {
-        &self.allowed_models
     }
 
-    fn allowed_request_models(&self) -> Vec<String> {
-        let mut models = self.allowed_models.clone();
-        for base_model in &self.allowed_models {
-            for suffix in REASONING_CANONICAL_SUFFIXES {
-                models.push(format!("{base_model}{suffix}"));
+    /// The next event, or `None` once the body ends, `[DONE]` arrives or the
+    /// event limit cuts the stream off.
+    async fn next(&mut self) -> Result<Option<Value>, ProxyError> {
+        loop {
+            while let Some(line) = self.lines.pop_front() {
+                let parsed = sse::parse_line(&line);
+                if self.trace && !line.is_empty() {
+                    println!("   [sse] {}", sse::describe_line(&parsed, line.len()));
+                }
+                match parsed {
+                    SseLine::Event(event) => {
+                        if !self.saw_event {
+                            self.saw_event = true;
+                            self.raw_body = Vec::new();
+                        }
+                        self.events += 1;
+                        // A runaway stream is cut off rather than read forever
+                        if self.max_events > 0 && self.events > self.max_events {
+                            eprintln!(
+                                "⚠️  Upstream sent more than {} SSE events; truncating the response",
+                                self.max_events
+                            );
+                            self.truncated = true;
+                            self.stop();
+                            return Ok(None);
+                        }
+                        return Ok(Some(event));
+                    }
+                    SseLine::Done => {
+                        self.stop();
+                        return Ok(None);
+                    }
+                    // Pings never reach the accumulator, so they cannot split or delay deltas
+                    SseLine::KeepAlive | SseLine::Ignored => {}
+                }
+            }
+            if self.done {
+                return Ok(None);
+            }
+            match with_idle_timeout(self.idle_timeout, self.body.chunk()).await?? {
+                Some(chunk) => {
+                    if !self.saw_event {
+                        self.raw_body.extend_from_slice(&chunk);
+                    }
+                    self.lines.extend(self.decoder.push(&chunk));
+                }
+                None => {
+                    self.done = true;
+                    self.lines
+                        .extend(std::mem::take(&mut self.decoder).finish());
+                }
             }
         }
+    }
 
-        let mut seen = HashSet::new();
-        models
-            .into_iter()
-            .filter(|model| seen.insert(model.clone()))
-            .collect()
+    fn stop(&mut self) {
+        self.done = true;
+        self.lines.clear();
     }
 
-    fn resolve_model(&self, model: &str) -> Option<ResolvedModel> {
-        if self.allowed_models.iter().any(|allowed| allowed == model) {
-            return Some(ResolvedModel {
-                request_model: model.to_string(),
-                backend_model: model.to_string(),
-                reasoning_effort: None,
-            });
+    /// Once the body has ended without a single event: the JSON object the
+    /// backend answered with instead, if it is one. An `error` object fails.
+    fn take_object(&mut self) -> Result<Option<Value>, ProxyError> {
+        if self.saw_event {
+            return Ok(None);
         }
-
-        for (suffix, effort) in REASONING_SUFFIX_ALIASES {
-            if let Some(base_model) = model.strip_suffix(suffix) {
-                if self
-                    .allowed_models
-                    .iter()
-                    .any(|allowed| allowed == base_model)
-                {
-                    return Some(ResolvedModel {
-                        request_model: model.to_string(),
-                        backend_model: base_model.to_string(),
-                        reasoning_effort: Some(effort.to_string()),
-                    });
-                }
-            }
+        let Ok(body) = serde_json::from_slice::<Value>(&std::mem::take(&mut self.raw_body)) else {
+            return Ok(None);
+        };
+        println!("   Upstream sent a JSON object instead of SSE; parsing it whole");
+        if let Some(error) = body.get("error").filter(|e| !e.is_null()) {
+            return Err(ProxyError::Upstream {
+                status: warp::http::StatusCode::BAD_GATEWAY.as_u16(),
+                body: error.to_string(),
+            });
         }
-
-        None
+        Ok(Some(body))
     }
 
-    fn models_response(&self) -> Value {
-        build_models_response(&self.allowed_request_models())
+    fn truncated(&self) -> bool {
+        self.truncated
     }
+}
 
-    fn convert_chat_to_responses(
-        &self,
-        chat_req: ChatCompletionsRequest,
-        resolved_model: &ResolvedModel,
-    ) -> ResponsesApiRequest {
-        // Convert messages to ResponseItems
-        let mut input = Vec::new();
-
-        for msg in chat_req.messages {
-            // Convert content to string (handle both string and array formats)
-            let content_text = match &msg.content {
-                Value::String(s) => s.clone(),
-                Value::Array(arr) => {
-                    // Extract text from array elements
-                    arr.iter()
-                        .filter_map(|v| {
-                            if let Some(obj) = v.as_object() {
-                                obj.get("text")
-                                    .and_then(|t| t.as_str())
-                                    .map(|s| s.to_string())
-                            } else {
-                                v.as_str().map(|s| s.to_string())
-                            }
-                        })
-                        .collect::<Vec<String>>()
-                        .join(" ")
-                }
-                _ => msg.content.to_string(),
-            };
+/// Random-length filler for the `obfuscation` chunk field, which keeps chunk
+/// sizes from revealing token lengths.
+fn obfuscation_padding(ids: &IdSource) -> String {
+    let id = ids.uuid();
+    let len = 1 + usize::from(id.as_bytes()[0] % 16);
+    id.simple().to_string()[..len].to_string()
+}
 
-            input.push(ResponseItem::Message {
-                id: None,
-                role: msg.role,
-                content: vec![ContentItem::InputText { text: content_text }],
-            });
-        }
+/// Writes the `chat.completion.chunk` SSE frames of one completion: the
+/// fields every chunk repeats, plus what `stream_options` adds to them.
+struct ChunkFrames {
+    id: String,
+    object: String,
+    created: i64,
+    model: String,
+    service_tier: String,
+    include_usage: bool,
+    include_obfuscation: bool,
+    /// Add `logprobs: null` to every choice
+    logprobs: bool,
+    ids: Arc<IdSource>,
+}
 
-        // Use proper instructions for ChatGPT Responses API
-        let instructions = "You are a helpful AI assistant. Provide clear, accurate, and concise responses to user questions and requests.".to_string();
+impl ChunkFrames {
+    fn frame(&self, choices: Value, usage: Option<Value>) -> String {
+        let usage = match usage {
+            Some(usage) => format!(",\"usage\":{}", usage),
+            None if self.include_usage => ",\"usage\":null".to_string(),
+            None => String::new(),
+        };
+        let obfuscation = if self.include_obfuscation {
+            format!(",\"obfuscation\":{}", json!(obfuscation_padding(&self.ids)))
+        } else {
+            String::new()
+        };
+        format!(
+            "data: {{\"id\":{},\"object\":{},\"created\":{},\"model\":{},\"service_tier\":{},\"choices\":{}{}{}}}\n\n",
+            json!(self.id),
+            json!(self.object),
+            self.created,
+            json!(self.model),
+            json!(self.service_tier),
+            choices,
+            usage,
+            obfuscation
+        )
+    }
 
-        ResponsesApiRequest {
-            model: resolved_model.backend_model.clone(),
-            instructions,
-            input,
-            tools: chat_req.tools.unwrap_or_default(),
-            tool_choice: "auto".to_string(),
-            parallel_tool_calls: false,
-            reasoning: resolved_model
-                .reasoning_effort
-                .as_ref()
-                .map(|effort| json!({ "effort": effort })),
-            store: false,
-            stream: true,
-            include: vec![],
+    /// One choice's delta, or its final chunk when `finish_reason` is set.
+    fn chunk(&self, index: i32, delta: Value, finish_reason: Option<&str>) -> String {
+        let mut choice = json!({ "index": index, "delta": delta, "finish_reason": finish_reason });
+        if self.logprobs {
+            choice["logprobs"] = Value::Null;
         }
+        self.frame(json!([choice]), None)
     }
 
-    async fn proxy_request(
-        &self,
-        chat_req: ChatCompletionsRequest,
-        resolved_model: ResolvedModel,
-    ) -> Result<ChatCompletionsResponse> {
-        println!("🔄 Processing proxy request...");
-        self.proxy_request_original(chat_req, resolved_model).await
+    /// The usage-only chunk sent after every choice has finished.
+    fn usage(&self, usage: &Option<Usage>) -> String {
+        self.frame(json!([]), Some(json!(usage)))
     }
+}
 
-    async fn proxy_request_original(
-        &self,
-        chat_req: ChatCompletionsRequest,
-        resolved_model: ResolvedModel,
-    ) -> Result<ChatCompletionsResponse> {
-        // Convert to Responses API format
-        let responses_req = self.convert_chat_to_responses(chat_req, &resolved_model);
+/// Replay a buffered completion as `chat.completion.chunk` SSE frames (the
+/// `object` is `chunk_object`, normally that string). Each choice
+/// streams under its own `index` and ends with its own `finish_reason` chunk;
+/// `[DONE]` follows once every choice has finished.
+fn build_stream_chunks(
+    response: &ChatCompletionsResponse,
+    chunk_object: &str,
+    reasoning_field: &str,
+    (prefix, suffix): (&str, &str),
+    options: StreamOptions,
+    ids: Arc<IdSource>,
+) -> Vec<String> {
+    let frames = ChunkFrames {
+        id: response.id.clone(),
+        object: chunk_object.to_string(),
+        created: response.created,
+        model: response.model.clone(),
+        service_tier: response.service_tier.clone(),
+        include_usage: options.include_usage.unwrap_or(false),
+        include_obfuscation: options.include_obfuscation.unwrap_or(false),
+        // Chunks carry `logprobs: null` when the response's choices do
+        logprobs: response
+            .choices
+            .iter()
+            .any(|choice| choice.logprobs.is_some()),
+        ids,
+    };
+    let chunk = |index: i32, delta: Value, finish_reason: Option<&str>| {
+        frames.chunk(index, delta, finish_reason)
+    };
 
-        // Build request to ChatGPT backend with browser-like headers
-        let mut request_builder = self
-            .client
-            .post("https://chatgpt.com/backend-api/codex/responses")
-            .header("Content-Type", "application/json")
-            .header("Accept", "text/event-stream")
-            .header("Accept-Language", "en-US,en;q=0.9")
-            .header("Accept-Encoding", "gzip, deflate, br")
-            .header("Referer", "https://chatgpt.com/")
-            .header("Origin", "https://chatgpt.com")
-            .header("Sec-Fetch-Dest", "empty")
-            .header("Sec-Fetch-Mode", "cors")
-            .header("Sec-Fetch-Site", "same-origin")
-            .header("Cache-Control", "no-cache")
-            .header("Pragma", "no-cache")
-            .header("DNT", "1")
-            .header("OpenAI-Beta", "responses=experimental")
-            .header("originator", "codex_cli_rs");
+    let mut sse_chunks = Vec::new();
+    for choice in &response.choices {
+        let message = &choice.message;
+        // Built from the finished response, so the role chunk never goes out
+        // before the backend has answered
+        sse_chunks.push(chunk(choice.index, json!({ "role": message.role }), None));
 
-        // Add authentication
-        if let Some(tokens) = &self.auth_data.tokens {
-            request_builder =
-                request_builder.header("Authorization", format!("Bearer {}", tokens.access_token));
-            request_builder = request_builder.header("chatgpt-account-id", &tokens.account_id);
-        } else if let Some(api_key) = &self.auth_data.api_key {
-            request_builder =
-                request_builder.header("Authorization", format!("Bearer {}", api_key));
+        // Reasoning goes in its own chunks ahead of the answer so clients
+        // can route it separately from the visible content.
+        if let Some(reasoning) = &message.reasoning {
+            sse_chunks.push(chunk(
+                choice.index,
+                json!({ reasoning_field: reasoning }),
+                None,
+            ));
         }
 
-        // Add session ID
-        let session_id = Uuid::new_v4();
-        request_builder = request_builder.header("session_id", session_id.to_string());
+        if let Some(content) = &message.content {
+            // The configured prefix and suffix travel as chunks of their own
+            if !prefix.is_empty() {
+                sse_chunks.push(chunk(choice.index, json!({ "content": prefix }), None));
+            }
+            sse_chunks.push(chunk(choice.index, json!({ "content": content }), None));
+            if !suffix.is_empty() {
+                sse_chunks.push(chunk(choice.index, json!({ "content": suffix }), None));
+            }
+        }
 
-        // Send request
-        let response = request_builder
-            .json(&responses_req)
-            .send()
-            .await
-            .context("Failed to send request to ChatGPT backend")?;
+        // Refusals stream under `delta.refusal`, never as content, and finish
+        // with "stop" as OpenAI's do
+        if let Some(refusal) = &message.refusal {
+            sse_chunks.push(chunk(choice.index, json!({ "refusal": refusal }), None));
+        }
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!(
-                "ChatGPT backend returned {} with body: {}",
-                status,
-                body
+        // Tool-call-only responses stream just the calls, with no content chunk.
+        // As with OpenAI, a call's first chunk carries its id, type and name and
+        // later chunks only its `index` and argument text.
+        for (index, call) in message.tool_calls.iter().flatten().enumerate() {
+            sse_chunks.push(chunk(
+                choice.index,
+                json!({ "tool_calls": [{
+                    "index": index,
+                    "id": call.id,
+                    "type": call.call_type,
+                    "function": { "name": call.function.name, "arguments": "" }
+                }] }),
+                None,
             ));
+            if !call.function.arguments.is_empty() {
+                sse_chunks.push(chunk(
+                    choice.index,
+                    json!({ "tool_calls": [{
+                        "index": index,
+                        "function": { "arguments": call.function.arguments }
+                    }] }),
+                    None,
+                ));
+            }
         }
 
-        // Handle streaming response
-        let mut response_content = String::new();
-        let mut fallback_output_text = String::new();
-        let mut saw_delta = false;
-        let response_text = response.text().await?;
-        let lines: Vec<&str> = response_text.lines().collect();
-
-        for line in lines {
-            if line.starts_with("data: ") {
-                let json_data = &line[6..]; // Remove "data: " prefix
-                if json_data == "[DONE]" {
-                    break;
-                }
-
-                if let Ok(event) = serde_json::from_str::<serde_json::Value>(json_data) {
-                    if let Some(event_type) = event.get("type").and_then(|v| v.as_str()) {
-                        match event_type {
-                            "response.output_text.delta" => {
-                                if let Some(delta) = event.get("delta").and_then(|v| v.as_str()) {
-                                    saw_delta = true;
-                                    response_content.push_str(delta);
-                                }
-                            }
-                            "response.output_item.done" => {
-                                if let Some(item) = event.get("item") {
-                                    if let Some(content_arr) =
-                                        item.get("content").and_then(|v| v.as_array())
-                                    {
-                                        for content_item in content_arr {
-                                            if let Some(text) =
-                                                content_item.get("text").and_then(|v| v.as_str())
-                                            {
-                                                fallback_output_text.push_str(text);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {} // Ignore other event types
-                        }
-                    }
-                }
+        if let Some(call) = &message.function_call {
+            sse_chunks.push(chunk(
+                choice.index,
+                json!({ "function_call": { "name": call.name, "arguments": "" } }),
+                None,
+            ));
+            if !call.arguments.is_empty() {
+                sse_chunks.push(chunk(
+                    choice.index,
+                    json!({ "function_call": { "arguments": call.arguments } }),
+                    None,
+                ));
             }
         }
 
-        if !saw_delta && !fallback_output_text.is_empty() {
-            response_content = fallback_output_text;
-        }
+        sse_chunks.push(chunk(
+            choice.index,
+            json!({}),
+            Some(choice.finish_reason.as_deref().unwrap_or("stop")),
+        ));
+    }
+    // Usage for the whole request comes after every choice has finished
+    if frames.include_usage {
+        sse_chunks.push(frames.usage(&response.usage));
+    }
+    sse_chunks.push("data: [DONE]\n\n".to_string());
+    sse_chunks
+}
 
-        // If no content was collected, surface an explicit error instead of faking output.
-        if response_content.is_empty() {
-            return Err(anyhow!(
-                "ChatGPT backend returned success but no assistant content could be extracted"
-            ));
-        }
+// Characters of an account id left readable in logs
+const ACCOUNT_ID_VISIBLE_CHARS: usize = 4;
 
-        // Create Chat Completions response
-        let chat_res = ChatCompletionsResponse {
-            id: format!("chatcmpl-{}", Uuid::new_v4()),
-            object: "chat.completion".to_string(),
-            created: chrono::Utc::now().timestamp(),
-            model: resolved_model.request_model,
-            choices: vec![Choice {
-                index: 0,
-                message: ChatResponseMessage {
-                    role: "assistant".to_string(),
-                    content: response_content,
-                },
-                finish_reason: Some("stop".to_string()),
-            }],
-            usage: Some(Usage {
-                prompt_tokens: 0,
-                completion_tokens: 0,
-                total_tokens: 0,
-            }),
-        };
+/// An account id for logs: masked except for its last few characters, so
+/// entries can still be told apart.
+fn mask_account_id(id: &str) -> String {
+    let count = id.chars().count();
+    let hidden = count
+        .saturating_sub(ACCOUNT_ID_VISIBLE_CHARS)
+        .max(count / 2);
+    let visible: String = id.chars().skip(hidden).collect();
+    format!("{}{}", "*".repeat(hidden), visible)
+}
 
-        Ok(chat_res)
+/// Whether a header or JSON key carries a ChatGPT account id
+/// (`chatgpt-account-id`, `account_id`, `accountId`, ...).
+fn is_account_id_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase().replace(['-', '_'], "");
+    key.ends_with("accountid")
+}
+
+/// A JSON body with every account id value masked. A body without any, or
+/// that is not JSON, is returned unchanged.
+fn redact_account_ids(body: &str) -> String {
+    // Whether anything was masked; every match is visited, so no short-circuiting
+    fn redact(value: &mut Value) -> bool {
+        let mut found = false;
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    match value {
+                        Value::String(id) if is_account_id_key(key) => {
+                            *id = mask_account_id(id);
+                            found = true;
+                        }
+                        _ => found |= redact(value),
+                    }
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    found |= redact(item);
+                }
+            }
+            _ => {}
+        }
+        found
+    }
+    let Ok(mut value) = serde_json::from_str::<Value>(body) else {
+        return body.to_string();
+    };
+    match redact(&mut value) {
+        true => value.to_string(),
+        false => body.to_string(),
     }
 }
 
 // Enhanced logging function
-fn log_request(method: &warp::http::Method, path: &str, headers: &warp::http::HeaderMap) {
+fn log_request(
+    method: &warp::http::Method,
+    path: &str,
+    headers: &warp::http::HeaderMap,
+    redact: bool,
+) {
     let timestamp = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S%.3f UTC");
 
     println!("\n🔍 === INTERCEPTED REQUEST ===");
@@ -574,13 +4188,12 @@ fn log_request(method: &warp::http::Method, path: &str, headers: &warp::http::He
     println!("\n📋 Headers ({} total):", headers.len());
     for (name, value) in headers.iter() {
         let header_name = name.as_str().to_lowercase();
-        let value_str = match value.to_str() {
-            Ok(v) => v,
-            Err(_) => "[INVALID UTF-8]",
-        };
+        let value_str = value.to_str().unwrap_or("[INVALID UTF-8]");
 
         // Highlight potential CLINE-specific headers
-        if header_name.contains("user-agent")
+        if redact && is_account_id_key(&header_name) {
+            println!("  🔐 {}: {}", name, mask_account_id(value_str));
+        } else if header_name.contains("user-agent")
             || header_name.contains("client")
             || header_name.contains("cline")
         {
@@ -616,14 +4229,125 @@ fn log_request(method: &warp::http::Method, path: &str, headers: &warp::http::He
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    // .env has to be applied before clap and env_logger read the environment,
+    // so the opt-out flag is checked on the raw arguments.
+    let dotenv_loaded = if std::env::args().any(|arg| arg == "--no-dotenv") {
+        None
+    } else {
+        match dotenvy::dotenv() {
+            Ok(path) => Some(Some(path)),
+            Err(e) if e.not_found() => Some(None),
+            Err(e) => return Err(e).context("Failed to load .env"),
+        }
+    };
+
+    env_logger::Builder::from_default_env()
+        .target(env_logger::Target::Pipe(Box::new(logging::LogWriter)))
+        .init();
     let args = Args::parse();
+    let args = match &args.config {
+        // Reparse with the file's options ahead of the real ones so flags still win
+        Some(path) => {
+            let config_args = profiles::config_args(path, args.profile.as_deref())?;
+            let mut argv = std::env::args_os();
+            let program = argv.next().unwrap_or_default();
+            Args::parse_from(
+                std::iter::once(program)
+                    .chain(config_args.into_iter().map(Into::into))
+                    .chain(argv),
+            )
+        }
+        None if args.profile.is_some() => {
+            return Err(anyhow!(
+                "--profile needs a config file; pass --config or set PROXY_CONFIG"
+            ));
+        }
+        None => args,
+    };
+    logging::init(
+        args.log_file.as_deref(),
+        args.log_max_size_mb.max(1) * 1024 * 1024,
+        args.log_keep,
+        if args.no_console_log {
+            logging::Console::Off
+        } else if args.quiet {
+            logging::Console::Quiet
+        } else {
+            logging::Console::All
+        },
+    )?;
+    if !(0.0..1.0).contains(&args.token_refresh_at) {
+        return Err(anyhow!("--token-refresh-at must be at least 0 and below 1"));
+    }
+    if args.stream_channel_capacity == 0 {
+        return Err(anyhow!("--stream-channel-capacity must be at least 1"));
+    }
 
     println!("Initializing Codex OpenAI Proxy...");
+    match dotenv_loaded {
+        Some(Some(path)) => println!("✓ Loaded settings from {}", path.display()),
+        Some(None) => println!("✓ No .env file found, using environment only"),
+        None => println!("✓ Skipped .env loading (--no-dotenv)"),
+    }
+    if let Some(path) = &args.config {
+        println!(
+            "✓ Applied config {} (profile: {})",
+            path.display(),
+            args.profile.as_deref().unwrap_or("default only")
+        );
+    }
 
-    let proxy = ProxyServer::new(&args.auth_path).await?;
-    println!("✓ Loaded authentication from {}", args.auth_path);
+    let proxy = ProxyServer::new(&args).await?;
+    for account in proxy.accounts.accounts() {
+        println!(
+            "✓ Loaded authentication from {} (weight {})",
+            account.label, account.weight
+        );
+    }
+    if let Some(account_id) = &proxy.account_id {
+        let account_id = match proxy.log_redaction {
+            true => mask_account_id(account_id),
+            false => account_id.clone(),
+        };
+        println!(
+            "✓ Sending chatgpt-account-id {} for every account (--account-id)",
+            account_id
+        );
+    }
+    if let Some(seed) = args.id_seed {
+        println!(
+            "🧪 Ids are generated from seed {} (--id-seed); output is reproducible",
+            seed
+        );
+    }
+    if args.instructions.is_some() {
+        println!(
+            "✓ Fallback instructions set ({} chars)",
+            proxy.fallback_instructions.len()
+        );
+    }
+    if proxy.forward_auth {
+        println!("✓ Forwarding each client's Authorization header upstream (no auth.json loaded)");
+    }
     println!("✓ Allowed models: {}", proxy.allowed_models().join(", "));
+    if args.mock_upstream {
+        println!("🧪 Mock upstream: completions are canned and the backend is never called");
+    }
+    if let Some(path) = &args.replay {
+        return replay::run(path, proxy).await;
+    }
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let refresh_tasks = if args.token_refresh_at > 0.0 {
+        refresh::spawn_scheduler(
+            proxy.client.clone(),
+            proxy.accounts.clone(),
+            args.token_refresh_at,
+            shutdown_rx,
+        )
+    } else {
+        Vec::new()
+    };
 
     // Multiple endpoints for CLINE compatibility
     let allowed_models_display = proxy.allowed_models().join(", ");
@@ -652,18 +4376,46 @@ async fn main() -> Result<()> {
     let universal_handler = warp::any()
         .and(warp::method())
         .and(warp::path::full())
+        .and(raw_query())
         .and(warp::header::headers_cloned())
         .and(warp::body::bytes())
         .and(proxy_filter.clone())
         .and_then(universal_request_handler);
 
-    let routes = universal_handler.with(cors).with(warp::log("codex_proxy"));
+    let routes = universal_handler
+        .with(cors)
+        .with(warp::reply::with::header("server", SERVER_HEADER));
+
+    // Bound here rather than by warp, which panics when the port is taken
+    let listener = match tokio::net::TcpListener::bind(("0.0.0.0", args.port)).await {
+        Ok(listener) => listener,
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            eprintln!(
+                "❌ Port {} is already in use. Stop whatever is listening there, or choose another port with --port (or PORT).",
+                args.port
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("❌ Cannot listen on port {}: {}", args.port, e);
+            std::process::exit(1);
+        }
+    };
+    let incoming = futures_util::stream::unfold(listener, |listener| async move {
+        let connection = listener.accept().await.map(|(stream, _)| stream);
+        Some((connection, listener))
+    });
 
     println!(
         "🚀 Codex OpenAI Proxy listening on http://0.0.0.0:{}",
         args.port
     );
     println!("   Health check: http://localhost:{}/health", args.port);
+    println!(
+        "   Version: {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        env!("BUILD_GIT_HASH")
+    );
     println!(
         "   Chat endpoint: http://localhost:{}/v1/chat/completions",
         args.port
@@ -673,38 +4425,251 @@ async fn main() -> Result<()> {
     println!("   Allowed Models: {}", allowed_models_display);
     println!("   API Key: (any value)");
 
-    warp::serve(routes).run(([0, 0, 0, 0], args.port)).await;
+    warp::serve(routes)
+        .serve_incoming_with_graceful_shutdown(incoming, async {
+            let _ = tokio::signal::ctrl_c().await;
+            println!("👋 Shutting down");
+        })
+        .await;
+
+    // Let any refresh that already started finish writing its tokens
+    let _ = shutdown_tx.send(true);
+    for task in refresh_tasks {
+        let _ = task.await;
+    }
 
     Ok(())
 }
 
+/// The raw query string, empty when the URL has none.
+fn raw_query() -> impl Filter<Extract = (String,), Error = std::convert::Infallible> + Clone {
+    warp::query::raw().or(warp::any().map(String::new)).unify()
+}
+
 // Universal handler that routes based on path and method
 async fn universal_request_handler(
     method: warp::http::Method,
     path: warp::path::FullPath,
+    query: String,
     headers: warp::http::HeaderMap,
     body: bytes::Bytes,
     proxy: ProxyServer,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    let started = std::time::Instant::now();
+    let method_str = method.to_string();
+    let path_str = path.as_str().to_string();
+    let bytes_in = body.len();
+    let log_format = proxy.log_format;
+    let errors_as_200 = proxy.errors_as_200;
+    let error_messages = proxy.error_messages.clone();
+    let recent = proxy.recent.clone();
+    // Quick routes get short limits so a stuck call fails fast instead of
+    // waiting as long as a generation may take. Streaming completions are
+    // bounded by --stream-idle-timeout instead.
+    let route_timeout = proxy.route_timeout(path.as_str()).filter(|_| {
+        !(path.as_str().ends_with("/chat/completions")
+            && requests_streaming(&body)
+            && proxy.streams_despite_accept(&headers))
+    });
+    let mut response = match route_timeout {
+        Some(limit) => {
+            match tokio::time::timeout(
+                limit,
+                route_request(method, path, query, headers, body, proxy),
+            )
+            .await
+            {
+                Ok(response) => response?,
+                Err(_) => {
+                    eprintln!("⏱️  {} timed out after {}s", path_str, limit.as_secs());
+                    proxy_error_response(&ProxyError::Timeout, &error_messages)
+                }
+            }
+        }
+        None => route_request(method, path, query, headers, body, proxy).await?,
+    };
+
+    // Compatibility mode for clients that drop the body of any non-2xx response
+    let is_json_error = !response.status().is_success()
+        && response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/json"));
+    if errors_as_200 && is_json_error {
+        println!(
+            "↩️  --errors-as-200: sending 200 instead of {}",
+            response.status()
+        );
+        *response.status_mut() = warp::http::StatusCode::OK;
+    }
+
+    let record = AccessRecord {
+        method: &method_str,
+        path: &path_str,
+        status: response.status().as_u16(),
+        bytes_in,
+        bytes_out: warp::hyper::body::HttpBody::size_hint(response.body()).exact(),
+        latency: started.elapsed(),
+        completion: response.extensions().get::<CompletionInfo>(),
+    };
+    logging::write_record(format_args!("{}", record.render(log_format)));
+    // Polling the list should not push real traffic out of it
+    if !path_str.starts_with("/admin/") {
+        recent.record(&record);
+    }
+
+    Ok(response)
+}
+
+async fn route_request(
+    method: warp::http::Method,
+    path: warp::path::FullPath,
+    query: String,
+    headers: warp::http::HeaderMap,
+    body: bytes::Bytes,
+    proxy: ProxyServer,
+) -> Result<warp::http::Response<warp::hyper::Body>, warp::Rejection> {
     let path_str = path.as_str();
 
-    log_request(&method, path_str, &headers);
+    Metrics::inc(&proxy.metrics.requests_total);
+
+    let looped = headers
+        .get(LOOP_GUARD_HEADER)
+        .is_some_and(|id| id.as_bytes() == proxy.instance_id.as_bytes());
+    if looped {
+        eprintln!(
+            "🔁 {} {} came from this proxy; the upstream URL points back at it",
+            method, path_str
+        );
+        return Ok(proxy_error_response(
+            &ProxyError::LoopDetected,
+            &proxy.error_messages,
+        ));
+    }
+    if proxy.unmatched_log == UnmatchedLog::All
+        || KNOWN_PATHS.contains(&path_str)
+        || stored_response_id(path_str).is_some()
+    {
+        log_request(&method, path_str, &headers, proxy.log_redaction);
+    }
 
     match (method.as_str(), path_str) {
         ("GET", "/health") => {
             println!("💚 Health check requested");
-            Ok(warp::reply::json(&json!({
+            let mut health = json!({
                 "status": "ok",
-                "service": "codex-openai-proxy"
+                "service": "codex-openai-proxy",
+                "queue_depth": proxy.limiter.queue_depth()
+            });
+            if let (Some(health), Value::Object(tokens)) =
+                (health.as_object_mut(), proxy.accounts.token_health())
+            {
+                health.extend(tokens);
+            }
+            let deep = query
+                .split('&')
+                .any(|pair| matches!(pair, "deep=1" | "deep=true"));
+            if deep {
+                // Account ids and scopes are sensitive, so only admins see them
+                if proxy.admin_rejection(&headers).is_none() {
+                    health["auth"] = proxy.accounts.token_diagnostics();
+                }
+                if let (Some(health), Value::Object(probe)) =
+                    (health.as_object_mut(), proxy.probe_upstream().await)
+                {
+                    health.extend(probe);
+                }
+            }
+            // Load balancers read the status code, so a failing upstream fails the check
+            let status = match health["upstream"].as_str() {
+                Some("unreachable" | "degraded") => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                _ => warp::http::StatusCode::OK,
+            };
+            Ok(warp::reply::with_status(warp::reply::json(&health), status).into_response())
+        }
+        ("GET", "/version") => Ok(warp::reply::json(&build_version_response()).into_response()),
+        ("GET", "/metrics") => {
+            let reply = warp::reply::with_header(
+                format!(
+                    "{}{}{}{}",
+                    proxy.metrics.render(),
+                    proxy.accounts.render_metrics(),
+                    proxy.limiter.render_metrics(),
+                    proxy.throughput.render_metrics()
+                ),
+                "content-type",
+                "text/plain; version=0.0.4",
+            );
+            Ok(reply.into_response())
+        }
+        ("GET", "/admin/requests") => {
+            if let Some(rejection) = proxy.admin_rejection(&headers) {
+                return Ok(rejection);
+            }
+            Ok(warp::reply::json(&json!({
+                "object": "list",
+                "capacity": proxy.recent.capacity(),
+                "data": proxy.recent.snapshot()
             }))
             .into_response())
         }
+        ("GET", "/admin/cache") | ("DELETE", "/admin/cache") => {
+            if let Some(rejection) = proxy.admin_rejection(&headers) {
+                return Ok(rejection);
+            }
+            // The proxy keeps no response cache, so there is nothing to report or flush
+            Ok(json_response(
+                warp::http::StatusCode::NOT_FOUND,
+                &build_cache_disabled_response(),
+            ))
+        }
         ("GET", "/models") | ("GET", "/v1/models") => {
             println!("📋 === MATCHED MODELS REQUEST ===");
             println!("📋 === END MATCHED ===\n");
 
-            let models_response = proxy.models_response();
-            Ok(warp::reply::json(&models_response).into_response())
+            if prefers_plain_text(&headers) {
+                let reply = warp::reply::with_header(
+                    models_plain_text(proxy.models_response()),
+                    "content-type",
+                    "text/plain; charset=utf-8",
+                );
+                return Ok(reply.into_response());
+            }
+            Ok(warp::reply::json(proxy.models_response()).into_response())
+        }
+        ("GET", _) if stored_response_id(path_str).is_some() => {
+            let id = stored_response_id(path_str).unwrap_or_default();
+            println!("📦 Fetching stored response {}", id);
+            // Retrieval knows no model or tools, so it goes where a plain request would
+            let Some(route) = proxy.forwarded_route(proxy.router.select(&[], false), &headers)
+            else {
+                return Ok(json_response(
+                    warp::http::StatusCode::UNAUTHORIZED,
+                    &build_missing_client_auth_response(),
+                ));
+            };
+            match proxy.fetch_stored_response(id, &route).await {
+                Ok(response) => {
+                    let reply = warp::reply::json(&response);
+                    let reply = warp::reply::with_header(reply, "access-control-allow-origin", "*");
+                    Ok(with_upstream_headers(reply.into_response(), &response))
+                }
+                Err(e) => {
+                    eprintln!("❌ Fetching stored response {} failed: {}", id, e);
+                    Ok(proxy_error_response(&e, &proxy.error_messages))
+                }
+            }
+        }
+        ("POST", "/chat/completions/validate") | ("POST", "/v1/chat/completions/validate") => {
+            // Pre-flight only: the same checks as a completion, and no upstream call
+            match proxy.validate_chat_request(&body, &headers) {
+                Ok(_) => Ok(json_response(
+                    warp::http::StatusCode::OK,
+                    &json!({ "valid": true }),
+                )),
+                Err(error) => Ok(json_response(warp::http::StatusCode::BAD_REQUEST, &error)),
+            }
         }
         ("POST", "/chat/completions") | ("POST", "/v1/chat/completions") => {
             println!("🔥 === MATCHED CHAT COMPLETIONS ===");
@@ -725,6 +4690,8 @@ async fn universal_request_handler(
                             name,
                             &value_str[..std::cmp::min(20, value_str.len())]
                         );
+                    } else if proxy.log_redaction && is_account_id_key(name.as_str()) {
+                        println!("  -H \"{}: {}\"", name, mask_account_id(value_str));
                     } else if name.as_str().to_lowercase().starts_with("x-forwarded") {
                         println!("  # Skip: -H \"{}: {}\"", name, value_str);
                     } else {
@@ -736,6 +4703,13 @@ async fn universal_request_handler(
             // Log body (truncated for readability)
             println!("\nBody (first 1000 chars):");
             if let Ok(body_str) = std::str::from_utf8(&body) {
+                let redacted;
+                let body_str = if proxy.log_redaction {
+                    redacted = redact_account_ids(body_str);
+                    redacted.as_str()
+                } else {
+                    body_str
+                };
                 let truncated = if body_str.len() > 1000 {
                     format!("{}... [TRUNCATED]", &body_str[..1000])
                 } else {
@@ -753,6 +4727,8 @@ async fn universal_request_handler(
                         {
                             if name.as_str().to_lowercase() == "authorization" {
                                 println!("  -H \"{}: test-key\" \\", name);
+                            } else if proxy.log_redaction && is_account_id_key(name.as_str()) {
+                                println!("  -H \"{}: {}\" \\", name, mask_account_id(value_str));
                             } else {
                                 println!("  -H \"{}: {}\" \\", name, value_str);
                             }
@@ -763,28 +4739,38 @@ async fn universal_request_handler(
             }
             println!("📋 === END CLINE REQUEST DETAILS ===\n");
 
-            // Parse JSON from bytes
-            let chat_req: ChatCompletionsRequest = match serde_json::from_slice(&body) {
-                Ok(req) => req,
-                Err(e) => {
-                    println!("❌ JSON parse error: {}", e);
-                    return Ok(json_response(
-                        warp::http::StatusCode::BAD_REQUEST,
-                        &build_invalid_json_response(&e.to_string()),
-                    ));
-                }
-            };
+            let (mut chat_req, mut resolved_model) =
+                match proxy.validate_chat_request(&body, &headers) {
+                    Ok(validated) => validated,
+                    Err(error) => {
+                        return Ok(json_response(warp::http::StatusCode::BAD_REQUEST, &error));
+                    }
+                };
 
-            let resolved_model = match proxy.resolve_model(&chat_req.model) {
-                Some(model) => model,
-                None => {
-                    let allowed_request_models = proxy.allowed_request_models();
-                    return Ok(json_response(
-                        warp::http::StatusCode::BAD_REQUEST,
-                        &build_model_not_allowed_response(&chat_req.model, &allowed_request_models),
-                    ));
+            chat_req.messages = transforms::apply_all(&proxy.transforms, chat_req.messages).await;
+            chat_req.tools =
+                proxy.with_default_tools(chat_req.tools, chat_req.tool_choice.as_ref());
+
+            // The response keeps reporting the client's model; only the backend sees the override
+            let upstream_model = headers
+                .get(UPSTREAM_MODEL_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|model| !model.is_empty());
+            if let Some(model) = upstream_model {
+                if proxy.allow_upstream_model_header {
+                    println!(
+                        "🎯 Upstream model override: '{}' -> '{}' ({} header)",
+                        resolved_model.backend_model, model, UPSTREAM_MODEL_HEADER
+                    );
+                    resolved_model.backend_model = model.to_string();
+                } else {
+                    println!(
+                        "   Ignoring {} header (--allow-upstream-model-header is off)",
+                        UPSTREAM_MODEL_HEADER
+                    );
                 }
-            };
+            }
 
             let reasoning_display = resolved_model
                 .reasoning_effort
@@ -796,6 +4782,18 @@ async fn universal_request_handler(
             );
 
             println!("   Model: {}", chat_req.model);
+            if let Some(metadata) = chat_req.metadata.as_ref().and_then(|m| m.as_object()) {
+                let logged = Value::Object(metadata.clone()).to_string();
+                match proxy.log_redaction {
+                    true => println!("   Metadata: {}", redact_account_ids(&logged)),
+                    false => println!("   Metadata: {}", logged),
+                }
+                proxy.metrics.record_metadata(
+                    metadata
+                        .iter()
+                        .filter_map(|(key, value)| Some((key.as_str(), value.as_str()?))),
+                );
+            }
             println!("   Messages: {} items", chat_req.messages.len());
             for (i, msg) in chat_req.messages.iter().enumerate() {
                 let content_preview = match &msg.content {
@@ -810,48 +4808,129 @@ async fn universal_request_handler(
             }
             println!("🔥 === END MATCHED ===\n");
 
+            let route = proxy.router.select(
+                &[&resolved_model.request_model, &resolved_model.backend_model],
+                chat_req
+                    .tools
+                    .as_ref()
+                    .is_some_and(|tools| !tools.is_empty()),
+            );
+            let Some(route) = proxy.forwarded_route(route, &headers) else {
+                return Ok(json_response(
+                    warp::http::StatusCode::UNAUTHORIZED,
+                    &build_missing_client_auth_response(),
+                ));
+            };
+            println!("   Upstream: {}", route.url);
+
+            let conversation_id = headers
+                .get("x-conversation-id")
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|id| !id.is_empty() && proxy.budgets.enabled())
+                .map(str::to_string);
+            if let Some(id) = &conversation_id {
+                if proxy.budgets.remaining(id) == 0 {
+                    println!("💸 Conversation '{}' is over its token budget", id);
+                    let response = json_response(
+                        warp::http::StatusCode::TOO_MANY_REQUESTS,
+                        &build_conversation_budget_exceeded_response(id, proxy.budgets.budget()),
+                    );
+                    return Ok(with_budget_header(response, Some(0)));
+                }
+            }
+            if let Some(retry_after) = proxy.throughput.throttle() {
+                println!(
+                    "🚦 Tokens-per-minute ceiling of {} reached, rejecting request",
+                    proxy.throughput.limit()
+                );
+                let mut response = json_response(
+                    warp::http::StatusCode::TOO_MANY_REQUESTS,
+                    &build_tokens_per_minute_exceeded_response(proxy.throughput.limit()),
+                );
+                response.headers_mut().insert(
+                    warp::http::header::RETRY_AFTER,
+                    warp::http::HeaderValue::from(retry_after),
+                );
+                return Ok(response);
+            }
+            let record_usage = |response: &ChatCompletionsResponse| {
+                proxy.record_usage(response, conversation_id.as_deref())
+            };
+
+            // Held until the response is built; bounded waiting keeps bursts from piling up
+            let permit = match proxy.limiter.acquire().await {
+                Ok(permit) => permit,
+                Err(_) => {
+                    println!(
+                        "🚦 Queue full ({} waiting), rejecting request",
+                        proxy.limiter.queue_depth()
+                    );
+                    return Ok(proxy_error_response(
+                        &ProxyError::Overloaded {
+                            retry_after: Some(proxy.queue_retry_after),
+                        },
+                        &proxy.error_messages,
+                    ));
+                }
+            };
+
             // Check if streaming is requested
+            let legacy_functions = chat_req.functions.is_some();
             if chat_req.stream.unwrap_or(false) {
                 println!("🔄 STREAMING: CLINE requested streaming response");
+                let mut stream_options = chat_req.stream_options.unwrap_or_default();
+                if proxy.always_stream_usage {
+                    stream_options.include_usage = Some(true);
+                }
 
-                match proxy.proxy_request(chat_req, resolved_model).await {
-                    Ok(response) => {
-                        let chunk_id = format!("chatcmpl-{}", Uuid::new_v4());
-                        let model = response.model.clone();
-                        let message = response
-                            .choices
-                            .first()
-                            .map(|choice| choice.message.content.clone())
-                            .unwrap_or_default();
-                        let message_json =
-                            serde_json::to_string(&message).unwrap_or_else(|_| "\"\"".to_string());
-
-                        let sse_chunks = vec![
-                            format!(
-                                "data: {{\"id\":\"{}\",\"object\":\"chat.completion.chunk\",\"created\":{},\"model\":\"{}\",\"choices\":[{{\"index\":0,\"delta\":{{\"role\":\"assistant\"}},\"finish_reason\":null}}]}}\n\n",
-                                chunk_id,
-                                chrono::Utc::now().timestamp(),
-                                model
-                            ),
-                            format!(
-                                "data: {{\"id\":\"{}\",\"object\":\"chat.completion.chunk\",\"created\":{},\"model\":\"{}\",\"choices\":[{{\"index\":0,\"delta\":{{\"content\":{}}},\"finish_reason\":null}}]}}\n\n",
-                                chunk_id,
-                                chrono::Utc::now().timestamp(),
-                                model,
-                                message_json
-                            ),
-                            format!(
-                                "data: {{\"id\":\"{}\",\"object\":\"chat.completion.chunk\",\"created\":{},\"model\":\"{}\",\"choices\":[{{\"index\":0,\"delta\":{{}},\"finish_reason\":\"stop\"}}]}}\n\n",
-                                chunk_id,
-                                chrono::Utc::now().timestamp(),
-                                model
-                            ),
-                            "data: [DONE]\n\n".to_string(),
-                        ];
-
-                        let sse_response = sse_chunks.join("");
+                let Some(reason) = proxy.replay_reason(&chat_req) else {
+                    return match live::open(
+                        &proxy,
+                        chat_req,
+                        resolved_model,
+                        &route,
+                        stream_options,
+                    )
+                    .await
+                    {
+                        Ok(stream) => {
+                            // The stream's tokens are only known at its end, so the
+                            // header reports the budget left before it
+                            let remaining = conversation_id
+                                .as_deref()
+                                .map(|id| proxy.budgets.remaining(id));
+                            Ok(with_budget_header(
+                                live_stream_response(stream, permit, conversation_id, &proxy),
+                                remaining,
+                            ))
+                        }
+                        Err(e) => {
+                            eprintln!("Proxy error (retryable: {}): {}", e.is_retryable(), e);
+                            Ok(proxy_error_response(&e, &proxy.error_messages))
+                        }
+                    };
+                };
+                println!("   Replaying the complete answer as a stream ({})", reason);
+                match proxy.proxy_request(chat_req, resolved_model, &route).await {
+                    Ok(mut response) => {
+                        if legacy_functions {
+                            response.use_legacy_function_call();
+                        }
+                        let frames = build_stream_chunks(
+                            &response,
+                            &proxy.object_names.chunk,
+                            &proxy.reasoning_field,
+                            (&proxy.response_prefix, &proxy.response_suffix),
+                            stream_options,
+                            proxy.ids.clone(),
+                        );
+                        let body = warp::hyper::Body::wrap_stream(flush::coalesce(
+                            futures_util::stream::iter(frames),
+                            proxy.flush_policy,
+                        ));
                         let reply = warp::reply::with_header(
-                            sse_response,
+                            warp::reply::Response::new(body),
                             "content-type",
                             "text/event-stream",
                         );
@@ -859,40 +4938,50 @@ async fn universal_request_handler(
                         let reply = warp::reply::with_header(reply, "connection", "keep-alive");
                         let reply =
                             warp::reply::with_header(reply, "access-control-allow-origin", "*");
-                        Ok(reply.into_response())
+                        let remaining = record_usage(&response);
+                        Ok(with_budget_header(
+                            with_upstream_headers(reply.into_response(), &response),
+                            remaining,
+                        ))
                     }
                     Err(e) => {
-                        eprintln!("Proxy error: {:#}", e);
-                        let reply = json_response(
-                            warp::http::StatusCode::BAD_GATEWAY,
-                            &build_proxy_error_response(&e.to_string()),
-                        );
-                        Ok(reply)
+                        eprintln!("Proxy error (retryable: {}): {}", e.is_retryable(), e);
+                        Ok(proxy_error_response(&e, &proxy.error_messages))
                     }
                 }
             } else {
-                match proxy.proxy_request(chat_req, resolved_model).await {
-                    Ok(response) => {
+                let content_parts =
+                    wants_content_parts(chat_req.content_format.as_deref(), &headers);
+                match proxy.proxy_request(chat_req, resolved_model, &route).await {
+                    Ok(mut response) => {
+                        if legacy_functions {
+                            response.use_legacy_function_call();
+                        }
+                        response.wrap_content(&proxy.response_prefix, &proxy.response_suffix);
+                        if content_parts {
+                            response.use_content_parts();
+                        }
                         let reply = warp::reply::json(&response);
                         let reply =
                             warp::reply::with_header(reply, "content-type", "application/json");
                         let reply =
                             warp::reply::with_header(reply, "access-control-allow-origin", "*");
-                        Ok(reply.into_response())
+                        let remaining = record_usage(&response);
+                        Ok(with_budget_header(
+                            with_upstream_headers(reply.into_response(), &response),
+                            remaining,
+                        ))
                     }
                     Err(e) => {
-                        eprintln!("Proxy error: {:#}", e);
-                        let reply = json_response(
-                            warp::http::StatusCode::BAD_GATEWAY,
-                            &build_proxy_error_response(&e.to_string()),
-                        );
-                        Ok(reply)
+                        eprintln!("Proxy error (retryable: {}): {}", e.is_retryable(), e);
+                        Ok(proxy_error_response(&e, &proxy.error_messages))
                     }
                 }
             }
         }
         _ => {
-            println!("❌ UNMATCHED: {} {}", method, path_str);
+            Metrics::inc(&proxy.metrics.unmatched_requests_total);
+            proxy.log_unmatched(&method, path_str);
             Ok(
                 warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND)
                     .into_response(),
@@ -906,8 +4995,1023 @@ impl Clone for ProxyServer {
     fn clone(&self) -> Self {
         Self {
             client: self.client.clone(),
-            auth_data: self.auth_data.clone(),
+            accounts: self.accounts.clone(),
+            account_retry_delay: self.account_retry_delay,
+            account_id: self.account_id.clone(),
             allowed_models: self.allowed_models.clone(),
+            models_response: self.models_response.clone(),
+            metrics: self.metrics.clone(),
+            unmatched_log: self.unmatched_log,
+            log_redaction: self.log_redaction,
+            last_unmatched_log: self.last_unmatched_log.clone(),
+            response_role: self.response_role.clone(),
+            response_prefix: self.response_prefix.clone(),
+            response_suffix: self.response_suffix.clone(),
+            audio_models: self.audio_models.clone(),
+            fallback_instructions: self.fallback_instructions.clone(),
+            drop_reasoning: self.drop_reasoning,
+            reasoning_field: self.reasoning_field.clone(),
+            always_stream_usage: self.always_stream_usage,
+            include_logprobs: self.include_logprobs,
+            strict_streaming: self.strict_streaming,
+            mock_upstream: self.mock_upstream,
+            upstream_based_ids: self.upstream_based_ids,
+            ids: self.ids.clone(),
+            limiter: self.limiter.clone(),
+            queue_retry_after: self.queue_retry_after,
+            fanout_concurrency: self.fanout_concurrency,
+            partial_fanout: self.partial_fanout,
+            retry_empty: self.retry_empty,
+            enforce_tool_choice: self.enforce_tool_choice,
+            stream_accept_conflict: self.stream_accept_conflict,
+            max_n: self.max_n,
+            strict_max_n: self.strict_max_n,
+            upstream_headers: self.upstream_headers.clone(),
+            error_messages: self.error_messages.clone(),
+            accept_encoding: self.accept_encoding.clone(),
+            fallback_models: self.fallback_models.clone(),
+            max_output_tokens: self.max_output_tokens.clone(),
+            strict_max_tokens: self.strict_max_tokens,
+            strict_request_fields: self.strict_request_fields,
+            max_messages: self.max_messages,
+            truncate_messages: self.truncate_messages,
+            default_reasoning_effort: self.default_reasoning_effort.clone(),
+            model_reasoning_efforts: self.model_reasoning_efforts.clone(),
+            model_map: self.model_map.clone(),
+            router: self.router.clone(),
+            object_names: self.object_names.clone(),
+            log_format: self.log_format,
+            route_timeouts: self.route_timeouts.clone(),
+            stream_idle_timeout: self.stream_idle_timeout,
+            health_check_timeout: self.health_check_timeout,
+            max_sse_events: self.max_sse_events,
+            max_tool_arguments_bytes: self.max_tool_arguments_bytes,
+            trace_sse: self.trace_sse,
+            flush_policy: self.flush_policy,
+            stream_channel_capacity: self.stream_channel_capacity,
+            transforms: self.transforms.clone(),
+            default_tools: self.default_tools.clone(),
+            default_tools_mode: self.default_tools_mode,
+            admin_token: self.admin_token.clone(),
+            forward_auth: self.forward_auth,
+            recent: self.recent.clone(),
+            budgets: self.budgets.clone(),
+            throughput: self.throughput.clone(),
+            token_estimator: self.token_estimator.clone(),
+            instance_id: self.instance_id.clone(),
+            allow_upstream_model_header: self.allow_upstream_model_header,
+            errors_as_200: self.errors_as_200,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    async fn test_proxy(flags: &[&str]) -> ProxyServer {
+        let base = ["codex-openai-proxy", "--no-dotenv", "--forward-auth"];
+        let args = Args::parse_from(base.iter().chain(flags));
+        ProxyServer::new(&args).await.expect("proxy should build")
+    }
+
+    fn resolved(model: &str) -> ResolvedModel {
+        ResolvedModel {
+            request_model: model.to_string(),
+            backend_model: model.to_string(),
+            reasoning_effort: None,
+        }
+    }
+
+    fn validate(
+        proxy: &ProxyServer,
+        body: Value,
+    ) -> Result<(ChatCompletionsRequest, ResolvedModel), Value> {
+        let body = serde_json::to_vec(&body).unwrap();
+        proxy.validate_chat_request(&body, &warp::http::HeaderMap::new())
+    }
+
+    /// Validate a request body and convert it, returning the upstream JSON.
+    fn upstream_request(proxy: &ProxyServer, body: Value) -> Value {
+        let (chat_req, resolved_model) = validate(proxy, body).expect("request should be valid");
+        serde_json::to_value(proxy.convert_chat_to_responses(chat_req, &resolved_model)).unwrap()
+    }
+
+    /// A local Responses endpoint answering the `n`th request (from 0) with
+    /// `reply(n, payload)`, reached through a keyed route.
+    fn upstream<F, Fut>(reply: F) -> UpstreamRoute
+    where
+        F: Fn(usize, Value) -> Fut + Clone + Send + Sync + 'static,
+        Fut: std::future::Future<Output = warp::http::Response<Vec<u8>>> + Send,
+    {
+        upstream_with_headers(move |n, _, payload| reply(n, payload))
+    }
+
+    /// As `upstream`, with the request headers passed to `reply` as well.
+    fn upstream_with_headers<F, Fut>(reply: F) -> UpstreamRoute
+    where
+        F: Fn(usize, warp::http::HeaderMap, Value) -> Fut + Clone + Send + Sync + 'static,
+        Fut: std::future::Future<Output = warp::http::Response<Vec<u8>>> + Send,
+    {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let endpoint = warp::post()
+            .and(warp::header::headers_cloned())
+            .and(warp::body::json())
+            .then(move |headers, payload: Value| {
+                let n = requests.fetch_add(1, Ordering::SeqCst);
+                reply.clone()(n, headers, payload)
+            });
+        let (addr, server) = warp::serve(endpoint).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        UpstreamRoute {
+            url: format!("http://{}/v1/responses", addr),
+            api_key: Some("test-key".to_string()),
+        }
+    }
+
+    /// The mock upstream's answer to `payload`, as an SSE response.
+    fn sse_reply(payload: &Value) -> warp::http::Response<Vec<u8>> {
+        warp::http::Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(mock::sse_body(payload))
+            .unwrap()
+    }
+
+    fn error_reply() -> warp::http::Response<Vec<u8>> {
+        let error =
+            json!({ "error": { "message": "Bad request", "type": "invalid_request_error" } });
+        warp::http::Response::builder()
+            .status(400)
+            .header("content-type", "application/json")
+            .body(error.to_string().into_bytes())
+            .unwrap()
+    }
+
+    /// Run upstream events through the proxy's accumulator and build the response.
+    fn respond(
+        proxy: &ProxyServer,
+        events: &[Value],
+    ) -> Result<ChatCompletionsResponse, ProxyError> {
+        let mut accumulator = proxy.response_accumulator();
+        for event in events {
+            accumulator.handle_event(event);
+        }
+        proxy.build_chat_response(
+            accumulator.finish(),
+            resolved("gpt-5"),
+            "default".to_string(),
+            &[],
+            Duration::ZERO,
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn tool_call_only_response_has_null_content() {
+        let proxy = test_proxy(&[]).await;
+        let call = json!({ "type": "function_call", "call_id": "call_1", "name": "read_file", "arguments": "{\"path\":\"a.rs\"}" });
+        let response = respond(
+            &proxy,
+            &[
+                json!({ "type": "response.output_item.added", "output_index": 0, "item": call }),
+                json!({ "type": "response.output_item.done", "output_index": 0, "item": call }),
+                json!({ "type": "response.completed", "response": { "id": "resp_1" } }),
+            ],
+        )
+        .expect("a tool call is a complete answer");
+
+        let choice = &serde_json::to_value(&response).unwrap()["choices"][0];
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert_eq!(choice["message"]["content"], Value::Null);
+        let calls = choice["message"]["tool_calls"].as_array().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0]["id"], "call_1");
+        assert_eq!(calls[0]["function"]["name"], "read_file");
+        assert_eq!(calls[0]["function"]["arguments"], "{\"path\":\"a.rs\"}");
+    }
+
+    async fn effort_proxy() -> ProxyServer {
+        test_proxy(&[
+            "--reasoning-effort",
+            "low",
+            "--model-reasoning-efforts",
+            "gpt-5=high",
+        ])
+        .await
+    }
+
+    #[tokio::test]
+    async fn requested_effort_beats_every_default() {
+        let proxy = effort_proxy().await;
+        let model = proxy.resolve_model("gpt-5-medium").unwrap();
+        assert_eq!(
+            proxy.reasoning_effort(&model, Some("minimal")).as_deref(),
+            Some("minimal")
+        );
+    }
+
+    #[tokio::test]
+    async fn model_suffix_beats_configured_defaults() {
+        let proxy = effort_proxy().await;
+        let model = proxy.resolve_model("gpt-5-medium").unwrap();
+        assert_eq!(
+            proxy.reasoning_effort(&model, None).as_deref(),
+            Some("medium")
+        );
+    }
+
+    #[tokio::test]
+    async fn per_model_effort_beats_global_default() {
+        let proxy = effort_proxy().await;
+        assert_eq!(
+            proxy.reasoning_effort(&resolved("gpt-5"), None).as_deref(),
+            Some("high")
+        );
+    }
+
+    #[tokio::test]
+    async fn global_effort_applies_to_other_models() {
+        let proxy = effort_proxy().await;
+        assert_eq!(
+            proxy
+                .reasoning_effort(&resolved("gpt-5-codex"), None)
+                .as_deref(),
+            Some("low")
+        );
+        let unset = test_proxy(&[]).await;
+        assert_eq!(unset.reasoning_effort(&resolved("gpt-5"), None), None);
+    }
+
+    fn assistant_calls(ids: &[&str]) -> Value {
+        let calls: Vec<Value> = ids
+            .iter()
+            .map(|id| json!({ "id": id, "type": "function", "function": { "name": "lookup", "arguments": "{}" } }))
+            .collect();
+        json!({ "role": "assistant", "content": null, "tool_calls": calls })
+    }
+
+    #[tokio::test]
+    async fn tool_results_pair_with_their_calls() {
+        let proxy = test_proxy(&[]).await;
+        let request = upstream_request(
+            &proxy,
+            json!({
+                "model": "gpt-5",
+                "messages": [
+                    { "role": "user", "content": "Look up both" },
+                    assistant_calls(&["call_a", "call_b"]),
+                    { "role": "tool", "tool_call_id": "call_b", "content": "B" },
+                    { "role": "tool", "content": "A" }
+                ]
+            }),
+        );
+
+        let items: Vec<(&str, &str)> = request["input"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|item| item["type"] == "function_call_output")
+            .map(|item| {
+                (
+                    item["call_id"].as_str().unwrap(),
+                    item["output"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(items, [("call_b", "B"), ("call_a", "A")]);
+    }
+
+    #[tokio::test]
+    async fn tool_result_without_id_or_call_is_rejected() {
+        let proxy = test_proxy(&[]).await;
+        let orphans = [
+            (json!({ "role": "tool", "content": "orphan" }), None),
+            (
+                json!({ "role": "tool", "tool_call_id": "call_x", "content": "orphan" }),
+                Some("'call_x'"),
+            ),
+        ];
+        for (orphan, named_id) in orphans {
+            let error = validate(
+                &proxy,
+                json!({
+                    "model": "gpt-5",
+                    "messages": [
+                        { "role": "user", "content": "Hi" },
+                        assistant_calls(&["call_a"]),
+                        { "role": "tool", "tool_call_id": "call_a", "content": "A" },
+                        orphan
+                    ]
+                }),
+            )
+            .expect_err("an orphan tool result answers no call");
+
+            assert_eq!(error["error"]["type"], "invalid_request_error");
+            assert_eq!(error["error"]["code"], "invalid_tool_message");
+            let message = error["error"]["message"].as_str().unwrap();
+            assert!(message.starts_with("messages[3]"));
+            if let Some(id) = named_id {
+                assert!(message.contains(id));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn instructions_alone_are_empty_messages() {
+        let proxy = test_proxy(&[]).await;
+        for messages in [
+            json!([]),
+            json!([{ "role": "system", "content": "Be brief" }]),
+            json!([
+                { "role": "system", "content": "Be brief" },
+                { "role": "developer", "content": "Answer in French" }
+            ]),
+        ] {
+            let error = validate(&proxy, json!({ "model": "gpt-5", "messages": messages }))
+                .expect_err("there is nothing to answer");
+            assert_eq!(error["error"]["code"], "empty_messages");
+        }
+
+        let messages = json!([
+            { "role": "developer", "content": "Answer in French" },
+            { "role": "user", "content": "Hi" }
+        ]);
+        assert!(validate(&proxy, json!({ "model": "gpt-5", "messages": messages })).is_ok());
+    }
+
+    #[tokio::test]
+    async fn truncation_keeps_system_and_developer_messages() {
+        let proxy = test_proxy(&["--max-messages", "2", "--truncate-messages"]).await;
+        let (chat_req, _) = validate(
+            &proxy,
+            json!({
+                "model": "gpt-5",
+                "messages": [
+                    { "role": "system", "content": "Be brief" },
+                    { "role": "developer", "content": "Answer in French" },
+                    { "role": "user", "content": "one" },
+                    { "role": "assistant", "content": "un" },
+                    { "role": "user", "content": "two" }
+                ]
+            }),
+        )
+        .expect("long requests are truncated, not rejected");
+
+        let kept: Vec<(&str, &str)> = chat_req
+            .messages
+            .iter()
+            .map(|msg| (msg.role.as_str(), msg.content.as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                ("system", "Be brief"),
+                ("developer", "Answer in French"),
+                ("assistant", "un"),
+                ("user", "two")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn tool_choice_is_forwarded_in_responses_shape() {
+        let proxy = test_proxy(&[]).await;
+        let tools = json!([{ "type": "function", "function": { "name": "lookup" } }]);
+        let cases = [
+            (None, json!("auto")),
+            (Some(json!("required")), json!("required")),
+            (Some(json!("none")), json!("none")),
+            (
+                Some(json!({ "type": "function", "function": { "name": "lookup" } })),
+                json!({ "type": "function", "name": "lookup" }),
+            ),
+        ];
+        for (tool_choice, expected) in cases {
+            let mut body = json!({
+                "model": "gpt-5",
+                "messages": [{ "role": "user", "content": "Hi" }],
+                "tools": tools
+            });
+            if let Some(tool_choice) = tool_choice {
+                body["tool_choice"] = tool_choice;
+            }
+            assert_eq!(upstream_request(&proxy, body)["tool_choice"], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_functions_become_tools_and_tool_choice() {
+        let proxy = test_proxy(&[]).await;
+        let function = json!({ "name": "lookup", "parameters": { "type": "object" } });
+        let (chat_req, _) = validate(
+            &proxy,
+            json!({
+                "model": "gpt-5",
+                "messages": [
+                    { "role": "user", "content": "Look it up" },
+                    { "role": "assistant", "content": null, "function_call": { "name": "lookup", "arguments": "{}" } },
+                    { "role": "function", "name": "lookup", "content": "found" }
+                ],
+                "functions": [function],
+                "function_call": { "name": "lookup" }
+            }),
+        )
+        .unwrap();
+
+        assert_eq!(
+            chat_req.tools,
+            Some(vec![json!({ "type": "function", "function": function })])
+        );
+        assert_eq!(
+            chat_req.tool_choice,
+            Some(json!({ "type": "function", "function": { "name": "lookup" } }))
+        );
+        let call = &chat_req.messages[1].tool_calls.as_ref().unwrap()[0];
+        assert_eq!(call["id"], "call_legacy_1");
+        assert_eq!(call["function"]["name"], "lookup");
+        assert_eq!(chat_req.messages[2].role, "tool");
+        assert_eq!(
+            chat_req.messages[2].tool_call_id.as_deref(),
+            Some("call_legacy_1")
+        );
+    }
+
+    #[tokio::test]
+    async fn legacy_function_call_strings_pass_through() {
+        let proxy = test_proxy(&[]).await;
+        for choice in ["none", "auto"] {
+            let (chat_req, _) = validate(
+                &proxy,
+                json!({
+                    "model": "gpt-5",
+                    "messages": [{ "role": "user", "content": "Hi" }],
+                    "functions": [{ "name": "lookup" }],
+                    "function_call": choice
+                }),
+            )
+            .unwrap();
+            assert_eq!(chat_req.tool_choice, Some(json!(choice)));
+        }
+    }
+
+    #[tokio::test]
+    async fn modern_tool_fields_win_over_legacy_ones() {
+        let proxy = test_proxy(&[]).await;
+        let tools = vec![json!({ "type": "function", "function": { "name": "modern" } })];
+        let (chat_req, _) = validate(
+            &proxy,
+            json!({
+                "model": "gpt-5",
+                "messages": [{ "role": "user", "content": "Hi" }],
+                "tools": tools,
+                "tool_choice": "required",
+                "functions": [{ "name": "legacy" }],
+                "function_call": { "name": "legacy" }
+            }),
+        )
+        .unwrap();
+        assert_eq!(chat_req.tools, Some(tools));
+        assert_eq!(chat_req.tool_choice, Some(json!("required")));
+    }
+
+    #[tokio::test]
+    async fn parallel_tool_calls_are_forwarded_with_tools() {
+        let proxy = test_proxy(&[]).await;
+        let tools = json!([{ "type": "function", "function": { "name": "lookup" } }]);
+        for (body, expected) in [
+            (json!({ "tools": tools }), false),
+            (json!({ "tools": tools, "parallel_tool_calls": true }), true),
+            (
+                json!({ "tools": tools, "parallel_tool_calls": false }),
+                false,
+            ),
+            (json!({ "parallel_tool_calls": true }), false),
+        ] {
+            let mut body = body;
+            body["model"] = json!("gpt-5");
+            body["messages"] = json!([{ "role": "user", "content": "Hi" }]);
+            assert_eq!(
+                upstream_request(&proxy, body)["parallel_tool_calls"],
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn default_tools_follow_the_client_tool_choice() {
+        let path = std::env::temp_dir().join(format!("default-tools-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            json!([{ "type": "function", "function": { "name": "search" } }]).to_string(),
+        )
+        .unwrap();
+        let proxy = test_proxy(&["--default-tools-file", path.to_str().unwrap()]).await;
+        std::fs::remove_file(&path).unwrap();
+
+        let request = |tool_choice: Value| {
+            let body = json!({
+                "model": "gpt-5",
+                "messages": [{ "role": "user", "content": "Hi" }],
+                "tool_choice": tool_choice,
+                "parallel_tool_calls": true
+            });
+            let (mut chat_req, resolved_model) = validate(&proxy, body).unwrap();
+            chat_req.tools =
+                proxy.with_default_tools(chat_req.tools, chat_req.tool_choice.as_ref());
+            serde_json::to_value(proxy.convert_chat_to_responses(chat_req, &resolved_model))
+                .unwrap()
+        };
+
+        let required = request(json!("required"));
+        assert_eq!(required["tools"][0]["name"], "search");
+        assert_eq!(required["tool_choice"], "required");
+        assert_eq!(required["parallel_tool_calls"], true);
+
+        // No defaults for a client that wants no tool calls, so nothing to run in parallel
+        let none = request(json!("none"));
+        assert_eq!(none["tools"], json!([]));
+        assert_eq!(none["tool_choice"], "none");
+        assert_eq!(none["parallel_tool_calls"], false);
+    }
+
+    /// Fan `n = 3` out to an upstream that fails the first request it gets.
+    async fn fan_out_with_one_failure(
+        flags: &[&str],
+    ) -> Result<ChatCompletionsResponse, ProxyError> {
+        let proxy = test_proxy(flags).await;
+        let route = upstream(|n, payload| async move {
+            if n == 0 {
+                error_reply()
+            } else {
+                sse_reply(&payload)
+            }
+        });
+        let body = json!({
+            "model": "gpt-5",
+            "messages": [{ "role": "user", "content": "Hello" }],
+            "n": 3
+        });
+        let (chat_req, resolved_model) = validate(&proxy, body).unwrap();
+        proxy.proxy_request(chat_req, resolved_model, &route).await
+    }
+
+    #[tokio::test]
+    async fn fan_out_fails_when_any_request_fails() {
+        let error = fan_out_with_one_failure(&[])
+            .await
+            .expect_err("one failed choice fails the completion");
+        assert_eq!(error.status(), warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn partial_fan_out_returns_the_choices_that_succeeded() {
+        let response = fan_out_with_one_failure(&["--partial-fanout"])
+            .await
+            .expect("two of three choices succeeded");
+        let indices: Vec<i32> = response.choices.iter().map(|choice| choice.index).collect();
+        assert_eq!(indices, [0, 1]);
+        for choice in &response.choices {
+            let message = serde_json::to_value(&choice.message).unwrap();
+            assert_eq!(message["content"], "Mock response: Hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn fan_out_limits_requests_in_flight() {
+        let proxy = test_proxy(&["--fanout-concurrency", "2"]).await;
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+        let route = upstream({
+            let (in_flight, most_in_flight) = (in_flight.clone(), most_in_flight.clone());
+            move |_, payload| {
+                let (in_flight, most_in_flight) = (in_flight.clone(), most_in_flight.clone());
+                async move {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_in_flight.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    sse_reply(&payload)
+                }
+            }
+        });
+        let body = json!({
+            "model": "gpt-5",
+            "messages": [{ "role": "user", "content": "Hello" }],
+            "n": 5
+        });
+        let (chat_req, resolved_model) = validate(&proxy, body).unwrap();
+        let response = proxy
+            .proxy_request(chat_req, resolved_model, &route)
+            .await
+            .unwrap();
+
+        assert_eq!(response.choices.len(), 5);
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn null_temperature_is_unset() {
+        let proxy = test_proxy(&[]).await;
+        for temperature in [json!(null), json!(0.2)] {
+            let (chat_req, _) = validate(
+                &proxy,
+                json!({
+                    "model": "gpt-5",
+                    "messages": [{ "role": "user", "content": "Hi" }],
+                    "temperature": temperature
+                }),
+            )
+            .unwrap();
+            assert_eq!(chat_req.temperature, temperature.as_f64().map(|t| t as f32));
+        }
+    }
+
+    #[tokio::test]
+    async fn prediction_is_forwarded_to_routed_upstreams() {
+        let proxy = test_proxy(&[]).await;
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let route = upstream({
+            let sent = sent.clone();
+            move |_, payload| {
+                let reply = sse_reply(&payload);
+                sent.lock().unwrap().push(payload);
+                async move { reply }
+            }
+        });
+        let prediction = json!({ "type": "content", "content": "fn main() {}" });
+        let body = json!({
+            "model": "gpt-5",
+            "messages": [{ "role": "user", "content": "Rename main" }],
+            "prediction": prediction
+        });
+        let (chat_req, resolved_model) = validate(&proxy, body).unwrap();
+        proxy
+            .proxy_request(chat_req, resolved_model, &route)
+            .await
+            .unwrap();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["prediction"], prediction);
+    }
+
+    #[tokio::test]
+    async fn replayed_chunks_match_snapshot() {
+        let proxy = test_proxy(&[]).await;
+        let mut response = respond(
+            &proxy,
+            &[
+                json!({ "type": "response.output_item.added", "output_index": 0, "item": { "type": "message" } }),
+                json!({ "type": "response.output_text.delta", "output_index": 0, "delta": "Hi \"there\"" }),
+                json!({ "type": "response.completed", "response": {
+                    "id": "resp_1",
+                    "usage": { "input_tokens": 5, "output_tokens": 2, "total_tokens": 7 }
+                } }),
+            ],
+        )
+        .unwrap();
+        // Quotes in the id and model must come out escaped
+        response.id = "chatcmpl-\"1\"".to_string();
+        response.model = "gpt-5 \"fast\"".to_string();
+        response.created = 1700000000;
+        let options = StreamOptions {
+            include_usage: Some(true),
+            include_obfuscation: Some(true),
+        };
+        let chunks = build_stream_chunks(
+            &response,
+            "chat.completion.chunk",
+            "reasoning_content",
+            ("", ""),
+            options,
+            Arc::new(IdSource::new(Some(7))),
+        );
+
+        let snapshot = [
+            concat!(
+                r#"data: {"id":"chatcmpl-\"1\"","object":"chat.completion.chunk","created":1700000000,"model":"gpt-5 \"fast\"","service_tier":"default","choices":[{"delta":{"role":"assistant"},"finish_reason":null,"index":0}],"usage":null,"obfuscation":"d70d3259"}"#,
+                "\n\n"
+            ),
+            concat!(
+                r#"data: {"id":"chatcmpl-\"1\"","object":"chat.completion.chunk","created":1700000000,"model":"gpt-5 \"fast\"","service_tier":"default","choices":[{"delta":{"content":"Hi \"there\""},"finish_reason":null,"index":0}],"usage":null,"obfuscation":"5ac389a30c3"}"#,
+                "\n\n"
+            ),
+            concat!(
+                r#"data: {"id":"chatcmpl-\"1\"","object":"chat.completion.chunk","created":1700000000,"model":"gpt-5 \"fast\"","service_tier":"default","choices":[{"delta":{},"finish_reason":"stop","index":0}],"usage":null,"obfuscation":"ed8f01dbe4144b"}"#,
+                "\n\n"
+            ),
+            concat!(
+                r#"data: {"id":"chatcmpl-\"1\"","object":"chat.completion.chunk","created":1700000000,"model":"gpt-5 \"fast\"","service_tier":"default","choices":[],"usage":{"completion_tokens":2,"prompt_tokens":5,"total_tokens":7},"obfuscation":"c1"}"#,
+                "\n\n"
+            ),
+            concat!(r#"data: [DONE]"#, "\n\n"),
+        ];
+        assert_eq!(chunks, snapshot);
+    }
+
+    /// Answer `n = 1` from an upstream that encodes its SSE body with `encoding`.
+    async fn encoded_completion(
+        encoding: &'static str,
+        encode: fn(&[u8]) -> Vec<u8>,
+    ) -> Result<ChatCompletionsResponse, ProxyError> {
+        let proxy = test_proxy(&[]).await;
+        let route = upstream(move |_, payload| {
+            let mut reply = sse_reply(&payload);
+            *reply.body_mut() = encode(reply.body());
+            reply.headers_mut().insert(
+                "content-encoding",
+                warp::http::HeaderValue::from_static(encoding),
+            );
+            async move { reply }
+        });
+        let body = json!({
+            "model": "gpt-5",
+            "messages": [{ "role": "user", "content": "Hello there" }]
+        });
+        let (chat_req, resolved_model) = validate(&proxy, body).unwrap();
+        proxy.proxy_request(chat_req, resolved_model, &route).await
+    }
+
+    #[tokio::test]
+    async fn gzip_encoded_events_are_decoded() {
+        use std::io::Write;
+        let gzip = |body: &[u8]| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        };
+        let response = encoded_completion("gzip", gzip).await.unwrap();
+
+        let message = serde_json::to_value(&response.choices[0].message).unwrap();
+        assert_eq!(message["content"], "Mock response: Hello there");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+
+    #[tokio::test]
+    async fn undecodable_encoding_fails_clearly() {
+        let error = encoded_completion("zstd", |body| body.to_vec())
+            .await
+            .expect_err("zstd bodies cannot be decoded");
+        assert_eq!(error.status(), warp::http::StatusCode::BAD_GATEWAY);
+        assert!(error.to_string().contains("'zstd' encoded body"));
+    }
+
+    #[tokio::test]
+    async fn tools_go_upstream_flat() {
+        let proxy = test_proxy(&[]).await;
+        let parameters =
+            json!({ "type": "object", "properties": { "path": { "type": "string" } } });
+        let request = upstream_request(
+            &proxy,
+            json!({
+                "model": "gpt-5",
+                "messages": [{ "role": "user", "content": "Read it" }],
+                "tools": [
+                    { "type": "function", "function": {
+                        "name": "read_file",
+                        "description": "Read a file",
+                        "parameters": parameters,
+                        "strict": true
+                    } },
+                    { "type": "function", "name": "list_files", "parameters": { "type": "object" } }
+                ]
+            }),
+        );
+
+        assert_eq!(
+            request["tools"],
+            json!([
+                {
+                    "type": "function",
+                    "name": "read_file",
+                    "description": "Read a file",
+                    "parameters": parameters,
+                    "strict": true
+                },
+                { "type": "function", "name": "list_files", "parameters": { "type": "object" } }
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn legacy_functions_go_upstream_flat() {
+        let proxy = test_proxy(&[]).await;
+        let request = upstream_request(
+            &proxy,
+            json!({
+                "model": "gpt-5",
+                "messages": [{ "role": "user", "content": "Look it up" }],
+                "functions": [{ "name": "lookup", "parameters": { "type": "object" } }]
+            }),
+        );
+        assert_eq!(
+            request["tools"],
+            json!([{ "type": "function", "name": "lookup", "parameters": { "type": "object" } }])
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_after_refresh_reuses_the_refreshed_account() {
+        let dir = std::env::temp_dir().join(format!("refresh-retry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut flags = vec!["codex-openai-proxy".to_string(), "--no-dotenv".to_string()];
+        for name in ["a", "b"] {
+            let path = dir.join(format!("{}.json", name));
+            let auth = json!({ "tokens": {
+                "access_token": format!("token-{}", name),
+                "refresh_token": format!("refresh-{}", name),
+                "account_id": format!("acct-{}", name)
+            } });
+            std::fs::write(&path, auth.to_string()).unwrap();
+            flags.extend(["--auth-path".to_string(), path.display().to_string()]);
+        }
+        let proxy = ProxyServer::new(&Args::parse_from(&flags)).await.unwrap();
+
+        let bearers = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let route = upstream_with_headers({
+            let (proxy, bearers) = (proxy.clone(), bearers.clone());
+            move |n, headers, payload| {
+                let bearer = headers["authorization"].to_str().unwrap().to_string();
+                bearers.lock().unwrap().push(bearer.clone());
+                let reply = if n == 0 {
+                    // Stand in for a refresh another request has just made, so
+                    // the token endpoint is not called
+                    let account = proxy
+                        .accounts
+                        .accounts()
+                        .iter()
+                        .find(|account| {
+                            bearer.ends_with(&account.auth().tokens.unwrap().access_token)
+                        })
+                        .unwrap();
+                    let token =
+                        format!("{}-refreshed", account.auth().tokens.unwrap().access_token);
+                    account.update_tokens(token, None);
+                    warp::http::Response::builder()
+                        .status(401)
+                        .body(b"{}".to_vec())
+                        .unwrap()
+                } else {
+                    sse_reply(&payload)
+                };
+                async move { reply }
+            }
+        });
+        let route = UpstreamRoute {
+            api_key: None,
+            ..route
+        };
+        let body = json!({ "model": "gpt-5", "messages": [{ "role": "user", "content": "Hi" }] });
+        let (chat_req, resolved_model) = validate(&proxy, body).unwrap();
+        proxy
+            .proxy_request(chat_req, resolved_model, &route)
+            .await
+            .expect("the retry with the refreshed token succeeds");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let bearers = bearers.lock().unwrap();
+        assert_eq!(bearers.len(), 2);
+        assert_eq!(bearers[1], format!("{}-refreshed", bearers[0]));
+    }
+
+    /// Answer a GET for `path` the way the server's filter chain does.
+    async fn get(
+        proxy: &ProxyServer,
+        path: &str,
+        bearer: Option<&str>,
+    ) -> (warp::http::StatusCode, Value) {
+        let proxy = proxy.clone();
+        let handler = warp::any()
+            .and(warp::method())
+            .and(warp::path::full())
+            .and(raw_query())
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .and(warp::any().map(move || proxy.clone()))
+            .and_then(universal_request_handler);
+        let mut request = warp::test::request().method("GET").path(path);
+        if let Some(bearer) = bearer {
+            request = request.header("authorization", format!("Bearer {}", bearer));
+        }
+        let response = request.reply(&handler).await;
+        (
+            response.status(),
+            serde_json::from_slice(response.body()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn deep_health_shows_auth_details_only_to_admins() {
+        let open = test_proxy(&["--mock-upstream"]).await;
+        let (_, health) = get(&open, "/health?deep=1", None).await;
+        assert_eq!(health["upstream"], "mock");
+        assert!(health.get("auth").is_none());
+
+        let guarded = test_proxy(&["--mock-upstream", "--admin-token", "secret"]).await;
+        for bearer in [None, Some("wrong")] {
+            let (_, health) = get(&guarded, "/health?deep=1", bearer).await;
+            assert_eq!(health["upstream"], "mock");
+            assert!(health.get("auth").is_none());
+        }
+        let (_, health) = get(&guarded, "/health?deep=1", Some("secret")).await;
+        assert!(health["auth"].is_array());
+    }
+
+    /// An SSE response carrying `events` as the backend would send them.
+    fn events_reply(events: &[Value]) -> warp::http::Response<Vec<u8>> {
+        let body: String = events
+            .iter()
+            .map(|event| format!("data: {}\n\n", event))
+            .collect();
+        warp::http::Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(body.into_bytes())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn live_retry_after_empty_answer_sends_one_reasoning_block() {
+        let proxy = test_proxy(&["--retry-empty"]).await;
+        let reasoning = |text: &str| {
+            [
+                json!({ "type": "response.output_item.added", "output_index": 0, "item": { "type": "reasoning" } }),
+                json!({ "type": "response.reasoning_summary_text.delta", "output_index": 0, "delta": text }),
+            ]
+        };
+        let route = upstream(move |n, _| {
+            let mut events = reasoning(if n == 0 {
+                "First thoughts"
+            } else {
+                "Second thoughts"
+            })
+            .to_vec();
+            if n > 0 {
+                events.extend([
+                    json!({ "type": "response.output_item.added", "output_index": 1, "item": { "type": "message" } }),
+                    json!({ "type": "response.output_text.delta", "output_index": 1, "delta": "Answer" }),
+                ]);
+            }
+            events.push(json!({ "type": "response.completed", "response": { "id": format!("resp_{}", n) } }));
+            let reply = events_reply(&events);
+            async move { reply }
+        });
+        let body = json!({
+            "model": "gpt-5",
+            "messages": [{ "role": "user", "content": "Hi" }],
+            "stream": true
+        });
+        let (chat_req, resolved_model) = validate(&proxy, body).unwrap();
+        let stream = live::open(
+            &proxy,
+            chat_req,
+            resolved_model,
+            &Arc::new(route),
+            StreamOptions::default(),
+        )
+        .await
+        .unwrap();
+        let frames: Vec<String> = stream.frames().collect().await;
+
+        let (mut reasoning, mut content) = (String::new(), String::new());
+        for frame in &frames {
+            let data = frame.strip_prefix("data: ").unwrap().trim_end();
+            if data == "[DONE]" {
+                continue;
+            }
+            let chunk: Value = serde_json::from_str(data).unwrap();
+            let delta = &chunk["choices"][0]["delta"];
+            reasoning.push_str(delta["reasoning_content"].as_str().unwrap_or_default());
+            content.push_str(delta["content"].as_str().unwrap_or_default());
+        }
+        assert_eq!(reasoning, "First thoughts");
+        assert_eq!(content, "Answer");
+        assert_eq!(frames.last().unwrap(), "data: [DONE]\n\n");
+    }
+
+    #[tokio::test]
+    async fn deep_health_fails_on_upstream_server_errors() {
+        for (status, upstream, check) in [
+            (404, "reachable", 200),
+            (405, "reachable", 200),
+            (502, "degraded", 503),
+        ] {
+            let backend = warp::any().map(move || {
+                warp::reply::with_status("", warp::http::StatusCode::from_u16(status).unwrap())
+            });
+            let (addr, server) = warp::serve(backend).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            let rule = format!("*=http://{}/v1/responses", addr);
+            let proxy = test_proxy(&["--route", &rule]).await;
+
+            let (code, health) = get(&proxy, "/health?deep=1", None).await;
+            assert_eq!(health["upstream"], upstream);
+            assert_eq!(code.as_u16(), check);
+            if upstream == "degraded" {
+                assert_eq!(health["upstream_status"], status);
+            }
         }
     }
 }
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// Distinct metadata key/value label pairs tracked before folding into `_other`.
+const METADATA_LABEL_LIMIT: usize = 100;
+const METADATA_OVERFLOW_LABEL: &str = "_other";
+
+// Upper bounds (bytes) of the upstream payload size histogram buckets
+const PAYLOAD_BUCKETS: [u64; 6] = [16_384, 65_536, 262_144, 1_048_576, 4_194_304, 16_777_216];
+
+/// Process-wide counters exposed at `/metrics` in Prometheus text format.
+#[derive(Default, Debug)]
+pub struct Metrics {
+    pub requests_total: AtomicU64,
+    pub unmatched_requests_total: AtomicU64,
+    metadata_requests: Mutex<BTreeMap<(String, String), u64>>,
+    /// Per bucket of `PAYLOAD_BUCKETS`, not cumulative; the last counts larger ones
+    payload_buckets: [AtomicU64; PAYLOAD_BUCKETS.len() + 1],
+    payload_bytes_sum: AtomicU64,
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request under each of its metadata tags, bounded to
+    /// `METADATA_LABEL_LIMIT` label pairs so clients cannot explode cardinality.
+    pub fn record_metadata<'a>(&self, tags: impl Iterator<Item = (&'a str, &'a str)>) {
+        let mut counts = self
+            .metadata_requests
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (key, value) in tags {
+            let label = (key.to_string(), value.to_string());
+            let label = if counts.contains_key(&label) || counts.len() < METADATA_LABEL_LIMIT {
+                label
+            } else {
+                (
+                    METADATA_OVERFLOW_LABEL.to_string(),
+                    METADATA_OVERFLOW_LABEL.to_string(),
+                )
+            };
+            *counts.entry(label).or_insert(0) += 1;
+        }
+    }
+
+    /// Count the size of a serialized upstream request.
+    pub fn record_payload_size(&self, bytes: usize) {
+        let bytes = bytes as u64;
+        let bucket = PAYLOAD_BUCKETS
+            .iter()
+            .position(|&bound| bytes <= bound)
+            .unwrap_or(PAYLOAD_BUCKETS.len());
+        self.payload_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.payload_bytes_sum.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "codex_proxy_requests_total",
+                "Total HTTP requests received",
+                &self.requests_total,
+            ),
+            (
+                "codex_proxy_unmatched_requests_total",
+                "Requests for paths the proxy does not serve",
+                &self.unmatched_requests_total,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        let name = "codex_proxy_upstream_request_bytes";
+        let _ = writeln!(out, "# HELP {name} Size of serialized upstream requests");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (index, count) in self.payload_buckets.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let bound = PAYLOAD_BUCKETS
+                .get(index)
+                .map_or_else(|| "+Inf".to_string(), ToString::to_string);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let sum = self.payload_bytes_sum.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {cumulative}");
+
+        let name = "codex_proxy_requests_by_metadata_total";
+        let _ = writeln!(out, "# HELP {name} Chat requests per metadata tag");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let counts = self
+            .metadata_requests
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for ((key, value), count) in counts.iter() {
+            let _ = writeln!(
+                out,
+                "{name}{{key=\"{}\",value=\"{}\"}} {count}",
+                escape_label(key),
+                escape_label(value)
+            );
+        }
+        out
+    }
+}
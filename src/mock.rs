@@ -0,0 +1,119 @@
+use serde_json::{json, Value};
+
+const MOCK_RESPONSE_ID: &str = "resp_mock";
+
+/// Text of the last user message in a Responses API `input` list.
+fn last_user_text(payload: &Value) -> String {
+    let input = payload
+        .get("input")
+        .and_then(|v| v.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    input
+        .iter()
+        .rev()
+        .find(|item| item.get("role").and_then(|v| v.as_str()) == Some("user"))
+        .and_then(|item| item.get("content"))
+        .and_then(|v| v.as_array())
+        .map(|parts| {
+            parts
+                .iter()
+                .filter_map(|part| part.get("text").and_then(|v| v.as_str()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .unwrap_or_default()
+}
+
+/// Name of the first declared tool, in either the Chat or the Responses shape.
+fn first_tool_name(payload: &Value) -> Option<&str> {
+    let tool = payload.get("tools")?.as_array()?.first()?;
+    tool.get("name")
+        .or_else(|| tool.get("function").and_then(|f| f.get("name")))
+        .and_then(|v| v.as_str())
+}
+
+/// Whether the conversation ends with a tool result, which the mock answers
+/// in text so a client's tool loop terminates.
+fn ends_with_tool_result(payload: &Value) -> bool {
+    payload
+        .get("input")
+        .and_then(|v| v.as_array())
+        .and_then(|input| input.last())
+        .is_some_and(|item| {
+            item.get("type").and_then(|v| v.as_str()) == Some("function_call_output")
+                || item.get("role").and_then(|v| v.as_str()) == Some("tool")
+        })
+}
+
+fn event_line(event: Value) -> String {
+    format!("data: {}\n\n", event)
+}
+
+/// A deterministic upstream SSE body for `payload`, the request that would
+/// have been sent: a canned call to the first tool when tools are declared,
+/// otherwise an echo of the last user message split into word deltas.
+pub fn sse_body(payload: &Value) -> Vec<u8> {
+    let mut lines = vec![
+        event_line(json!({
+            "type": "response.created",
+            "response": { "id": MOCK_RESPONSE_ID }
+        })),
+        // Exercise keep-alive filtering like a real backend does
+        ": keep-alive\n\n".to_string(),
+    ];
+
+    match first_tool_name(payload).filter(|_| !ends_with_tool_result(payload)) {
+        Some(name) => {
+            let item = json!({
+                "type": "function_call",
+                "call_id": "call_mock_0",
+                "name": name,
+                "arguments": ""
+            });
+            lines.push(event_line(json!({
+                "type": "response.output_item.added",
+                "output_index": 0,
+                "item": item
+            })));
+            for delta in ["{", "}"] {
+                lines.push(event_line(json!({
+                    "type": "response.function_call_arguments.delta",
+                    "output_index": 0,
+                    "delta": delta
+                })));
+            }
+        }
+        None => {
+            let text = format!("Mock response: {}", last_user_text(payload));
+            lines.push(event_line(json!({
+                "type": "response.output_item.added",
+                "output_index": 0,
+                "item": { "type": "message", "role": "assistant", "content": [] }
+            })));
+            for delta in text.split_inclusive(' ') {
+                lines.push(event_line(json!({
+                    "type": "response.output_text.delta",
+                    "output_index": 0,
+                    "delta": delta
+                })));
+            }
+            lines.push(event_line(json!({
+                "type": "response.output_item.done",
+                "output_index": 0,
+                "item": {
+                    "type": "message",
+                    "role": "assistant",
+                    "content": [{ "type": "output_text", "text": text }]
+                }
+            })));
+        }
+    }
+
+    lines.push(event_line(json!({
+        "type": "response.completed",
+        "response": { "id": MOCK_RESPONSE_ID }
+    })));
+    lines.push("data: [DONE]\n\n".to_string());
+    lines.concat().into_bytes()
+}
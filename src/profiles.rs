@@ -0,0 +1,75 @@
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use toml::{Table, Value};
+
+// Read before the config file is, so only the command line or environment can set them
+const RESERVED_KEYS: [&str; 3] = ["config", "profile", "no-dotenv"];
+
+/// Turn a config file's `[default]` table, overlaid with `[profiles.NAME]` when
+/// a profile is selected, into command-line arguments. Keys are option names
+/// (`max-concurrent-requests` or `max_concurrent_requests`); `true` sets a
+/// switch, `false` leaves it off, and arrays repeat the option.
+pub fn config_args(path: &Path, profile: Option<&str>) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let config: Table = content
+        .parse()
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+    let table = |value: Option<&Value>, name: &str| -> Result<Table> {
+        match value {
+            None => Ok(Table::new()),
+            Some(Value::Table(table)) => Ok(table.clone()),
+            Some(_) => Err(anyhow!("'{}' in {} must be a table", name, path.display())),
+        }
+    };
+    let mut settings = table(config.get("default"), "default")?;
+    if let Some(name) = profile {
+        let profiles = table(config.get("profiles"), "profiles")?;
+        let Some(selected) = profiles.get(name) else {
+            let mut available = profiles.keys().cloned().collect::<Vec<_>>();
+            available.sort();
+            return Err(anyhow!(
+                "Profile '{}' is not defined in {} (available: {})",
+                name,
+                path.display(),
+                if available.is_empty() {
+                    "none".to_string()
+                } else {
+                    available.join(", ")
+                }
+            ));
+        };
+        // Profile values replace default ones key by key
+        settings.extend(table(Some(selected), &format!("profiles.{}", name))?);
+    }
+
+    let mut args = Vec::new();
+    for (key, value) in settings {
+        let option = key.replace('_', "-");
+        if RESERVED_KEYS.contains(&option.as_str()) {
+            return Err(anyhow!("'{}' cannot be set from a config file", key));
+        }
+        let values = match value {
+            Value::Array(items) => items,
+            other => vec![other],
+        };
+        for value in values {
+            match value {
+                Value::Boolean(true) => args.push(format!("--{}", option)),
+                Value::Boolean(false) => {}
+                Value::String(s) => args.push(format!("--{}={}", option, s)),
+                Value::Integer(n) => args.push(format!("--{}={}", option, n)),
+                Value::Float(f) => args.push(format!("--{}={}", option, f)),
+                _ => {
+                    return Err(anyhow!(
+                        "Unsupported value for '{}' in {}: use strings, numbers or booleans",
+                        key,
+                        path.display()
+                    ))
+                }
+            }
+        }
+    }
+    Ok(args)
+}
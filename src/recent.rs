@@ -0,0 +1,78 @@
+use super::access_log::AccessRecord;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Summary of one served request, as listed by `GET /admin/requests`.
+#[derive(Clone, Debug, Serialize)]
+struct RecentRequest {
+    /// When the response was sent (RFC 3339, UTC)
+    time: String,
+    method: String,
+    path: String,
+    status: u16,
+    /// Completion id, for chat completions
+    id: Option<String>,
+    model: Option<String>,
+    tokens: Option<i64>,
+    latency_ms: u64,
+    upstream_latency_ms: Option<u64>,
+}
+
+/// The last `capacity` requests, newest last. Older ones are dropped.
+#[derive(Debug)]
+pub struct RecentRequests {
+    capacity: usize,
+    entries: Mutex<VecDeque<RecentRequest>>,
+}
+
+impl RecentRequests {
+    /// `capacity == 0` keeps nothing.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn record(&self, record: &AccessRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        let completion = record.completion;
+        let entry = RecentRequest {
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            method: record.method.to_string(),
+            path: record.path.to_string(),
+            status: record.status,
+            id: completion.map(|c| c.id.clone()),
+            model: completion.map(|c| c.model.clone()),
+            tokens: completion.and_then(|c| c.total_tokens),
+            latency_ms: record.latency.as_millis() as u64,
+            upstream_latency_ms: completion
+                .and_then(|c| c.upstream_latency)
+                .map(|latency| latency.as_millis() as u64),
+        };
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The kept requests, newest first.
+    pub fn snapshot(&self) -> serde_json::Value {
+        let entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        serde_json::json!(entries.iter().rev().collect::<Vec<_>>())
+    }
+}
@@ -0,0 +1,198 @@
+use super::accounts::{Account, AccountPool};
+use super::jwt;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// OAuth client and token endpoint the Codex CLI signs in to ChatGPT with.
+const TOKEN_URL: &str = "https://auth.openai.com/oauth/token";
+const CLIENT_ID: &str = "app_EMoamEEZ73f0CkXaXp7hrann";
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct RefreshResponse {
+    access_token: String,
+    // The token endpoint may rotate these; keep the old ones when it does not
+    refresh_token: Option<String>,
+    id_token: Option<String>,
+}
+
+/// Exchange an account's refresh token for a new access token, swap it in and
+/// write the tokens back to its auth.json.
+pub async fn refresh_account(client: &reqwest::Client, account: &Account) -> Result<()> {
+    let _guard = account.refresh_lock.lock().await;
+    refresh_locked(client, account).await
+}
+
+/// Refresh after the backend rejected `rejected_token`, unless a concurrent
+/// request already has. Returns whether a new token had to be fetched.
+pub async fn refresh_rejected(
+    client: &reqwest::Client,
+    account: &Account,
+    rejected_token: &str,
+) -> Result<bool> {
+    let _guard = account.refresh_lock.lock().await;
+    let current = account.auth().tokens.map(|tokens| tokens.access_token);
+    if current.is_some_and(|token| token != rejected_token) {
+        return Ok(false);
+    }
+    refresh_locked(client, account).await?;
+    Ok(true)
+}
+
+async fn refresh_locked(client: &reqwest::Client, account: &Account) -> Result<()> {
+    let refresh_token = account
+        .auth()
+        .tokens
+        .and_then(|tokens| tokens.refresh_token)
+        .ok_or_else(|| anyhow!("{} has no refresh_token", account.label))?;
+
+    let response = client
+        .post(TOKEN_URL)
+        .json(&json!({
+            "client_id": CLIENT_ID,
+            "grant_type": "refresh_token",
+            "refresh_token": refresh_token,
+            "scope": "openid profile email"
+        }))
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to reach the token endpoint: {}", e))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(anyhow!("Token endpoint returned {}: {}", status, body));
+    }
+    let refreshed: RefreshResponse = response
+        .json()
+        .await
+        .context("Failed to parse the token endpoint response")?;
+
+    account.update_tokens(
+        refreshed.access_token.clone(),
+        refreshed.refresh_token.clone(),
+    );
+    persist_tokens(&account.auth_path, &refreshed)
+        .await
+        .with_context(|| format!("Failed to save refreshed tokens to {}", account.label))
+}
+
+/// Update the tokens in auth.json in place, leaving any other fields alone.
+/// The file is replaced by rename so an interrupted write cannot truncate it.
+async fn persist_tokens(path: &Path, refreshed: &RefreshResponse) -> Result<()> {
+    let content = tokio::fs::read_to_string(path).await?;
+    let mut auth: Value = serde_json::from_str(&content)?;
+    let tokens = auth
+        .get_mut("tokens")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow!("auth.json has no tokens object"))?;
+    tokens.insert("access_token".into(), json!(refreshed.access_token));
+    if let Some(refresh_token) = &refreshed.refresh_token {
+        tokens.insert("refresh_token".into(), json!(refresh_token));
+    }
+    if let Some(id_token) = &refreshed.id_token {
+        tokens.insert("id_token".into(), json!(id_token));
+    }
+    auth["last_refresh"] = json!(chrono::Utc::now().to_rfc3339());
+
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_string_pretty(&auth)?).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// How long until `fraction` of the token's lifetime has passed. Tokens
+/// without an `iat` claim are treated as issued now.
+fn refresh_delay(access_token: &str, fraction: f64) -> Result<Duration> {
+    let (issued_at, expires_at) = jwt::token_lifetime(access_token)?;
+    let now = chrono::Utc::now().timestamp();
+    let issued_at = issued_at.unwrap_or(now).min(expires_at);
+    let refresh_at = issued_at + ((expires_at - issued_at) as f64 * fraction) as i64;
+    Ok(Duration::from_secs(
+        refresh_at.saturating_sub(now).max(0) as u64
+    ))
+}
+
+/// Start one refresh task per account that signs in with a refresh token.
+/// The tasks stop once `shutdown` changes; await the handles to let an
+/// in-flight refresh finish saving first.
+pub fn spawn_scheduler(
+    client: reqwest::Client,
+    accounts: Arc<AccountPool>,
+    fraction: f64,
+    shutdown: watch::Receiver<bool>,
+) -> Vec<JoinHandle<()>> {
+    (0..accounts.accounts().len())
+        .filter(|&index| {
+            accounts.accounts()[index]
+                .auth()
+                .tokens
+                .is_some_and(|tokens| tokens.refresh_token.is_some())
+        })
+        .map(|index| {
+            tokio::spawn(run_account(
+                client.clone(),
+                accounts.clone(),
+                index,
+                fraction,
+                shutdown.clone(),
+            ))
+        })
+        .collect()
+}
+
+async fn run_account(
+    client: reqwest::Client,
+    accounts: Arc<AccountPool>,
+    index: usize,
+    fraction: f64,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let account = &accounts.accounts()[index];
+    loop {
+        let Some(tokens) = account.auth().tokens else {
+            return;
+        };
+        let delay = match refresh_delay(&tokens.access_token, fraction) {
+            Ok(delay) => delay,
+            Err(e) => {
+                println!(
+                    "⚠️  Not scheduling token refresh for {}: {:#}",
+                    account.label, e
+                );
+                return;
+            }
+        };
+        println!(
+            "🔑 Token refresh for {} scheduled in {}s",
+            account.label,
+            delay.as_secs()
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.changed() => return,
+        }
+
+        // Not raced against shutdown: dropping it midway could lose a rotated refresh token
+        match refresh_account(&client, account).await {
+            Ok(()) => println!("✓ Refreshed access token for {}", account.label),
+            Err(e) => {
+                eprintln!(
+                    "❌ Token refresh for {} failed, retrying in {}s: {:#}",
+                    account.label,
+                    RETRY_DELAY.as_secs(),
+                    e
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(RETRY_DELAY) => {}
+                    _ = shutdown.changed() => return,
+                }
+            }
+        }
+    }
+}
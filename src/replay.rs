@@ -0,0 +1,115 @@
+use super::{raw_query, universal_request_handler, ProxyServer};
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+use warp::Filter;
+
+const DEFAULT_PATH: &str = "/v1/chat/completions";
+
+/// A captured request: either a bare Chat Completions body, or
+/// `{"method", "path", "headers", "body"}` with everything but `body` optional.
+struct CapturedRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl CapturedRequest {
+    fn from_json(capture: Value) -> Result<Self> {
+        let Some(body) = capture
+            .get("body")
+            .filter(|_| capture.get("messages").is_none())
+        else {
+            return Ok(Self {
+                method: "POST".to_string(),
+                path: DEFAULT_PATH.to_string(),
+                headers: vec![("content-type".to_string(), "application/json".to_string())],
+                body: serde_json::to_vec(&capture)?,
+            });
+        };
+        let field = |name: &str| capture.get(name).and_then(|v| v.as_str());
+        let headers = match capture.get("headers") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Object(headers)) => headers
+                .iter()
+                .map(|(name, value)| {
+                    let value = value
+                        .as_str()
+                        .ok_or_else(|| anyhow!("Header '{}' must be a string", name))?;
+                    Ok((name.clone(), value.to_string()))
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => return Err(anyhow!("\"headers\" must be an object of strings")),
+        };
+        // A body captured as text is replayed byte for byte
+        let body = match body {
+            Value::String(text) => text.clone().into_bytes(),
+            body => serde_json::to_vec(body)?,
+        };
+        Ok(Self {
+            method: field("method").unwrap_or("POST").to_uppercase(),
+            path: field("path").unwrap_or(DEFAULT_PATH).to_string(),
+            headers,
+            body,
+        })
+    }
+}
+
+/// Send the request captured in `path` through the same handler the server
+/// uses, print the response, and fail when its status is not a success.
+pub async fn run(path: &Path, proxy: ProxyServer) -> Result<()> {
+    let raw = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let capture: Value = serde_json::from_str(&raw)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let request = CapturedRequest::from_json(capture)?;
+    println!(
+        "🔁 Replaying {} {} from {}",
+        request.method,
+        request.path,
+        path.display()
+    );
+
+    let handler = warp::any()
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(raw_query())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || proxy.clone()))
+        .and_then(universal_request_handler);
+    let mut builder = warp::test::request()
+        .method(&request.method)
+        .path(&request.path)
+        .body(request.body);
+    for (name, value) in &request.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    let response = builder.reply(&handler).await;
+
+    // The result is the point of a replay, so it bypasses --quiet. A closed
+    // pipe (e.g. `| head`) is not an error worth reporting.
+    let status = response.status();
+    let mut out = format!("HTTP {}\n", status);
+    for (name, value) in response.headers() {
+        let value = value.to_str().unwrap_or("[INVALID UTF-8]");
+        let _ = writeln!(out, "{}: {}", name, value);
+    }
+    out.push('\n');
+    let body = response.body();
+    match serde_json::from_slice::<Value>(body) {
+        Ok(json) => out.push_str(&serde_json::to_string_pretty(&json)?),
+        Err(_) => out.push_str(&String::from_utf8_lossy(body)),
+    }
+    out.push('\n');
+    let _ = std::io::stdout().write_all(out.as_bytes());
+
+    if !status.is_success() {
+        return Err(anyhow!("Replayed request failed with status {}", status));
+    }
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use anyhow::{anyhow, Context, Result};
+use std::sync::Arc;
+
+/// The ChatGPT Codex backend, used when no rule matches.
+pub const CODEX_RESPONSES_URL: &str = "https://chatgpt.com/backend-api/codex/responses";
+
+/// Where a chat completion is sent and how it authenticates.
+#[derive(Debug)]
+pub struct UpstreamRoute {
+    pub url: String,
+    /// Bearer key for this upstream; `None` uses the ChatGPT account pool
+    pub api_key: Option<String>,
+}
+
+impl UpstreamRoute {
+    /// Whether this is the ChatGPT Codex backend, which accepts fewer request fields.
+    pub fn is_codex(&self) -> bool {
+        self.url == CODEX_RESPONSES_URL
+    }
+}
+
+#[derive(Debug)]
+enum RouteCondition {
+    Tools,
+    NoTools,
+    Model(String),
+    Any,
+}
+
+#[derive(Debug)]
+struct RouteRule {
+    condition: RouteCondition,
+    route: Arc<UpstreamRoute>,
+}
+
+/// Upstream routing rules, evaluated in order; the first match wins.
+#[derive(Debug)]
+pub struct Router {
+    rules: Vec<RouteRule>,
+    default: Arc<UpstreamRoute>,
+}
+
+/// Parse `CONDITION=URL[,api-key-env=VAR]`, where CONDITION is `tools`,
+/// `no-tools`, `model:NAME` or `*`.
+fn parse_rule(spec: &str) -> Result<RouteRule> {
+    let invalid = || {
+        anyhow!(
+            "Invalid route '{}': expected 'CONDITION=URL[,api-key-env=VAR]'",
+            spec
+        )
+    };
+    let (condition, target) = spec.split_once('=').ok_or_else(invalid)?;
+    let condition = match condition.trim() {
+        "tools" => RouteCondition::Tools,
+        "no-tools" => RouteCondition::NoTools,
+        "*" => RouteCondition::Any,
+        other => match other.strip_prefix("model:") {
+            Some(model) if !model.trim().is_empty() => RouteCondition::Model(model.trim().into()),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid route condition '{}': use tools, no-tools, model:NAME or *",
+                    other
+                ))
+            }
+        },
+    };
+
+    let mut parts = target.split(',').map(str::trim);
+    let url = parts
+        .next()
+        .filter(|url| !url.is_empty())
+        .ok_or_else(invalid)?;
+    let mut api_key = None;
+    for option in parts {
+        let Some(var) = option.strip_prefix("api-key-env=") else {
+            return Err(anyhow!("Unknown option '{}' in route '{}'", option, spec));
+        };
+        let key = std::env::var(var)
+            .with_context(|| format!("Route '{}' reads its API key from unset {}", spec, var))?;
+        api_key = Some(key);
+    }
+
+    Ok(RouteRule {
+        condition,
+        route: Arc::new(UpstreamRoute {
+            url: url.to_string(),
+            api_key,
+        }),
+    })
+}
+
+impl Router {
+    /// Rules from UPSTREAM_ROUTES (`;`-separated) come first, then `--route` flags.
+    pub fn load(flags: &[String]) -> Result<Self> {
+        let from_env = std::env::var("UPSTREAM_ROUTES").unwrap_or_default();
+        let rules = from_env
+            .split(';')
+            .map(str::trim)
+            .filter(|spec| !spec.is_empty())
+            .chain(flags.iter().map(String::as_str))
+            .map(parse_rule)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            rules,
+            default: Arc::new(UpstreamRoute {
+                url: CODEX_RESPONSES_URL.to_string(),
+                api_key: None,
+            }),
+        })
+    }
+
+    /// Printable summary of each rule, for the startup log.
+    pub fn describe(&self) -> Vec<String> {
+        self.rules
+            .iter()
+            .map(|rule| {
+                let condition = match &rule.condition {
+                    RouteCondition::Tools => "tools".to_string(),
+                    RouteCondition::NoTools => "no-tools".to_string(),
+                    RouteCondition::Model(model) => format!("model:{}", model),
+                    RouteCondition::Any => "*".to_string(),
+                };
+                let auth = if rule.route.api_key.is_some() {
+                    "API key"
+                } else {
+                    "account pool"
+                };
+                format!("{} -> {} ({})", condition, rule.route.url, auth)
+            })
+            .collect()
+    }
+
+    /// Pick the upstream for a request by its model (as requested or as sent
+    /// upstream) and whether it declares any tools.
+    pub fn select(&self, models: &[&str], has_tools: bool) -> Arc<UpstreamRoute> {
+        self.rules
+            .iter()
+            .find(|rule| match &rule.condition {
+                RouteCondition::Tools => has_tools,
+                RouteCondition::NoTools => !has_tools,
+                RouteCondition::Model(model) => models.contains(&model.as_str()),
+                RouteCondition::Any => true,
+            })
+            .map_or_else(|| self.default.clone(), |rule| rule.route.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(rules: &[&str]) -> Router {
+        let flags: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        Router::load(&flags).expect("rules should parse")
+    }
+
+    #[test]
+    fn requests_with_tools_take_the_tools_route() {
+        let router = router(&[
+            &format!("tools={}", CODEX_RESPONSES_URL),
+            "no-tools=http://cheap.local/v1/responses",
+        ]);
+        assert!(router.select(&["gpt-5"], true).is_codex());
+        assert_eq!(
+            router.select(&["gpt-5"], false).url,
+            "http://cheap.local/v1/responses"
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_wins_over_the_tools_rule() {
+        let router = router(&[
+            "model:gpt-5-mini=http://mini.local/v1/responses",
+            "tools=http://tools.local/v1/responses",
+            "*=http://any.local/v1/responses",
+        ]);
+        // Both the model and the tools rule match; the earlier one is used
+        assert_eq!(
+            router.select(&["gpt-5-mini", "gpt-5-mini"], true).url,
+            "http://mini.local/v1/responses"
+        );
+        assert_eq!(
+            router.select(&["gpt-5", "gpt-5"], true).url,
+            "http://tools.local/v1/responses"
+        );
+        assert_eq!(
+            router.select(&["gpt-5", "gpt-5"], false).url,
+            "http://any.local/v1/responses"
+        );
+    }
+
+    #[test]
+    fn tools_route_ignores_requests_without_tools() {
+        let router = router(&["tools=http://tools.local/v1/responses"]);
+        assert!(router.select(&["gpt-5"], false).is_codex());
+    }
+}
@@ -0,0 +1,645 @@
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+
+/// One line of an upstream SSE body.
+#[derive(Debug)]
+pub enum SseLine {
+    /// A `data:` line carrying a JSON event
+    Event(Value),
+    /// The `[DONE]` terminator
+    Done,
+    /// Keep-alive traffic: `:` comments, `event: ping` and `{"type": "ping"}` payloads.
+    /// These are dropped on purpose; they carry no output and are never forwarded
+    /// to clients, which get their own framing from the proxy.
+    KeepAlive,
+    /// Anything else (`event:`/`id:` fields, blank separators, unparsable data)
+    Ignored,
+}
+
+const KEEPALIVE_TYPES: [&str; 3] = ["ping", "keepalive", "keep-alive"];
+
+pub fn parse_line(line: &str) -> SseLine {
+    if line.starts_with(':') {
+        return SseLine::KeepAlive;
+    }
+    if let Some(event_name) = line.strip_prefix("event:") {
+        if KEEPALIVE_TYPES.contains(&event_name.trim()) {
+            return SseLine::KeepAlive;
+        }
+        return SseLine::Ignored;
+    }
+
+    let Some(data) = line.strip_prefix("data:") else {
+        return SseLine::Ignored;
+    };
+    let data = data.trim_start();
+    if data == "[DONE]" {
+        return SseLine::Done;
+    }
+
+    match serde_json::from_str::<Value>(data) {
+        Ok(event) if is_keepalive(&event) => SseLine::KeepAlive,
+        Ok(event) => SseLine::Event(event),
+        Err(_) => SseLine::Ignored,
+    }
+}
+
+fn is_keepalive(event: &Value) -> bool {
+    event
+        .get("type")
+        .and_then(|v| v.as_str())
+        .is_some_and(|event_type| KEEPALIVE_TYPES.contains(&event_type))
+}
+
+/// One-line summary of a parsed line for `--trace-sse`: the event type plus
+/// what shows where its content goes (output index, item type, delta size).
+pub fn describe_line(line: &SseLine, bytes: usize) -> String {
+    let event = match line {
+        SseLine::Event(event) => event,
+        SseLine::Done => return "[DONE]".to_string(),
+        SseLine::KeepAlive => return format!("keep-alive ({} bytes)", bytes),
+        SseLine::Ignored => return format!("ignored line ({} bytes)", bytes),
+    };
+    let mut summary = vec![event
+        .get("type")
+        .and_then(|v| v.as_str())
+        .unwrap_or("(no type)")
+        .to_string()];
+    if let Some(index) = event.get("output_index").and_then(|v| v.as_u64()) {
+        summary.push(format!("output_index={}", index));
+    }
+    if let Some(item) = event.get("item") {
+        let item_type = item.get("type").and_then(|v| v.as_str()).unwrap_or("?");
+        summary.push(format!("item={}", item_type));
+        if let Some(name) = item.get("name").and_then(|v| v.as_str()) {
+            summary.push(format!("name={}", name));
+        }
+    }
+    for field in ["delta", "text", "arguments"] {
+        if let Some(text) = event.get(field).and_then(|v| v.as_str()) {
+            summary.push(format!("{}={} chars", field, text.chars().count()));
+        }
+    }
+    if let Some(status) = event
+        .get("response")
+        .and_then(|r| r.get("status"))
+        .and_then(|v| v.as_str())
+    {
+        summary.push(format!("status={}", status));
+    }
+    summary.push(format!("({} bytes)", bytes));
+    summary.join(" ")
+}
+
+/// Splits an upstream SSE body into lines as network chunks arrive. Bytes are
+/// held until a full line is buffered, so a multibyte character split across
+/// chunks is decoded whole rather than as two broken halves.
+#[derive(Default, Debug)]
+pub struct LineDecoder {
+    buffer: Vec<u8>,
+}
+
+impl LineDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a chunk and return every line it completed, without terminators.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        // `\n` never occurs inside a UTF-8 multibyte sequence, so every split
+        // point is a character boundary
+        let Some(end) = self.buffer.iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+        let rest = self.buffer.split_off(end + 1);
+        let complete = std::mem::replace(&mut self.buffer, rest);
+        complete[..end]
+            .split(|&b| b == b'\n')
+            .map(decode_line)
+            .collect()
+    }
+
+    /// The trailing line when the body does not end with a newline.
+    pub fn finish(self) -> Option<String> {
+        (!self.buffer.is_empty()).then(|| decode_line(&self.buffer))
+    }
+}
+
+fn decode_line(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
+
+/// Kind of an upstream output item, tracked by `output_index` so deltas can be
+/// attributed to the item that produced them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputItemKind {
+    Message,
+    Reasoning,
+    FunctionCall,
+    Other,
+}
+
+impl OutputItemKind {
+    fn from_item(item: &Value) -> Self {
+        match item.get("type").and_then(|v| v.as_str()) {
+            Some("message") => Self::Message,
+            Some("reasoning") => Self::Reasoning,
+            Some("function_call") => Self::FunctionCall,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Accumulates Responses API SSE events into separate reasoning, content and
+/// refusal buffers so reasoning never leaks into the visible answer.
+#[derive(Default, Debug)]
+pub struct ResponseAccumulator {
+    items: HashMap<u64, OutputItemKind>,
+    response_id: Option<String>,
+    created_at: Option<i64>,
+    service_tier: Option<String>,
+    usage: Option<UpstreamUsage>,
+    role: Option<String>,
+    content: String,
+    refusal: String,
+    reasoning: String,
+    fallback_content: String,
+    fallback_refusal: String,
+    fallback_reasoning: String,
+    content_parts: Vec<Value>,
+    saw_content_delta: bool,
+    saw_refusal_delta: bool,
+    saw_reasoning_delta: bool,
+    incomplete_reason: Option<String>,
+    tool_calls: BTreeMap<u64, AccumulatedToolCall>,
+    drop_reasoning: bool,
+    max_tool_arguments: usize,
+}
+
+/// A function call requested by the model.
+#[derive(Debug, Default, Clone)]
+pub struct AccumulatedToolCall {
+    /// Upstream `call_id`, echoed back by the client on the tool result
+    pub call_id: String,
+    pub name: String,
+    pub arguments: String,
+    /// Bytes of arguments the backend sent, when more than the limit were kept
+    pub truncated_from: Option<usize>,
+}
+
+impl AccumulatedToolCall {
+    /// Append argument text, keeping at most `limit` bytes (0 = no limit).
+    /// Beyond the limit the text is only counted, so a runaway call cannot
+    /// grow the buffer without bound.
+    fn push_arguments(&mut self, text: &str, limit: usize) {
+        if let Some(received) = self.truncated_from.as_mut() {
+            *received += text.len();
+            return;
+        }
+        if limit == 0 || self.arguments.len() + text.len() <= limit {
+            self.arguments.push_str(text);
+            return;
+        }
+        let mut end = limit - self.arguments.len();
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.arguments.push_str(&text[..end]);
+        self.truncated_from = Some(self.arguments.len() - end + text.len());
+    }
+}
+
+/// Token counts the backend reports on its final lifecycle event.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UpstreamUsage {
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    pub total_tokens: i64,
+}
+
+impl UpstreamUsage {
+    fn from_value(usage: &Value) -> Option<Self> {
+        let count = |name: &str| usage.get(name).and_then(|v| v.as_i64());
+        let input_tokens = count("input_tokens").unwrap_or(0);
+        let output_tokens = count("output_tokens").unwrap_or(0);
+        let total_tokens = count("total_tokens").unwrap_or(input_tokens + output_tokens);
+        usage.is_object().then_some(Self {
+            input_tokens,
+            output_tokens,
+            total_tokens,
+        })
+    }
+}
+
+/// Final text collected from an upstream response.
+#[derive(Debug, Default)]
+pub struct AccumulatedOutput {
+    /// Upstream response id (`resp_...`) from the lifecycle events
+    pub response_id: Option<String>,
+    /// Upstream `created_at` (Unix seconds), the latest lifecycle event's value
+    pub created_at: Option<i64>,
+    /// Tier the backend processed the request on, when it says
+    pub service_tier: Option<String>,
+    /// `None` when the backend reported no usage
+    pub usage: Option<UpstreamUsage>,
+    /// Role reported on the upstream message item, if any
+    pub role: Option<String>,
+    pub content: String,
+    pub refusal: String,
+    pub reasoning: String,
+    /// Text and refusal parts of the completed message items, in Chat
+    /// Completions shape (`text` with any `annotations`, `refusal`)
+    pub content_parts: Vec<Value>,
+    /// `incomplete_details.reason` when the response ended early (e.g. `content_filter`)
+    pub incomplete_reason: Option<String>,
+    /// Function calls in output order
+    pub tool_calls: Vec<AccumulatedToolCall>,
+}
+
+fn output_index(event: &Value) -> Option<u64> {
+    event.get("output_index").and_then(|v| v.as_u64())
+}
+
+impl ResponseAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discard reasoning deltas and items instead of collecting them.
+    pub fn dropping_reasoning(mut self, drop: bool) -> Self {
+        self.drop_reasoning = drop;
+        self
+    }
+
+    /// Keep at most `limit` bytes of each tool call's arguments (0 = no limit).
+    pub fn limiting_tool_arguments(mut self, limit: usize) -> Self {
+        self.max_tool_arguments = limit;
+        self
+    }
+
+    fn kind_at(&self, event: &Value) -> Option<OutputItemKind> {
+        output_index(event).and_then(|index| self.items.get(&index).copied())
+    }
+
+    pub fn handle_event(&mut self, event: &Value) {
+        let Some(event_type) = event.get("type").and_then(|v| v.as_str()) else {
+            return;
+        };
+        let delta = event.get("delta").and_then(|v| v.as_str());
+
+        let is_reasoning_delta = event_type.starts_with("response.reasoning")
+            || (event_type == "response.output_text.delta"
+                && self.kind_at(event) == Some(OutputItemKind::Reasoning));
+        if self.drop_reasoning && is_reasoning_delta {
+            return;
+        }
+
+        match event_type {
+            "response.created" | "response.in_progress" | "response.completed" => {
+                let response = event.get("response");
+                if let Some(id) = response.and_then(|r| r.get("id")).and_then(|v| v.as_str()) {
+                    self.response_id = Some(id.to_string());
+                }
+                if let Some(created_at) = response
+                    .and_then(|r| r.get("created_at"))
+                    .and_then(|v| v.as_f64())
+                {
+                    self.created_at = Some(created_at as i64);
+                }
+                if let Some(tier) = response
+                    .and_then(|r| r.get("service_tier"))
+                    .and_then(|v| v.as_str())
+                {
+                    self.service_tier = Some(tier.to_string());
+                }
+                if let Some(usage) = response
+                    .and_then(|r| r.get("usage"))
+                    .and_then(UpstreamUsage::from_value)
+                {
+                    self.usage = Some(usage);
+                }
+            }
+            "response.output_item.added" => {
+                if let Some(item) = event.get("item") {
+                    let kind = OutputItemKind::from_item(item);
+                    if let Some(index) = output_index(event) {
+                        self.items.insert(index, kind);
+                    }
+                    self.record_role(kind, item);
+                    self.record_tool_call(kind, event, item);
+                }
+            }
+            "response.function_call_arguments.delta" => {
+                if let (Some(index), Some(delta)) = (output_index(event), delta) {
+                    self.tool_calls
+                        .entry(index)
+                        .or_default()
+                        .push_arguments(delta, self.max_tool_arguments);
+                }
+            }
+            // Backends that skip the deltas send the arguments here, ahead of the item
+            "response.function_call_arguments.done" => {
+                let arguments = event.get("arguments").and_then(|v| v.as_str());
+                if let (Some(index), Some(arguments)) = (output_index(event), arguments) {
+                    let call = self.tool_calls.entry(index).or_default();
+                    if call.arguments.is_empty() && call.truncated_from.is_none() {
+                        call.push_arguments(arguments, self.max_tool_arguments);
+                    }
+                }
+            }
+            "response.output_text.delta" => {
+                if let Some(delta) = delta {
+                    if self.kind_at(event) == Some(OutputItemKind::Reasoning) {
+                        self.saw_reasoning_delta = true;
+                        self.reasoning.push_str(delta);
+                    } else {
+                        self.saw_content_delta = true;
+                        self.content.push_str(delta);
+                    }
+                }
+            }
+            "response.refusal.delta" => {
+                if let Some(delta) = delta {
+                    self.saw_refusal_delta = true;
+                    self.refusal.push_str(delta);
+                }
+            }
+            "response.reasoning_text.delta" | "response.reasoning_summary_text.delta" => {
+                if let Some(delta) = delta {
+                    self.saw_reasoning_delta = true;
+                    self.reasoning.push_str(delta);
+                }
+            }
+            "response.output_item.done" => {
+                if let Some(item) = event.get("item") {
+                    let kind = OutputItemKind::from_item(item);
+                    if let Some(index) = output_index(event) {
+                        self.items.insert(index, kind);
+                    }
+                    self.record_role(kind, item);
+                    self.record_tool_call(kind, event, item);
+                    self.collect_item_text(kind, item);
+                }
+            }
+            "response.incomplete" => {
+                self.incomplete_reason = event
+                    .get("response")
+                    .and_then(|r| r.get("incomplete_details"))
+                    .and_then(|d| d.get("reason"))
+                    .and_then(|v| v.as_str())
+                    .map(ToString::to_string);
+            }
+            _ => {} // Ignore other event types
+        }
+    }
+
+    /// Handle a whole Responses API object, as the backend sends in place of
+    /// an event stream for some errors or non-streamed requests. Each output
+    /// item is treated like its `response.output_item.done` event.
+    pub fn handle_response_object(&mut self, body: &Value) {
+        // Accept the bare object or one wrapped as `{"response": {...}}`
+        let response = body
+            .get("response")
+            .filter(|r| r.is_object())
+            .unwrap_or(body);
+        self.handle_event(&json!({ "type": "response.completed", "response": response }));
+        if response.get("status").and_then(|v| v.as_str()) == Some("incomplete") {
+            self.handle_event(&json!({ "type": "response.incomplete", "response": response }));
+        }
+
+        let output = response
+            .get("output")
+            .and_then(|v| v.as_array())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        for (index, item) in output.iter().enumerate() {
+            self.handle_event(&json!({
+                "type": "response.output_item.done",
+                "output_index": index,
+                "item": item
+            }));
+        }
+    }
+
+    fn record_role(&mut self, kind: OutputItemKind, item: &Value) {
+        if kind == OutputItemKind::Message {
+            if let Some(role) = item.get("role").and_then(|v| v.as_str()) {
+                self.role = Some(role.to_string());
+            }
+        }
+    }
+
+    fn record_tool_call(&mut self, kind: OutputItemKind, event: &Value, item: &Value) {
+        if kind != OutputItemKind::FunctionCall {
+            return;
+        }
+        let index = output_index(event).unwrap_or_else(|| {
+            self.tool_calls
+                .keys()
+                .next_back()
+                .map_or(0, |last| last + 1)
+        });
+        let field = |name: &str| item.get(name).and_then(|v| v.as_str());
+
+        let call = self.tool_calls.entry(index).or_default();
+        if let Some(call_id) = field("call_id").or_else(|| field("id")) {
+            call.call_id = call_id.to_string();
+        }
+        if let Some(name) = field("name") {
+            call.name = name.to_string();
+        }
+        // The completed item carries the full arguments; it supersedes the deltas
+        if let Some(arguments) = field("arguments").filter(|a| !a.is_empty()) {
+            call.arguments.clear();
+            call.truncated_from = None;
+            call.push_arguments(arguments, self.max_tool_arguments);
+        }
+    }
+
+    fn collect_item_text(&mut self, kind: OutputItemKind, item: &Value) {
+        let parts = |field: &str| {
+            item.get(field)
+                .and_then(|v| v.as_array())
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        match kind {
+            OutputItemKind::Message => {
+                for part in parts("content") {
+                    if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                        self.fallback_content.push_str(text);
+                        let mut chat_part = json!({ "type": "text", "text": text });
+                        if let Some(annotations) = part
+                            .get("annotations")
+                            .filter(|a| a.as_array().is_some_and(|a| !a.is_empty()))
+                        {
+                            chat_part["annotations"] = annotations.clone();
+                        }
+                        self.content_parts.push(chat_part);
+                    }
+                    if let Some(refusal) = part.get("refusal").and_then(|v| v.as_str()) {
+                        self.fallback_refusal.push_str(refusal);
+                        self.content_parts
+                            .push(json!({ "type": "refusal", "refusal": refusal }));
+                    }
+                }
+            }
+            OutputItemKind::Reasoning if !self.drop_reasoning => {
+                // Prefer the summary; raw reasoning text is only present on some models.
+                let mut texts = parts("summary");
+                if texts.is_empty() {
+                    texts = parts("content");
+                }
+                for part in texts {
+                    if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                        self.fallback_reasoning.push_str(text);
+                    }
+                }
+            }
+            OutputItemKind::Reasoning | OutputItemKind::FunctionCall | OutputItemKind::Other => {}
+        }
+    }
+
+    /// Upstream response id, once a lifecycle event has reported it.
+    pub fn response_id(&self) -> Option<&str> {
+        self.response_id.as_deref()
+    }
+
+    pub fn created_at(&self) -> Option<i64> {
+        self.created_at
+    }
+
+    pub fn service_tier(&self) -> Option<&str> {
+        self.service_tier.as_deref()
+    }
+
+    /// Answer text streamed so far (deltas only, no completed-item fallback).
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn refusal(&self) -> &str {
+        &self.refusal
+    }
+
+    pub fn reasoning(&self) -> &str {
+        &self.reasoning
+    }
+
+    /// Function calls seen so far, in output order.
+    pub fn tool_calls(&self) -> impl Iterator<Item = &AccumulatedToolCall> {
+        self.tool_calls.values()
+    }
+
+    /// Prefer streamed deltas, falling back to completed items when no deltas arrived.
+    pub fn finish(self) -> AccumulatedOutput {
+        let pick = |saw_delta: bool, streamed: String, fallback: String| {
+            if !saw_delta && !fallback.is_empty() {
+                fallback
+            } else {
+                streamed
+            }
+        };
+
+        AccumulatedOutput {
+            response_id: self.response_id,
+            created_at: self.created_at,
+            service_tier: self.service_tier,
+            usage: self.usage,
+            role: self.role,
+            content: pick(self.saw_content_delta, self.content, self.fallback_content),
+            refusal: pick(self.saw_refusal_delta, self.refusal, self.fallback_refusal),
+            reasoning: pick(
+                self.saw_reasoning_delta,
+                self.reasoning,
+                self.fallback_reasoning,
+            ),
+            content_parts: self.content_parts,
+            incomplete_reason: self.incomplete_reason,
+            tool_calls: self.tool_calls.into_values().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode `chunks` as the proxy reads an upstream body, feeding events to
+    /// `accumulator`. Returns how many keep-alive lines were dropped.
+    fn feed(chunks: &[&[u8]], accumulator: &mut ResponseAccumulator) -> usize {
+        let mut decoder = LineDecoder::new();
+        let mut lines: Vec<String> = chunks.iter().flat_map(|c| decoder.push(c)).collect();
+        lines.extend(decoder.finish());
+        let mut keepalives = 0;
+        for line in lines {
+            match parse_line(&line) {
+                SseLine::Event(event) => accumulator.handle_event(&event),
+                SseLine::KeepAlive => keepalives += 1,
+                SseLine::Done | SseLine::Ignored => {}
+            }
+        }
+        keepalives
+    }
+
+    #[test]
+    fn pings_between_deltas_are_skipped() {
+        let body = concat!(
+            "data: {\"type\":\"response.output_item.added\",\"output_index\":0,\"item\":{\"type\":\"message\"}}\n\n",
+            "event: ping\ndata: {\"type\":\"ping\"}\n\n",
+            "data: {\"type\":\"response.output_text.delta\",\"output_index\":0,\"delta\":\"Hello\"}\n\n",
+            ": keep-alive\n\n",
+            "data: {\"type\":\"keepalive\"}\n\n",
+            "data: {\"type\":\"response.output_text.delta\",\"output_index\":0,\"delta\":\", world\"}\n\n",
+            "event: ping\n\n",
+            "data: [DONE]\n\n",
+        );
+        let mut accumulator = ResponseAccumulator::new();
+        let keepalives = feed(&[body.as_bytes()], &mut accumulator);
+
+        assert_eq!(keepalives, 5);
+        assert_eq!(accumulator.content(), "Hello, world");
+        assert_eq!(accumulator.finish().content, "Hello, world");
+    }
+
+    fn reasoning_then_answer() -> Vec<Value> {
+        vec![
+            json!({ "type": "response.output_item.added", "output_index": 0, "item": { "type": "reasoning" } }),
+            json!({ "type": "response.reasoning_summary_text.delta", "output_index": 0, "delta": "Thinking" }),
+            json!({ "type": "response.reasoning_text.delta", "output_index": 0, "delta": " hard" }),
+            json!({ "type": "response.output_text.delta", "output_index": 0, "delta": " still" }),
+            json!({ "type": "response.output_item.done", "output_index": 0, "item": {
+                "type": "reasoning",
+                "summary": [{ "type": "summary_text", "text": "Thinking hard still" }]
+            } }),
+            json!({ "type": "response.output_item.added", "output_index": 1, "item": { "type": "message" } }),
+            json!({ "type": "response.output_text.delta", "output_index": 1, "delta": "Answer" }),
+        ]
+    }
+
+    #[test]
+    fn reasoning_is_dropped_when_asked() {
+        let mut accumulator = ResponseAccumulator::new().dropping_reasoning(true);
+        for event in reasoning_then_answer() {
+            accumulator.handle_event(&event);
+        }
+        assert_eq!(accumulator.reasoning(), "");
+        let output = accumulator.finish();
+        assert_eq!(output.reasoning, "");
+        assert_eq!(output.content, "Answer");
+    }
+
+    #[test]
+    fn reasoning_is_kept_by_default() {
+        let mut accumulator = ResponseAccumulator::new();
+        for event in reasoning_then_answer() {
+            accumulator.handle_event(&event);
+        }
+        let output = accumulator.finish();
+        assert_eq!(output.reasoning, "Thinking hard still");
+        assert_eq!(output.content, "Answer");
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// The window is kept as one bucket per second, so it slides in 1s steps
+const WINDOW_SECS: i64 = 60;
+
+/// Tokens used across all requests over the last minute, optionally capped.
+#[derive(Debug)]
+pub struct TokenThroughput {
+    limit: u64,
+    /// `(unix second, tokens)`, oldest first, covering at most `WINDOW_SECS`
+    buckets: Mutex<VecDeque<(i64, u64)>>,
+    tokens_total: AtomicU64,
+    throttled_total: AtomicU64,
+}
+
+impl TokenThroughput {
+    /// `limit == 0` only tracks throughput and never throttles.
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(VecDeque::with_capacity(WINDOW_SECS as usize)),
+            tokens_total: AtomicU64::new(0),
+            throttled_total: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    fn window(&self, now: i64) -> std::sync::MutexGuard<'_, VecDeque<(i64, u64)>> {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        while buckets
+            .front()
+            .is_some_and(|(second, _)| *second <= now - WINDOW_SECS)
+        {
+            buckets.pop_front();
+        }
+        buckets
+    }
+
+    /// Count a completed request's tokens.
+    pub fn record(&self, tokens: u64) {
+        self.tokens_total.fetch_add(tokens, Ordering::Relaxed);
+        let now = chrono::Utc::now().timestamp();
+        let mut buckets = self.window(now);
+        match buckets.back_mut() {
+            Some((second, count)) if *second == now => *count += tokens,
+            _ => buckets.push_back((now, tokens)),
+        }
+    }
+
+    pub fn tokens_per_minute(&self) -> u64 {
+        let now = chrono::Utc::now().timestamp();
+        self.window(now).iter().map(|(_, tokens)| tokens).sum()
+    }
+
+    /// When the ceiling is reached, the seconds until enough of the window
+    /// has expired to fall below it again.
+    pub fn throttle(&self) -> Option<u64> {
+        if self.limit == 0 {
+            return None;
+        }
+        let now = chrono::Utc::now().timestamp();
+        let buckets = self.window(now);
+        let mut used: u64 = buckets.iter().map(|(_, tokens)| tokens).sum();
+        if used < self.limit {
+            return None;
+        }
+        self.throttled_total.fetch_add(1, Ordering::Relaxed);
+        for (second, tokens) in buckets.iter() {
+            used -= tokens;
+            if used < self.limit {
+                return Some((second + WINDOW_SECS - now).max(1) as u64);
+            }
+        }
+        Some(1)
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let name = "codex_proxy_tokens_per_minute";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Tokens used over the last 60s (1s granularity)"
+        );
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {}", self.tokens_per_minute());
+        let counters = [
+            (
+                "codex_proxy_tokens_total",
+                "Tokens used by completed requests",
+                &self.tokens_total,
+            ),
+            (
+                "codex_proxy_tokens_per_minute_throttled_total",
+                "Requests rejected by the tokens-per-minute ceiling",
+                &self.throttled_total,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
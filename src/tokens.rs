@@ -0,0 +1,165 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::sync::Arc;
+
+// OpenAI's chat framing: tokens around each message, and the reply priming
+const TOKENS_PER_MESSAGE: usize = 3;
+const TOKENS_PER_REPLY: usize = 3;
+
+/// Counts tokens where the backend has not reported them. Every feature
+/// that needs a count uses the one estimator chosen at startup, so their
+/// figures agree with each other.
+pub trait TokenEstimator: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Tokens in `text`.
+    fn count(&self, text: &str) -> usize;
+
+    /// Tokens for Chat Completions `messages`, including per-message framing.
+    fn count_messages(&self, messages: &[Value]) -> usize {
+        let framed: usize = messages
+            .iter()
+            .map(|message| {
+                let mut tokens = TOKENS_PER_MESSAGE;
+                for field in ["role", "name", "tool_call_id"] {
+                    if let Some(text) = message.get(field).and_then(|v| v.as_str()) {
+                        tokens += self.count(text);
+                    }
+                }
+                tokens += match message.get("content") {
+                    Some(Value::String(text)) => self.count(text),
+                    Some(Value::Array(parts)) => parts
+                        .iter()
+                        .filter_map(|part| part.get("text").and_then(|t| t.as_str()))
+                        .map(|text| self.count(text))
+                        .sum(),
+                    _ => 0,
+                };
+                if let Some(calls) = message.get("tool_calls").and_then(|v| v.as_array()) {
+                    for function in calls.iter().filter_map(|call| call.get("function")) {
+                        for field in ["name", "arguments"] {
+                            if let Some(text) = function.get(field).and_then(|v| v.as_str()) {
+                                tokens += self.count(text);
+                            }
+                        }
+                    }
+                }
+                tokens
+            })
+            .sum();
+        framed + TOKENS_PER_REPLY
+    }
+}
+
+/// About four characters per token, and never fewer tokens than words.
+/// Close enough for English prose and code; no tokenizer data needed.
+pub struct HeuristicEstimator;
+
+impl TokenEstimator for HeuristicEstimator {
+    fn name(&self) -> &'static str {
+        "heuristic"
+    }
+
+    fn count(&self, text: &str) -> usize {
+        let by_chars = text.chars().count().div_ceil(4);
+        by_chars.max(text.split_whitespace().count())
+    }
+}
+
+/// Exact counts with the `o200k_base` encoding used by the GPT-5 family.
+#[cfg(feature = "tiktoken")]
+pub struct TiktokenEstimator {
+    bpe: tiktoken_rs::CoreBPE,
+}
+
+#[cfg(feature = "tiktoken")]
+impl TiktokenEstimator {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            bpe: tiktoken_rs::o200k_base()?,
+        })
+    }
+}
+
+#[cfg(feature = "tiktoken")]
+impl TokenEstimator for TiktokenEstimator {
+    fn name(&self) -> &'static str {
+        "tiktoken (o200k_base)"
+    }
+
+    fn count(&self, text: &str) -> usize {
+        self.bpe.encode_ordinary(text).len()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum EstimatorKind {
+    /// Character and word based estimate
+    Heuristic,
+    /// Exact BPE counts (requires building with `--features tiktoken`)
+    Tiktoken,
+}
+
+pub fn build(kind: EstimatorKind) -> Result<Arc<dyn TokenEstimator>> {
+    match kind {
+        EstimatorKind::Heuristic => Ok(Arc::new(HeuristicEstimator)),
+        #[cfg(feature = "tiktoken")]
+        EstimatorKind::Tiktoken => Ok(Arc::new(TiktokenEstimator::new()?)),
+        #[cfg(not(feature = "tiktoken"))]
+        EstimatorKind::Tiktoken => Err(anyhow::anyhow!(
+            "--token-estimator tiktoken needs a build with `--features tiktoken`"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const PROSE: &str = "The quick brown fox jumps over the lazy dog.";
+    const CODE: &str = "fn main() {\n    println!(\"Hello, world!\");\n}\n";
+
+    #[test]
+    fn heuristic_counts_characters_and_words() {
+        let estimator = HeuristicEstimator;
+        assert_eq!(estimator.count(""), 0);
+        // 44 characters round up to 11 tokens, more than the 9 words
+        assert_eq!(estimator.count(PROSE), 11);
+        assert_eq!(estimator.count(CODE), 12);
+        // Short words: the word count is the floor
+        assert_eq!(estimator.count("a b c d e f"), 6);
+    }
+
+    #[test]
+    fn messages_add_framing_to_their_text() {
+        let estimator = HeuristicEstimator;
+        let messages = [
+            json!({ "role": "user", "content": PROSE }),
+            json!({ "role": "assistant", "content": null, "tool_calls": [
+                { "function": { "name": "lookup", "arguments": "{}" } }
+            ] }),
+        ];
+        // user 1 + 11, assistant 3 + lookup 2 + {} 1, framing 3 + 3 + 3
+        assert_eq!(estimator.count_messages(&messages), 27);
+    }
+
+    #[cfg(feature = "tiktoken")]
+    #[test]
+    fn heuristic_stays_close_to_tiktoken() {
+        let exact = TiktokenEstimator::new().unwrap();
+        let heuristic = HeuristicEstimator;
+        assert_eq!(exact.count(PROSE), 10);
+        assert_eq!(exact.count(CODE), 12);
+        for text in [PROSE, CODE] {
+            let (exact, estimate) = (exact.count(text), heuristic.count(text));
+            assert!(
+                estimate * 2 >= exact && estimate <= exact * 2,
+                "heuristic {} is far from tiktoken {} for {:?}",
+                estimate,
+                exact,
+                text
+            );
+        }
+    }
+}
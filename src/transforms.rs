@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// How long an external transform command may run before it is abandoned.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One step applied to a request's `messages` before conversion.
+#[derive(Debug, Clone)]
+pub enum Transform {
+    /// Run `sh -c COMMAND` with the messages as a JSON array on stdin; its
+    /// stdout must be the rewritten JSON array
+    Exec(String),
+    /// Insert the file's current contents as a leading system message
+    PrependFile(String),
+    /// Remove client system messages
+    DropSystem,
+    /// Trim surrounding whitespace from string message contents
+    Trim,
+}
+
+impl Transform {
+    /// Parse `exec:COMMAND`, `prepend-file:PATH`, `drop-system` or `trim`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        if let Some(command) = spec.strip_prefix("exec:") {
+            if command.trim().is_empty() {
+                return Err(anyhow!("Transform 'exec:' needs a command"));
+            }
+            return Ok(Self::Exec(command.to_string()));
+        }
+        if let Some(path) = spec.strip_prefix("prepend-file:") {
+            if path.trim().is_empty() {
+                return Err(anyhow!("Transform 'prepend-file:' needs a path"));
+            }
+            return Ok(Self::PrependFile(path.trim().to_string()));
+        }
+        match spec {
+            "drop-system" => Ok(Self::DropSystem),
+            "trim" => Ok(Self::Trim),
+            other => Err(anyhow!(
+                "Unknown transform '{}': use exec:COMMAND, prepend-file:PATH, drop-system or trim",
+                other
+            )),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Exec(command) => format!("exec:{}", command),
+            Self::PrependFile(path) => format!("prepend-file:{}", path),
+            Self::DropSystem => "drop-system".to_string(),
+            Self::Trim => "trim".to_string(),
+        }
+    }
+
+    async fn apply(&self, messages: Vec<Value>) -> Result<Vec<Value>> {
+        match self {
+            Self::Exec(command) => run_command(command, &messages).await,
+            Self::PrependFile(path) => {
+                let context = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("Failed to read {}", path))?;
+                let mut result = Vec::with_capacity(messages.len() + 1);
+                result.push(json!({ "role": "system", "content": context }));
+                result.extend(messages);
+                Ok(result)
+            }
+            Self::DropSystem => Ok(messages
+                .into_iter()
+                .filter(|msg| msg.get("role").and_then(|v| v.as_str()) != Some("system"))
+                .collect()),
+            Self::Trim => Ok(messages
+                .into_iter()
+                .map(|mut msg| {
+                    if let Some(Value::String(content)) = msg.get_mut("content") {
+                        *content = content.trim().to_string();
+                    }
+                    msg
+                })
+                .collect()),
+        }
+    }
+}
+
+async fn run_command(command: &str, messages: &[Value]) -> Result<Vec<Value>> {
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to start transform command")?;
+
+    let input = serde_json::to_vec(messages)?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("Transform command has no stdin")?;
+    let output = tokio::time::timeout(COMMAND_TIMEOUT, async move {
+        // A command that ignores its input closes the pipe early; that is not an error
+        let _ = stdin.write_all(&input).await;
+        drop(stdin);
+        child.wait_with_output().await
+    })
+    .await
+    .map_err(|_| anyhow!("Transform command timed out after {:?}", COMMAND_TIMEOUT))?
+    .context("Transform command failed")?;
+
+    if !output.status.success() {
+        return Err(anyhow!("Transform command exited with {}", output.status));
+    }
+    let result: Value =
+        serde_json::from_slice(&output.stdout).context("Transform command did not print JSON")?;
+    match result {
+        Value::Array(messages) => Ok(messages),
+        _ => Err(anyhow!(
+            "Transform command must print a JSON array of messages"
+        )),
+    }
+}
+
+/// Apply each transform in order. A transform that fails, or whose output is
+/// not a valid message list, is skipped and the messages pass through as they
+/// were before it.
+pub async fn apply_all<T>(transforms: &[Transform], messages: Vec<T>) -> Vec<T>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    if transforms.is_empty() {
+        return messages;
+    }
+    let mut current = messages;
+    for transform in transforms {
+        let values = match current.iter().map(serde_json::to_value).collect() {
+            Ok(values) => values,
+            Err(e) => {
+                eprintln!("⚠️  Transform '{}' skipped: {}", transform.describe(), e);
+                continue;
+            }
+        };
+        let result = transform.apply(values).await.and_then(|values| {
+            values
+                .into_iter()
+                .map(|value| serde_json::from_value(value).context("Invalid message"))
+                .collect::<Result<Vec<T>>>()
+        });
+        match result {
+            Ok(messages) => current = messages,
+            Err(e) => eprintln!(
+                "⚠️  Transform '{}' failed, passing messages through: {:#}",
+                transform.describe(),
+                e
+            ),
+        }
+    }
+    current
+}